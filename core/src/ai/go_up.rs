@@ -1,10 +1,7 @@
-use std::hash::Hash;
-use std::fmt::Debug;
-
-use crossy_multi_core::map::obstacle_row::ObstaclePublic;
-use crossy_multi_core::{GameState, PlayerId, Input, CoordPos, PreciseCoords, player, Pos, crossy_ruleset};
-use crossy_multi_core::map::{Map, RowType};
-use crossy_multi_core::player::MoveState;
+use crate::map::obstacle_row::ObstaclePublic;
+use crate::{GameState, PlayerId, Input, CoordPos, PreciseCoords, crossy_ruleset};
+use crate::map::{Map, RowType};
+use crate::player::MoveState;
 
 use froggy_rand::FroggyRand;
 
@@ -32,43 +29,6 @@ impl GoUpAI {
         }
     }
 
-    fn side_random<T : Hash + Debug>(&self, x : T) -> Input {
-        if (self.rng.gen_unit(("do_nothing", self.rng_t, &x)) < 0.9) {
-            Input::None
-        }
-        else {
-            *self.rng.choose(("side_random", self.rng_t, &x), &[Input::Left, Input::Right])
-        }
-    }
-
-    fn think_lobby(&mut self, game_state : &GameState, map : &Map) -> Input
-    {
-        let maybe_player_state = game_state.get_player(self.player_id);
-        if let Some(player_state) = maybe_player_state {
-            if (crossy_ruleset::player_in_lobby_ready_zone(player_state)) {
-                return Input::None;
-            }
-            else {
-                if let Pos::Coord(CoordPos{x, y}) = player_state.pos {
-                    if (x < crossy_ruleset::LOBBY_READ_ZONE_X_MIN) {
-                        return Input::Right;
-                    }
-                    if (x > crossy_ruleset::LOBBY_READ_ZONE_X_MAX) {
-                        return Input::Left;
-                    }
-                    if (y < crossy_ruleset::LOBBY_READ_ZONE_Y_MIN) {
-                        return Input::Down;
-                    }
-                    if (y > crossy_ruleset::LOBBY_READ_ZONE_Y_MAX) {
-                        return Input::Up;
-                    }
-                }
-            }
-        }
-
-        Input::None
-    }
-
     fn think_game(&mut self, game_state : &GameState, map : &Map) -> Input
     {
         let maybe_player_state = game_state.get_player(self.player_id);
@@ -78,7 +38,7 @@ impl GoUpAI {
                 MoveState::Moving(moving_state) => moving_state.target,
             };
 
-            let precise_pos = map.realise_pos(game_state.time_us, &player_pos);
+            let precise_pos = map.realise_pos(game_state.time_us, &player_pos, &game_state.rules_state.fst);
             let player_pos_coords = precise_pos.to_coords();
             let y_up = precise_pos.y - 1;
             let test_pos = CoordPos { x : precise_pos.x.round() as i32, y : y_up };
@@ -91,7 +51,7 @@ impl GoUpAI {
                     return Input::None;
                 }
                 else {
-                    //log!("Testing {:?} was safe", &test_pos);
+                    //debug_log!("Testing {:?} was safe", &test_pos);
                     if (self_pos_safe) {
                         return if (self.rng.gen_unit(("safe_idle", self.rng_t)) < 0.9)
                         {
@@ -106,7 +66,7 @@ impl GoUpAI {
                 }
             }
 
-            //log!("Testing {:?} NOT SAFE", &test_pos);
+            //debug_log!("Testing {:?} NOT SAFE", &test_pos);
             // TODO Fix not shuffling last elem correctly
             let mut to_try = [Input::None, Input::Left, Input::Right, Input::Down];
             self.rng.shuffle(("shuffle_inputs", self.rng_t), &mut to_try);
@@ -114,18 +74,18 @@ impl GoUpAI {
             for input in &to_try {
                 let try_pos = player_pos_coords.apply_input(*input);
                 if (is_safe(&try_pos, game_state, map, &mut self.draw_state)) {
-                    log!("AGENT {:?} picking shuffled {:?} as safe", self.player_id, input);
+                    debug_log!("AGENT {:?} picking shuffled {:?} as safe", self.player_id, input);
                     return *input;
                 }
             }
 
             // Last resort pick random
-            log!("AGENT {:?} resorting to random", self.player_id);
+            debug_log!("AGENT {:?} resorting to random", self.player_id);
             return *(to_try.first().unwrap());
         }
 
         // Player dead
-        return Input::None;
+        Input::None
     }
 }
 
@@ -138,12 +98,7 @@ fn is_safe_inner(coordpos : &CoordPos, game_state : &GameState, map : &Map, draw
     let current_row = map.get_row(game_state.get_round_id(), coordpos.y);
     match &current_row.row_type {
         RowType::River(_)  => {
-            if let Some(_lillipad) = map.lillipad_at_pos(game_state.get_round_id(), game_state.time_us, coordpos.to_precise(), &game_state.rules_state) {
-                true
-            }
-            else {
-                false
-            }
+            map.lillipad_at_pos(game_state.get_round_id(), game_state.time_us, coordpos.to_precise(), &game_state.rules_state).is_some()
         }
 
         RowType::Road(_) => {
@@ -222,7 +177,7 @@ impl AIAgent for GoUpAI
         match game_state.get_rule_state().fst
         {
             crossy_ruleset::CrossyRulesetFST::Lobby{..} => {
-                self.think_lobby(game_state, map)
+                think_lobby(self.player_id, game_state, map, &self.rng, self.rng_t)
             },
             _ => {
                 self.think_game(game_state, map)
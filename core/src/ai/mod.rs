@@ -0,0 +1,266 @@
+pub mod go_up;
+pub mod pathfind;
+
+use crate::{PlayerId, GameState, Input, CoordPos, Pos, crossy_ruleset};
+use crate::map::{Map, RowType};
+
+use froggy_rand::FroggyRand;
+
+use crate::draw_commands::DrawCommands;
+
+// Send so the server can hold agents inside its game state.
+pub trait AIAgent : std::fmt::Debug + Send
+{
+    fn think(&mut self, game_state: &GameState, map: &Map) -> Input;
+    fn get_drawstate(&self) -> &DrawCommands;
+}
+
+// Tuning for the pathfinding agent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AIConfig
+{
+    // Frames between deciding on a move and making it, the world keeps moving in the meantime.
+    pub reaction_delay_frames : u32,
+    // How many moves ahead we search
+    pub planning_horizon_steps : usize,
+    // Chance each move is swapped for a random one
+    pub error_rate : f64,
+    // Chance to push a neighbouring player when we get the opportunity
+    pub aggressiveness : f64,
+}
+
+pub const MAX_REACTION_DELAY_FRAMES : u32 = 60;
+pub const MAX_PLANNING_HORIZON_STEPS : usize = 24;
+
+impl AIConfig {
+    pub fn novice() -> Self {
+        Self {
+            reaction_delay_frames : 12,
+            planning_horizon_steps : 4,
+            error_rate : 0.12,
+            aggressiveness : 0.0,
+        }
+    }
+
+    pub fn normal() -> Self {
+        Self {
+            reaction_delay_frames : 6,
+            planning_horizon_steps : 8,
+            error_rate : 0.03,
+            aggressiveness : 0.25,
+        }
+    }
+
+    pub fn expert() -> Self {
+        Self {
+            reaction_delay_frames : 0,
+            planning_horizon_steps : 12,
+            error_rate : 0.0,
+            aggressiveness : 0.6,
+        }
+    }
+
+    fn set(&mut self, key : &str, value : &str) -> Result<(), String> {
+        let parse_err = || format!("Invalid value for {}: {}", key, value);
+        match key {
+            "reaction" => {
+                self.reaction_delay_frames = value.parse().map_err(|_| parse_err())?;
+                if (self.reaction_delay_frames > MAX_REACTION_DELAY_FRAMES) {
+                    return Err(format!("reaction must be at most {} frames", MAX_REACTION_DELAY_FRAMES));
+                }
+            },
+            "horizon" => {
+                self.planning_horizon_steps = value.parse().map_err(|_| parse_err())?;
+                if (self.planning_horizon_steps == 0 || self.planning_horizon_steps > MAX_PLANNING_HORIZON_STEPS) {
+                    return Err(format!("horizon must be between 1 and {}", MAX_PLANNING_HORIZON_STEPS));
+                }
+            },
+            "error" => {
+                self.error_rate = value.parse().map_err(|_| parse_err())?;
+                if !(0.0..=1.0).contains(&self.error_rate) {
+                    return Err("error must be between 0 and 1".to_owned());
+                }
+            },
+            "aggression" => {
+                self.aggressiveness = value.parse().map_err(|_| parse_err())?;
+                if !(0.0..=1.0).contains(&self.aggressiveness) {
+                    return Err("aggression must be between 0 and 1".to_owned());
+                }
+            },
+            _ => return Err(format!("Unknown ai option {}", key)),
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for AIConfig {
+    fn default() -> Self {
+        Self::normal()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AgentKind
+{
+    GoUp,
+    BackAndForth,
+    Pathfind(AIConfig),
+}
+
+// Config strings look like "expert" or "pathfind:horizon=6,error=0.1,aggression=0.5".
+// The presets (novice, normal, expert) are pathfinding agents and can have options applied on top.
+// Options are reaction (frames), horizon (moves), error and aggression (0 to 1).
+pub fn parse_agent(config : &str) -> Result<AgentKind, String> {
+    let (name, options) = match config.split_once(':') {
+        Some((name, options)) => (name, Some(options)),
+        None => (config, None),
+    };
+
+    let mut ai_config = match name.trim() {
+        "go_up" | "back_and_forth" if options.is_some() => {
+            return Err(format!("{} does not take options", name));
+        },
+        "go_up" => return Ok(AgentKind::GoUp),
+        "back_and_forth" => return Ok(AgentKind::BackAndForth),
+        "pathfind" | "normal" => AIConfig::normal(),
+        "novice" => AIConfig::novice(),
+        "expert" => AIConfig::expert(),
+        _ => return Err(format!("Unknown ai agent {}", name)),
+    };
+
+    for option in options.iter().flat_map(|x| x.split(',')).filter(|x| !x.trim().is_empty()) {
+        let (key, value) = option.split_once('=').ok_or_else(|| format!("Expected key=value, got {}", option))?;
+        ai_config.set(key.trim(), value.trim())?;
+    }
+
+    Ok(AgentKind::Pathfind(ai_config))
+}
+
+// Same config string gives the same behaviour on the client and the server,
+// agents only seed their randomness from the player id.
+pub fn create_agent(config : &str, player_id : PlayerId) -> Result<Box<dyn AIAgent>, String> {
+    Ok(match parse_agent(config)? {
+        AgentKind::GoUp => Box::new(go_up::GoUpAI::new(player_id)),
+        AgentKind::BackAndForth => Box::<BackAndForth>::default(),
+        AgentKind::Pathfind(ai_config) => Box::new(pathfind::PathfindingAI::with_config(player_id, ai_config)),
+    })
+}
+
+
+#[derive(Debug, Default)]
+pub struct BackAndForth
+{
+    draw_state : DrawCommands,
+}
+
+impl AIAgent for BackAndForth
+{
+    fn think(&mut self, game_state : &GameState, _ : &Map) -> Input
+    {
+        /*
+        if (game_state.frame_id % 60 == 0) {
+            Input::Left
+        }
+        else if (game_state.frame_id % 60 == 30) {
+            Input::Right
+        }
+        else {
+            Input::None
+        }
+        */
+
+        if (game_state.frame_id / 60).is_multiple_of(2) {
+            Input::Left
+        }
+        else {
+            Input::Right
+        }
+
+    }
+
+    fn get_drawstate(&self) -> &DrawCommands {
+        &self.draw_state
+    }
+}
+
+// Shared by all agents, walk down to the river then hop on the raft when it is underneath us.
+// Never step into someone else, pushing them off the bank drowns them.
+pub(crate) fn think_lobby(player_id : PlayerId, game_state : &GameState, map : &Map, rng : &FroggyRand, rng_t : u64) -> Input
+{
+    let maybe_player_state = game_state.get_player(player_id);
+    if let Some(player_state) = maybe_player_state {
+        if (crossy_ruleset::player_in_lobby_ready_zone(player_state)) {
+            return Input::None;
+        }
+
+        if let Pos::Coord(pos) = player_state.pos {
+            let below = CoordPos { x : pos.x, y : pos.y + 1 };
+            if let RowType::LobbyRiver = map.get_row(game_state.get_round_id(), below.y).row_type {
+                if let Some(lillipad) = map.lillipad_at_pos(game_state.get_round_id(), game_state.time_us, below.to_precise(), &game_state.rules_state) {
+                    if (game_state.space_occupied_with_player(Pos::Lillipad(lillipad), Some(player_id))) {
+                        let input = *rng.choose(("side_step", rng_t), &[Input::Left, Input::Right]);
+                        return step_if_free(player_id, game_state, pos, input);
+                    }
+
+                    return Input::Down;
+                }
+
+                if let crossy_ruleset::CrossyRulesetFST::Lobby { raft_pos, .. } = &game_state.rules_state.fst {
+                    // Aim for the middle of the raft
+                    let raft_centre = (*raft_pos + 1.5).round() as i32;
+                    if (pos.x < raft_centre) {
+                        return step_if_free(player_id, game_state, pos, Input::Right);
+                    }
+                    if (pos.x > raft_centre) {
+                        return step_if_free(player_id, game_state, pos, Input::Left);
+                    }
+                }
+
+                return Input::None;
+            }
+
+            return step_if_free(player_id, game_state, pos, Input::Down);
+        }
+    }
+
+    Input::None
+}
+
+fn step_if_free(player_id : PlayerId, game_state : &GameState, pos : CoordPos, input : Input) -> Input {
+    if (game_state.space_occupied_with_player(Pos::Coord(pos.apply_input(input)), Some(player_id))) {
+        Input::None
+    }
+    else {
+        input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_presets_and_options() {
+        assert_eq!(parse_agent("go_up"), Ok(AgentKind::GoUp));
+        assert_eq!(parse_agent("expert"), Ok(AgentKind::Pathfind(AIConfig::expert())));
+        assert_eq!(parse_agent("pathfind"), Ok(AgentKind::Pathfind(AIConfig::normal())));
+
+        let expected = AIConfig {
+            planning_horizon_steps : 6,
+            aggressiveness : 1.0,
+            ..AIConfig::novice()
+        };
+        assert_eq!(parse_agent("novice:horizon=6, aggression=1"), Ok(AgentKind::Pathfind(expected)));
+    }
+
+    #[test]
+    fn parse_rejects_bad_configs() {
+        assert!(parse_agent("grandmaster").is_err());
+        assert!(parse_agent("go_up:error=0.5").is_err());
+        assert!(parse_agent("expert:horizon=0").is_err());
+        assert!(parse_agent("expert:error=2").is_err());
+        assert!(parse_agent("expert:speed=10").is_err());
+        assert!(parse_agent("expert:reaction").is_err());
+    }
+}
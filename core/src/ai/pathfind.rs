@@ -0,0 +1,478 @@
+use std::collections::HashSet;
+
+use crate::{GameState, PlayerId, Input, Pos, crossy_ruleset};
+use crate::crossy_ruleset::{AliveState, RulesState};
+use crate::map::{Map, RowType};
+use crate::player::MOVE_DUR;
+use crate::timeline::TICK_INTERVAL_US;
+
+use froggy_rand::FroggyRand;
+
+use crate::ai::*;
+use crate::draw_commands::{DrawCommand, DrawCoords, DrawType, DrawColour};
+
+// When nothing in the normal horizon gets us any further up (eg the way through an icy section
+// is a long way round) we search a lot further. This is slow so only done when stuck.
+const STUCK_HORIZON_MULTIPLIER : usize = 4;
+
+// A move takes MOVE_DUR and then we can act again on the next frame.
+const STEP_US : u32 = MOVE_DUR + TICK_INTERVAL_US;
+
+// We check we would still be alive on every frame of each step.
+const SAFETY_SAMPLES : u32 = STEP_US / TICK_INTERVAL_US;
+
+// Lillipads kill you once they are this far off the screen (see should_kill)
+// we keep a margin so we are never relying on the last frame.
+const LILLIPAD_KILL_THRESH : f64 = 2.5;
+const LILLIPAD_EDGE_MARGIN : f64 = 1.0;
+
+// Order matters, earlier inputs win ties so we prefer going up as early as possible.
+const SEARCH_INPUTS : [Input; 5] = [Input::Up, Input::Left, Input::Right, Input::None, Input::Down];
+
+const PUSH_INPUTS : [Input; 4] = [Input::Up, Input::Left, Input::Right, Input::Down];
+
+// Searches forward in time over where we could be after each move,
+// using the real car and lillipad positions at that time.
+// Picks the path that gets furthest up the screen while surviving the whole horizon.
+// How well it plays is set by the AIConfig.
+#[derive(Debug)]
+pub struct PathfindingAI
+{
+    player_id : PlayerId,
+    config : AIConfig,
+    rng : FroggyRand,
+    rng_t : u64,
+    draw_state : DrawCommands,
+    // Row we could not get past with the normal horizon, keep using the long one until we are above it
+    // otherwise we oscillate between backing off and going straight back to where we were stuck.
+    stuck_y : Option<i32>,
+    // Decided on but waiting out our reaction delay
+    pending : Option<PendingInput>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingInput
+{
+    input : Input,
+    act_frame_id : u32,
+}
+
+struct SearchNode
+{
+    pos : Pos,
+    parent : usize,
+    input : Input,
+    // Landing on ice keeps us moving the same way whatever we press
+    sliding : Input,
+}
+
+struct PlannedStep
+{
+    pos : Pos,
+    time_us : u32,
+    input : Input,
+}
+
+impl PathfindingAI {
+    pub fn new(player_id : PlayerId) -> Self {
+        Self::with_config(player_id, AIConfig::default())
+    }
+
+    pub fn with_config(player_id : PlayerId, config : AIConfig) -> Self {
+        Self {
+            player_id,
+            config,
+            rng: FroggyRand::new(4321 + 555*(player_id.0 as u64)),
+            rng_t : 0,
+            draw_state : DrawCommands::default(),
+            stuck_y : None,
+            pending : None,
+        }
+    }
+
+    fn think_game(&mut self, game_state : &GameState, map : &Map) -> Input
+    {
+        if (game_state.rules_state.fst.get_player_alive(self.player_id) != AliveState::Alive) {
+            self.draw_state.commands.clear();
+            return Input::None;
+        }
+
+        let player_state = match game_state.get_player(self.player_id) {
+            Some(x) => x,
+            None => return Input::None,
+        };
+
+        // Inputs are ignored mid move, keep showing the last plan until we can act on a new one.
+        if (!player_state.can_move()) {
+            self.pending = None;
+            return Input::None;
+        }
+
+        if let Some(pending) = self.pending {
+            if (game_state.frame_id < pending.act_frame_id) {
+                return Input::None;
+            }
+
+            self.pending = None;
+            return pending.input;
+        }
+
+        let input = self.decide(game_state, map, player_state.pos);
+        if (input == Input::None || self.config.reaction_delay_frames == 0) {
+            return input;
+        }
+
+        self.pending = Some(PendingInput {
+            input,
+            act_frame_id : game_state.frame_id + self.config.reaction_delay_frames,
+        });
+
+        Input::None
+    }
+
+    fn decide(&mut self, game_state : &GameState, map : &Map, pos : Pos) -> Input
+    {
+        self.draw_state.commands.clear();
+
+        if (self.config.aggressiveness > 0.0 && self.rng.gen_unit(("aggression", self.rng_t)) < self.config.aggressiveness) {
+            if let Some(input) = self.find_push(game_state, map, pos) {
+                debug_log!("AGENT {:?} pushing {:?}", self.player_id, input);
+                return input;
+            }
+        }
+
+        let y = pos.get_y_grid();
+        if (self.stuck_y.map(|stuck_y| y < stuck_y).unwrap_or(false)) {
+            self.stuck_y = None;
+        }
+
+        let horizon_steps = self.config.planning_horizon_steps;
+        let target_y = self.stuck_y.unwrap_or(y);
+        let mut path = self.plan(game_state, map, pos, horizon_steps);
+        if (path.last().map(|x| x.pos.get_y_grid() >= target_y).unwrap_or(true)) {
+            self.stuck_y = Some(target_y);
+            path = self.plan(game_state, map, pos, horizon_steps * STUCK_HORIZON_MULTIPLIER);
+        }
+
+        if (self.config.error_rate > 0.0 && self.rng.gen_unit(("error", self.rng_t)) < self.config.error_rate) {
+            return *self.rng.choose(("error_input", self.rng_t), &SEARCH_INPUTS);
+        }
+
+        if (path.len() < 2) {
+            // Nothing survives, may as well try something
+            debug_log!("AGENT {:?} has no safe plan", self.player_id);
+            return *self.rng.choose(("no_plan", self.rng_t), &SEARCH_INPUTS);
+        }
+
+        self.draw_plan(&path, game_state, map);
+        path[1].input
+    }
+
+    // A push we can make right now that we survive and, ideally, they dont.
+    fn find_push(&mut self, game_state : &GameState, map : &Map, pos : Pos) -> Option<Input>
+    {
+        let rules_state = &game_state.rules_state;
+        let mut best = None;
+
+        for input in PUSH_INPUTS {
+            let target = match map.try_apply_input(game_state.time_us, rules_state, &pos, input) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let other = game_state.player_states.iter().find(|(id, x)| {
+                *id != self.player_id
+                    && rules_state.fst.get_player_alive(*id) == AliveState::Alive
+                    && x.pos == target
+                    && x.can_move()
+            });
+
+            let (other_id, other_state) = match other {
+                Some(x) => x,
+                None => continue,
+            };
+
+            if (!game_state.can_push(other_id, input, game_state.time_us, rules_state, map)) {
+                continue;
+            }
+
+            if (!step_safe(map, rules_state, &pos, &target, game_state.time_us)) {
+                continue;
+            }
+
+            let pushed_to = map.try_apply_input(game_state.time_us, rules_state, &other_state.pos, input).unwrap();
+            let lethal = !step_safe(map, rules_state, &other_state.pos, &pushed_to, game_state.time_us);
+
+            self.draw_state.commands.push(DrawCommand {
+                pos : DrawCoords::from_precise(map.realise_pos(game_state.time_us, &other_state.pos, &rules_state.fst)),
+                draw_type : DrawType::Circle,
+                colour : if (lethal) { DrawColour::Red } else { DrawColour::Grey },
+            });
+
+            if (lethal) {
+                return Some(input);
+            }
+
+            // Shoving people backwards is no use to us
+            if (best.is_none() && input != Input::Down) {
+                best = Some(input);
+            }
+        }
+
+        best
+    }
+
+    fn plan(&self, game_state : &GameState, map : &Map, start : Pos, horizon_steps : usize) -> Vec<PlannedStep>
+    {
+        let rules_state = &game_state.rules_state;
+        let round_id = game_state.get_round_id();
+
+        let mut layers = vec![vec![SearchNode {
+            pos : start,
+            parent : 0,
+            input : Input::None,
+            sliding : Input::None,
+        }]];
+
+        for step in 0..horizon_steps {
+            let time_us = game_state.time_us + step as u32 * STEP_US;
+            let mut next = Vec::new();
+            let mut seen = HashSet::new();
+
+            for (parent, node) in layers[step].iter().enumerate() {
+                let inputs : &[Input] = if (node.sliding != Input::None) {
+                    std::slice::from_ref(&node.sliding)
+                }
+                else {
+                    &SEARCH_INPUTS
+                };
+
+                for &input in inputs {
+                    let target = if (input == Input::None) {
+                        Some(node.pos)
+                    }
+                    else {
+                        map.try_apply_input(time_us, rules_state, &node.pos, input)
+                    };
+
+                    let (target, input) = match target {
+                        Some(x) => (x, input),
+                        // Sliding into a wall stops us
+                        None if node.sliding != Input::None => (node.pos, Input::None),
+                        None => continue,
+                    };
+
+                    // Dont plan on walking into anyone, we only know where they are right now.
+                    if (step == 0 && input != Input::None && game_state.space_occupied_with_player(target, Some(self.player_id))) {
+                        continue;
+                    }
+
+                    let sliding = match (&node.pos, &target) {
+                        (Pos::Coord(_), Pos::Coord(coord)) if input != Input::None => {
+                            if let RowType::IcyRow(_) = map.get_row(round_id, coord.y).row_type {
+                                input
+                            }
+                            else {
+                                Input::None
+                            }
+                        },
+                        _ => Input::None,
+                    };
+
+                    let key = (pos_key(&target), sliding as i32);
+                    if (seen.contains(&key)) {
+                        continue;
+                    }
+
+                    if (step_safe(map, rules_state, &node.pos, &target, time_us)) {
+                        seen.insert(key);
+                        next.push(SearchNode {
+                            pos : target,
+                            parent,
+                            input,
+                            sliding,
+                        });
+                    }
+                }
+            }
+
+            if (next.is_empty()) {
+                break;
+            }
+
+            layers.push(next);
+        }
+
+        // Furthest up out of everything that survived longest, ties go to whoever was found first.
+        let last_layer = layers.len() - 1;
+        let mut index = layers[last_layer].iter()
+            .enumerate()
+            .min_by_key(|(_, node)| node.pos.get_y_grid())
+            .map(|(i, _)| i)
+            .unwrap();
+
+        let mut path = Vec::with_capacity(layers.len());
+        for layer in (0..=last_layer).rev() {
+            let node = &layers[layer][index];
+            path.push(PlannedStep {
+                pos : node.pos,
+                time_us : game_state.time_us + layer as u32 * STEP_US,
+                input : node.input,
+            });
+            index = node.parent;
+        }
+
+        path.reverse();
+        path
+    }
+
+    fn draw_plan(&mut self, path : &[PlannedStep], game_state : &GameState, map : &Map)
+    {
+        let fst = &game_state.rules_state.fst;
+        let realise = |step : &PlannedStep| DrawCoords::from_precise(map.realise_pos(step.time_us, &step.pos, fst));
+
+        for pair in path.windows(2) {
+            self.draw_state.commands.push(DrawCommand {
+                pos : realise(&pair[0]),
+                draw_type : DrawType::Line(realise(&pair[1])),
+                colour : DrawColour::White,
+            });
+        }
+
+        if let Some(last) = path.last() {
+            self.draw_state.commands.push(DrawCommand {
+                pos : realise(last),
+                draw_type : DrawType::Tick,
+                colour : DrawColour::Green,
+            });
+        }
+    }
+}
+
+// Lillipad ids are only unique within a row
+fn pos_key(pos : &Pos) -> (i32, i32, bool) {
+    match pos {
+        Pos::Coord(coord) => (coord.x, coord.y, false),
+        Pos::Lillipad(lillipad) => (lillipad.id as i32, lillipad.y, true),
+        Pos::Absolute(_) => unreachable!(),
+    }
+}
+
+// Mirrors should_kill, sampled over a move from `from` to `to` starting at `start_us`.
+fn step_safe(map : &Map, rules_state : &RulesState, from : &Pos, to : &Pos, start_us : u32) -> bool {
+    for i in 0..SAFETY_SAMPLES {
+        let offset_us = i * TICK_INTERVAL_US;
+
+        // While moving we are still at the old position, but cars are checked against where we are going.
+        let at = if (offset_us < MOVE_DUR) { from } else { to };
+        if (!pos_safe(map, rules_state, at, to, start_us + offset_us)) {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn pos_safe(map : &Map, rules_state : &RulesState, at : &Pos, moving_to : &Pos, time_us : u32) -> bool {
+    let round_id = rules_state.fst.get_round_id();
+
+    match at {
+        Pos::Coord(coord) => {
+            // Falling off the bottom of the screen
+            if (coord.y > rules_state.fst.get_screen_y() + crate::SCREEN_SIZE) {
+                return false;
+            }
+
+            if let RowType::River(_) = map.get_row(round_id, coord.y).row_type {
+                return false;
+            }
+
+            let car_check_pos = match moving_to {
+                Pos::Coord(target) => *target,
+                _ => *coord,
+            };
+
+            !map.collides_car(time_us, round_id, car_check_pos)
+        },
+        Pos::Lillipad(lillipad) => {
+            let x = map.get_lillipad_screen_x(time_us, lillipad, &rules_state.fst);
+            let min_x = -LILLIPAD_KILL_THRESH + LILLIPAD_EDGE_MARGIN;
+            let max_x = crate::SCREEN_SIZE as f64 + LILLIPAD_KILL_THRESH - LILLIPAD_EDGE_MARGIN;
+            x > min_x && x < max_x
+        },
+        Pos::Absolute(_) => false,
+    }
+}
+
+impl AIAgent for PathfindingAI
+{
+    fn think(&mut self, game_state : &GameState, map : &Map) -> Input
+    {
+        self.rng_t += 1;
+
+        match game_state.get_rule_state().fst
+        {
+            crossy_ruleset::CrossyRulesetFST::Lobby{..} => {
+                self.draw_state.commands.clear();
+                think_lobby(self.player_id, game_state, map, &self.rng, self.rng_t)
+            },
+            _ => {
+                self.think_game(game_state, map)
+            }
+        }
+    }
+
+    fn get_drawstate(&self) -> &DrawCommands {
+        &self.draw_state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::{Timeline, RemoteInput};
+    use crate::crossy_ruleset::{CrossyRulesetFST, GameConfig};
+
+    #[test]
+    fn crosses_roads_and_rivers_alone() {
+        let config = GameConfig {
+            minimum_players : 1,
+            bypass_lobby : true,
+            ..GameConfig::default()
+        };
+
+        let player_id = PlayerId(1);
+        let mut timeline = Timeline::from_seed(config, "pathfind");
+        timeline.add_player(player_id, Pos::new_coord(10, 10));
+        let mut agent = PathfindingAI::with_config(player_id, AIConfig::expert());
+
+        let mut start_y = None;
+        for _ in 0..60 * 60 {
+            timeline.tick(None, TICK_INTERVAL_US);
+
+            let top = timeline.top_state().clone();
+            let input = agent.think(&top, &timeline.map);
+            if (input != Input::None) {
+                timeline.try_propagate_inputs(vec![RemoteInput {
+                    time_us : top.time_us,
+                    frame_id : top.frame_id,
+                    input,
+                    player_id,
+                }], true);
+            }
+
+            let top = timeline.top_state();
+            if let CrossyRulesetFST::Round(round_state) = &top.rules_state.fst {
+                assert_eq!(round_state.alive_states.get_copy(player_id), Some(AliveState::Alive), "Died at {:?}", top.get_player(player_id));
+
+                let y = top.get_player(player_id).unwrap().pos.get_y_grid();
+                let start_y = *start_y.get_or_insert(y);
+                if (start_y - y >= 40) {
+                    return;
+                }
+            }
+        }
+
+        panic!("Did not make it 40 rows up");
+    }
+}
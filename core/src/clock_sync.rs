@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+
+use crate::interop::TimeRequestEnd;
+
+// Estimates the offset between a client clock and the server clock from ping round trips.
+//
+// Each TimeRequestEnd gives four timestamps, t0 client send, t1 server receive, t2 server send
+// and t3 client receive. From them we get the round trip excluding server time
+//   rtt = (t3 - t0) - (t2 - t1)
+// and the ntp offset, server time minus client time, assuming the trip is symmetric
+//   offset = ((t1 - t0) + (t2 - t3)) / 2
+//
+// Single samples are noisy and queueing only ever makes a trip slower, so we keep a window
+// of recent samples, throw out the ones far from the median and trust the fastest round trips.
+// The offset actually handed out is slewed towards that estimate so the client clock never
+// jumps around, unless it is so far off that catching up slowly would be worse.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSyncConfig
+{
+    // Number of recent samples the estimate is made from
+    pub window : usize,
+    // Samples further than this many median absolute deviations from the median are ignored
+    pub outlier_mads : f64,
+    // Smallest deviation used for outlier rejection, stops a very steady window rejecting everything
+    pub min_deviation_us : i64,
+    // Fraction of the remaining samples, fastest round trip first, that the offset is averaged over
+    pub best_fraction : f64,
+    // Most the applied offset can move per second of client time
+    pub max_slew_us_per_sec : i64,
+    // Errors bigger than this are corrected immediately rather than slewed
+    pub step_threshold_us : i64,
+}
+
+impl Default for ClockSyncConfig {
+    fn default() -> Self {
+        Self {
+            window : 16,
+            outlier_mads : 3.0,
+            min_deviation_us : 1_000,
+            best_fraction : 0.25,
+            // 2ms per second, about a frame every 8 seconds
+            max_slew_us_per_sec : 2_000,
+            step_threshold_us : 250_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample
+{
+    pub rtt_us : i64,
+    pub offset_us : i64,
+    // Client time the response arrived
+    pub client_time_us : i64,
+}
+
+impl ClockSample {
+    pub fn from_time_request(time_request_end : &TimeRequestEnd) -> Option<Self> {
+        let t0 = time_request_end.client_send_time_us as i64;
+        let t1 = time_request_end.server_receive_time_us as i64;
+        let t2 = time_request_end.server_send_time_us as i64;
+        let t3 = time_request_end.client_receive_time_us as i64;
+
+        let rtt_us = (t3 - t0) - (t2 - t1);
+        if (t3 < t0 || t2 < t1 || rtt_us < 0) {
+            // Clock wrapped or the packet is garbage
+            return None;
+        }
+
+        Some(Self {
+            rtt_us,
+            offset_us : ((t1 - t0) + (t2 - t3)) / 2,
+            client_time_us : t3,
+        })
+    }
+
+    pub fn latency_us(&self) -> i64 {
+        self.rtt_us / 2
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClockSync
+{
+    config : ClockSyncConfig,
+    samples : VecDeque<ClockSample>,
+
+    target_offset_us : Option<i64>,
+    latency_us : Option<i64>,
+
+    // What we hand out, moves towards target_offset_us
+    applied_offset_us : Option<f64>,
+    last_update_client_time_us : Option<i64>,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new(ClockSyncConfig::default())
+    }
+}
+
+impl ClockSync {
+    pub fn new(config : ClockSyncConfig) -> Self {
+        Self {
+            config,
+            samples : VecDeque::with_capacity(config.window),
+            target_offset_us : None,
+            latency_us : None,
+            applied_offset_us : None,
+            last_update_client_time_us : None,
+        }
+    }
+
+    // Returns the sample when it was usable.
+    pub fn add_time_request(&mut self, time_request_end : &TimeRequestEnd) -> Option<ClockSample> {
+        let sample = ClockSample::from_time_request(time_request_end)?;
+        self.add_sample(sample);
+        Some(sample)
+    }
+
+    pub fn add_sample(&mut self, sample : ClockSample) {
+        if (self.samples.len() >= self.config.window.max(1)) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.recompute();
+    }
+
+    // Moves the applied offset towards the estimate, call with the current client time
+    // every tick so the correction is spread out.
+    pub fn update(&mut self, client_time_us : i64) {
+        let target = match self.target_offset_us {
+            Some(x) => x as f64,
+            None => return,
+        };
+
+        let applied = match (self.applied_offset_us, self.last_update_client_time_us) {
+            (Some(applied), Some(last_time_us)) => {
+                let error = target - applied;
+                if (error.abs() > self.config.step_threshold_us as f64) {
+                    debug_log!("Clock sync stepping by {}us", error as i64);
+                    target
+                }
+                else {
+                    let dt_us = (client_time_us - last_time_us).max(0) as f64;
+                    let max_step = self.config.max_slew_us_per_sec as f64 * dt_us / 1_000_000.0;
+                    applied + error.clamp(-max_step, max_step)
+                }
+            }
+            _ => target,
+        };
+
+        self.applied_offset_us = Some(applied);
+        self.last_update_client_time_us = Some(client_time_us);
+    }
+
+    // Best estimate of server time minus client time, ignoring slewing
+    pub fn target_offset_us(&self) -> Option<i64> {
+        self.target_offset_us
+    }
+
+    // Server time minus client time as it should currently be used
+    pub fn offset_us(&self) -> Option<i64> {
+        self.applied_offset_us.map(|x| x.round() as i64)
+    }
+
+    pub fn server_time_us(&self, client_time_us : i64) -> Option<i64> {
+        self.offset_us().map(|x| client_time_us + x)
+    }
+
+    // One way latency from the fastest recent round trips
+    pub fn latency_us(&self) -> Option<i64> {
+        self.latency_us
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn recompute(&mut self) {
+        if (self.samples.is_empty()) {
+            return;
+        }
+
+        let offsets : Vec<i64> = self.samples.iter().map(|x| x.offset_us).collect();
+        let rtts : Vec<i64> = self.samples.iter().map(|x| x.rtt_us).collect();
+        let offset_median = median(&offsets);
+        let rtt_median = median(&rtts);
+        let offset_limit = self.deviation_limit(&offsets, offset_median);
+        let rtt_limit = self.deviation_limit(&rtts, rtt_median);
+
+        // Fast round trips are never outliers, that is what we are looking for
+        let mut kept : Vec<ClockSample> = self.samples.iter()
+            .filter(|x| (x.offset_us - offset_median).abs() <= offset_limit)
+            .filter(|x| x.rtt_us - rtt_median <= rtt_limit)
+            .copied()
+            .collect();
+
+        if (kept.is_empty()) {
+            // Cant happen as the median sample is always kept, but dont leave the estimate stale
+            kept = self.samples.iter().copied().collect();
+        }
+
+        kept.sort_by_key(|x| x.rtt_us);
+        let best_count = ((kept.len() as f64 * self.config.best_fraction).ceil() as usize).clamp(1, kept.len());
+        let best = &kept[..best_count];
+
+        let offset_sum : i64 = best.iter().map(|x| x.offset_us).sum();
+        let rtt_sum : i64 = best.iter().map(|x| x.rtt_us).sum();
+        self.target_offset_us = Some(offset_sum / best_count as i64);
+        self.latency_us = Some(rtt_sum / best_count as i64 / 2);
+    }
+
+    fn deviation_limit(&self, values : &[i64], median_value : i64) -> i64 {
+        let deviations : Vec<i64> = values.iter().map(|x| (x - median_value).abs()).collect();
+        let mad = median(&deviations).max(self.config.min_deviation_us);
+        (mad as f64 * self.config.outlier_mads) as i64
+    }
+}
+
+fn median(values : &[i64]) -> i64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted[sorted.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use froggy_rand::FroggyRand;
+
+    const OFFSET_US : i64 = 5_000_000;
+    const LATENCY_US : i64 = 40_000;
+
+    // Simulates a ping with the given extra delay on each leg.
+    fn ping(client_send_us : i64, offset_us : i64, up_jitter_us : i64, down_jitter_us : i64) -> TimeRequestEnd {
+        let server_receive = client_send_us + offset_us + LATENCY_US + up_jitter_us;
+        let server_send = server_receive + 500;
+        let client_receive = server_send - offset_us + LATENCY_US + down_jitter_us;
+        TimeRequestEnd {
+            client_send_time_us : client_send_us as u32,
+            server_receive_time_us : server_receive as u32,
+            server_send_time_us : server_send as u32,
+            client_receive_time_us : client_receive as u32,
+        }
+    }
+
+    fn jitter(rng : &FroggyRand, seed : (u32, u32)) -> i64 {
+        (rng.gen_unit(seed) * 20_000.0) as i64
+    }
+
+    #[test]
+    fn sample_maths() {
+        let sample = ClockSample::from_time_request(&ping(1_000, OFFSET_US, 0, 0)).unwrap();
+        assert_eq!(sample.offset_us, OFFSET_US);
+        assert_eq!(sample.latency_us(), LATENCY_US);
+
+        let backwards = TimeRequestEnd {
+            client_send_time_us : 100,
+            server_receive_time_us : 0,
+            server_send_time_us : 0,
+            client_receive_time_us : 50,
+        };
+        assert!(ClockSample::from_time_request(&backwards).is_none());
+    }
+
+    #[test]
+    fn converges_under_jitter() {
+        let rng = FroggyRand::new(1);
+        let mut sync = ClockSync::default();
+        for i in 0..64 {
+            let time_request = ping(i as i64 * 500_000, OFFSET_US, jitter(&rng, (i, 0)), jitter(&rng, (i, 1)));
+            sync.add_time_request(&time_request);
+        }
+
+        // Naive averaging is out by half the mean asymmetry, the fastest trips are much closer
+        let error = (sync.target_offset_us().unwrap() - OFFSET_US).abs();
+        assert!(error < 3_000, "offset error {}us", error);
+        let latency_error = (sync.latency_us().unwrap() - LATENCY_US).abs();
+        assert!(latency_error < 5_000, "latency error {}us", latency_error);
+    }
+
+    #[test]
+    fn rejects_spikes() {
+        let rng = FroggyRand::new(2);
+        let mut sync = ClockSync::default();
+        for i in 0..64 {
+            // Every fifth ping gets stuck in a queue on the way back
+            let spike = if (i % 5 == 0) { 400_000 } else { 0 };
+            let time_request = ping(i as i64 * 500_000, OFFSET_US, jitter(&rng, (i, 0)), jitter(&rng, (i, 1)) + spike);
+            sync.add_time_request(&time_request);
+        }
+
+        let error = (sync.target_offset_us().unwrap() - OFFSET_US).abs();
+        assert!(error < 3_000, "offset error {}us", error);
+    }
+
+    #[test]
+    fn slews_small_corrections() {
+        let mut sync = ClockSync::default();
+        sync.add_time_request(&ping(0, OFFSET_US, 0, 0));
+        sync.update(0);
+        assert_eq!(sync.offset_us(), Some(OFFSET_US));
+
+        // Server clock turns out to be 20ms further ahead
+        for i in 0..16 {
+            sync.add_time_request(&ping(i * 100_000, OFFSET_US + 20_000, 0, 0));
+        }
+        assert_eq!(sync.target_offset_us(), Some(OFFSET_US + 20_000));
+
+        sync.update(1_000_000);
+        assert_eq!(sync.offset_us(), Some(OFFSET_US + 2_000));
+
+        sync.update(20_000_000);
+        assert_eq!(sync.offset_us(), Some(OFFSET_US + 20_000));
+    }
+
+    #[test]
+    fn steps_large_corrections() {
+        let mut sync = ClockSync::default();
+        sync.add_time_request(&ping(0, OFFSET_US, 0, 0));
+        sync.update(0);
+
+        // eg the tab was suspended, no point taking minutes to catch up
+        for i in 0..16 {
+            sync.add_time_request(&ping(i * 100_000, OFFSET_US + 1_000_000, 0, 0));
+        }
+        sync.update(16_666);
+        assert_eq!(sync.offset_us(), Some(OFFSET_US + 1_000_000));
+    }
+}
@@ -107,6 +107,11 @@ pub struct RulesState
     pub game_id : u32,
     pub fst : CrossyRulesetFST,
     pub config : GameConfig,
+
+    // Player allowed to change the config and kick people while in the lobby.
+    pub host : Option<PlayerId>,
+    // When locked the server refuses new joins.
+    pub locked : bool,
 }
 
 impl RulesState {
@@ -115,7 +120,26 @@ impl RulesState {
             game_id: 0,
             fst: CrossyRulesetFST::start(),
             config,
+            host: None,
+            locked: false,
+        }
+    }
+}
+
+pub const MAX_REQUIRED_WIN_COUNT : u8 = 10;
+pub const MAX_PLAYERS : u8 = 8;
+
+impl GameConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if (self.required_win_count == 0 || self.required_win_count > MAX_REQUIRED_WIN_COUNT) {
+            return Err("required_win_count out of range");
+        }
+
+        if (self.minimum_players == 0 || self.minimum_players > MAX_PLAYERS) {
+            return Err("minimum_players out of range");
         }
+
+        Ok(())
     }
 }
 
@@ -141,6 +165,8 @@ impl RulesState
                 game_id: self.game_id + 1,
                 fst: new_fst,
                 config: self.config.clone(),
+                host: self.host,
+                locked: self.locked,
             }
         }
         else
@@ -149,6 +175,8 @@ impl RulesState
                 game_id: self.game_id,
                 fst: new_fst,
                 config: self.config.clone(),
+                host: self.host,
+                locked: self.locked,
             }
         }
     }
@@ -489,7 +517,22 @@ fn update_screen_y(mut screen_y : i32, player_states : &PlayerIdMap<PlayerState>
     screen_y
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum DeathCause {
+    // Fell too far behind the screen
+    OffScreen,
+    Drowned,
+    HitByCar,
+    // Carried off the side of the screen on a lillipad
+    SweptAway,
+}
+
 fn should_kill(time_us : u32, round_id : u8, map : &Map, player_state : &PlayerState, screen_y : i32, ruleset_fst: &CrossyRulesetFST) -> bool{
+    death_cause(time_us, round_id, map, player_state, screen_y, ruleset_fst).is_some()
+}
+
+// Why a player in this state would be killed, if they would be.
+pub fn death_cause(time_us : u32, round_id : u8, map : &Map, player_state : &PlayerState, screen_y : i32, ruleset_fst: &CrossyRulesetFST) -> Option<DeathCause> {
     // TODO also check position you are moving to
     //if let Stationary = player_state.move_state {
         match &player_state.pos {
@@ -498,13 +541,13 @@ fn should_kill(time_us : u32, round_id : u8, map : &Map, player_state : &PlayerS
                 const SCREEN_KILL_BUFFER : i32 = 4;
                 if y > screen_y + crate::SCREEN_SIZE + SCREEN_KILL_BUFFER {
                     debug_log!("Killing, off the end of the screen {:?} {:?}", player_state.id, player_state.pos);
-                    return true;
+                    return Some(DeathCause::OffScreen);
                 }
 
                 let row = map.get_row(round_id, y);
                 if let RowType::River(_) = row.row_type {
                     debug_log!("Killing, walked into river {:?} {:?}", player_state.id, player_state.pos);
-                    return true;
+                    return Some(DeathCause::Drowned);
                 }
 
                 let mut coord_pos_to_check_car_collision = *coord_pos;
@@ -518,15 +561,20 @@ fn should_kill(time_us : u32, round_id : u8, map : &Map, player_state : &PlayerS
                 }
 
                 if map.collides_car(time_us, round_id, coord_pos_to_check_car_collision) {
-                    return true;
+                    return Some(DeathCause::HitByCar);
                 }
 
-                false
+                None
             },
             Pos::Lillipad(lillypad_id) => {
                 let precise_pos = map.get_lillipad_screen_x(time_us, lillypad_id, ruleset_fst);
                 const KILL_OFF_MAP_THRESH : f64 = 2.5;
-                precise_pos < -KILL_OFF_MAP_THRESH || precise_pos > (160.0 / 8.0 + KILL_OFF_MAP_THRESH)
+                if precise_pos < -KILL_OFF_MAP_THRESH || precise_pos > (160.0 / 8.0 + KILL_OFF_MAP_THRESH) {
+                    Some(DeathCause::SweptAway)
+                }
+                else {
+                    None
+                }
             },
             _ => {
                 unreachable!()
@@ -24,6 +24,14 @@ pub enum CrossyMessage {
 
     TelemetryMessagePackage(TelemetryMessagePackage),
 
+    ClientChat(ClientChat),
+    ServerChat(ServerChat),
+
+    HostCommand(HostCommand),
+    PlayerKicked(PlayerKicked),
+
+    MessageRejected(MessageRejected),
+
     GoodBye(),
 
     EmptyMessage(),
@@ -41,6 +49,46 @@ pub struct InitServerResponse {
     pub player_count: u8,
     pub seed: u32,
     pub player_id: crate::game::PlayerId,
+    pub player_name: String,
+    pub roster: PlayerIdMap<PlayerRosterEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PlayerRosterEntry {
+    pub name: String,
+    #[serde(default)]
+    pub is_bot: bool,
+}
+
+pub const MAX_PLAYER_NAME_LENGTH: usize = 16;
+
+// Names come straight from a query parameter so strip anything that isnt plain text
+// and clamp the length so they fit above a frog.
+pub fn sanitize_player_name(name : &str) -> String {
+    let mut sanitized = String::with_capacity(MAX_PLAYER_NAME_LENGTH);
+    let mut count = 0;
+    let mut last_was_space = true;
+
+    for c in name.chars() {
+        if (count >= MAX_PLAYER_NAME_LENGTH) {
+            break;
+        }
+
+        if c.is_whitespace() {
+            if (!last_was_space) {
+                sanitized.push(' ');
+                count += 1;
+                last_was_space = true;
+            }
+        }
+        else if c.is_alphanumeric() || c == '_' || c == '-' {
+            sanitized.push(c);
+            count += 1;
+            last_was_space = false;
+        }
+    }
+
+    sanitized.trim_end().to_owned()
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -81,6 +129,118 @@ pub struct LindenServerTick {
     pub delta_inputs : Vec<crate::timeline::RemoteInput>,
     pub last_client_frame_id : PlayerIdMap<u32>,
     pub rules_state : RulesState,
+    pub roster : PlayerIdMap<PlayerRosterEntry>,
+}
+
+// Emotes are a fixed set so native clients can map each id to an animation played above the frog.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum Emote {
+    Wave = 0,
+    Laugh = 1,
+    Angry = 2,
+    Cry = 3,
+    Heart = 4,
+    ThumbsUp = 5,
+}
+
+pub const ALL_EMOTES : [Emote; 6] = [
+    Emote::Wave,
+    Emote::Laugh,
+    Emote::Angry,
+    Emote::Cry,
+    Emote::Heart,
+    Emote::ThumbsUp,
+];
+
+impl Emote {
+    pub fn from_id(id : u8) -> Option<Self> {
+        num_traits::FromPrimitive::from_u8(id)
+    }
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum ChatContent {
+    Text(String),
+    Emote(Emote),
+}
+
+// Sent from a client, the frame_id is the client frame the message was sent on.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ClientChat {
+    pub frame_id : u32,
+    pub content : ChatContent,
+}
+
+// Relayed by the server to everyone in the game.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ServerChat {
+    pub player_id : crate::game::PlayerId,
+    pub frame_id : u32,
+    pub content : ChatContent,
+}
+
+// Sent by the host while in the lobby, the server ignores them from anyone else.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum HostCommand {
+    SetRequiredWinCount(u8),
+    SetMinimumPlayers(u8),
+    SetLocked(bool),
+    Kick(crate::game::PlayerId),
+    TransferHost(crate::game::PlayerId),
+    // Server controlled player, takes an ai config string eg "novice"
+    AddBot(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PlayerKicked {
+    pub player_id : crate::game::PlayerId,
+    // HACKY only server understands this type
+    pub socket_id : u32,
+}
+
+// Limits on what the server accepts in a single client message.
+pub const MAX_CLIENT_MESSAGE_BYTES: usize = 32 * 1024;
+pub const MAX_CLIENT_TICKS_PER_MESSAGE: usize = 128;
+pub const MAX_TELEMETRY_MESSAGES_PER_PACKAGE: usize = 128;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    MessageTooLarge,
+    TooManyEntries,
+    Malformed,
+}
+
+// Sent back to a client when the server drops one of its messages.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct MessageRejected {
+    pub reason : RejectionReason,
+}
+
+impl CrossyMessage {
+    pub fn within_collection_limits(&self) -> bool {
+        match self {
+            CrossyMessage::ClientTick(ticks) => ticks.len() <= MAX_CLIENT_TICKS_PER_MESSAGE,
+            CrossyMessage::TelemetryMessagePackage(package) => package.messages.len() <= MAX_TELEMETRY_MESSAGES_PER_PACKAGE,
+            _ => true,
+        }
+    }
+}
+
+pub const MAX_CHAT_LENGTH: usize = 120;
+
+pub fn sanitize_chat_text(text : &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CHAT_LENGTH)
+        .collect::<String>()
+        .trim()
+        .to_owned()
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -162,4 +322,46 @@ pub struct Telemetry_PingOutcome
 
     pub current_client_time_ms : u32,
     pub current_client_date_time_ms : u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_strips_markup() {
+        assert_eq!(sanitize_player_name("<b>dan</b>"), "bdanb");
+        assert_eq!(sanitize_player_name("  frog   king "), "frog king");
+        assert_eq!(sanitize_player_name("\n\t"), "");
+    }
+
+    #[test]
+    fn emote_ids_round_trip() {
+        for emote in ALL_EMOTES {
+            assert_eq!(Emote::from_id(emote.id()), Some(emote));
+        }
+
+        assert_eq!(Emote::from_id(ALL_EMOTES.len() as u8), None);
+    }
+
+    #[test]
+    fn client_tick_collection_limit() {
+        let tick = ClientTick {
+            time_us: 0,
+            frame_id: 0,
+            input: crate::game::Input::None,
+        };
+
+        assert!(CrossyMessage::ClientTick(vec![tick.clone(); MAX_CLIENT_TICKS_PER_MESSAGE]).within_collection_limits());
+        assert!(!CrossyMessage::ClientTick(vec![tick; MAX_CLIENT_TICKS_PER_MESSAGE + 1]).within_collection_limits());
+    }
+
+    #[test]
+    fn sanitize_limits_length() {
+        let long = "a".repeat(100);
+        assert_eq!(sanitize_player_name(&long).chars().count(), MAX_PLAYER_NAME_LENGTH);
+
+        let unicode = "🐸".repeat(4) + &"é".repeat(100);
+        assert_eq!(sanitize_player_name(&unicode), "é".repeat(MAX_PLAYER_NAME_LENGTH));
+    }
 }
\ No newline at end of file
@@ -59,8 +59,14 @@ pub mod timeline;
 pub mod crossy_ruleset;
 pub mod map;
 pub mod telemetry;
+pub mod clock_sync;
+pub mod net_sim;
+pub mod udp_protocol;
 pub mod ring_buffer;
 pub mod math;
 pub mod bitmap;
+pub mod ai;
+pub mod draw_commands;
+pub mod round_end_predictor;
 
 pub use game::*;
\ No newline at end of file
@@ -0,0 +1,329 @@
+use std::str::FromStr;
+
+use froggy_rand::FroggyRand;
+use serde::{Deserialize, Serialize};
+
+// Makes a perfectly good connection bad on purpose, so the rollback and late input handling
+// can be exercised on a developer machine.
+//
+// Messages go in with send and come back out of poll once their simulated delivery time has passed.
+// Everything is driven by the caller's clock and a seeded rng so runs are reproducible.
+
+// Anything worse than this isnt a network, its an outage
+pub const MAX_SIM_LATENCY_MS : u32 = 5_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConditions
+{
+    // One way delay added to every message
+    pub latency_ms : u32,
+    // Extra random delay, uniform between zero and this
+    pub jitter_ms : u32,
+    // Chances per message, 0 to 1
+    pub loss : f32,
+    pub duplicate : f32,
+    // Held back long enough to arrive after messages sent later
+    pub reorder : f32,
+}
+
+impl NetworkConditions {
+    pub fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if (self.latency_ms.saturating_add(self.jitter_ms) > MAX_SIM_LATENCY_MS) {
+            return Err(format!("latency plus jitter must be at most {}ms", MAX_SIM_LATENCY_MS));
+        }
+
+        for (name, chance) in [("loss", self.loss), ("duplicate", self.duplicate), ("reorder", self.reorder)] {
+            if !(0.0..=1.0).contains(&chance) {
+                return Err(format!("{} must be between 0 and 1, got {}", name, chance));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Parses the short form used in flags and query strings, eg "latency=120,jitter=30,loss=0.02".
+impl FromStr for NetworkConditions {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let mut conditions = Self::default();
+        for part in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let (key, value) = part.split_once('=').ok_or_else(|| format!("Expected key=value, got {}", part))?;
+            match key {
+                "latency" | "latency_ms" => conditions.latency_ms = parse_value(key, value)?,
+                "jitter" | "jitter_ms" => conditions.jitter_ms = parse_value(key, value)?,
+                "loss" => conditions.loss = parse_value(key, value)?,
+                "dup" | "duplicate" => conditions.duplicate = parse_value(key, value)?,
+                "reorder" => conditions.reorder = parse_value(key, value)?,
+                _ => return Err(format!("Unknown network condition {}", key)),
+            }
+        }
+
+        conditions.validate()?;
+        Ok(conditions)
+    }
+}
+
+fn parse_value<T : FromStr>(key : &str, value : &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", key, value))
+}
+
+struct InFlight<T>
+{
+    deliver_at_us : u64,
+    // Breaks ties so equal delivery times keep send order
+    id : u64,
+    message : T,
+}
+
+pub struct NetworkSimulator<T>
+{
+    conditions : NetworkConditions,
+    rng : FroggyRand,
+    next_id : u64,
+    // Messages that werent reordered never overtake each other
+    last_in_order_us : u64,
+    in_flight : Vec<InFlight<T>>,
+
+    dropped : u64,
+    duplicated : u64,
+    reordered : u64,
+}
+
+impl<T : Clone> NetworkSimulator<T> {
+    pub fn new(conditions : NetworkConditions, seed : u64) -> Self {
+        Self {
+            conditions,
+            rng : FroggyRand::new(seed),
+            next_id : 0,
+            last_in_order_us : 0,
+            in_flight : Vec::new(),
+            dropped : 0,
+            duplicated : 0,
+            reordered : 0,
+        }
+    }
+
+    pub fn conditions(&self) -> &NetworkConditions {
+        &self.conditions
+    }
+
+    pub fn send(&mut self, now_us : u64, message : T) {
+        let id = self.take_id();
+        if (self.roll(id, 0) < self.conditions.loss) {
+            self.dropped += 1;
+            return;
+        }
+
+        if (self.roll(id, 1) < self.conditions.duplicate) {
+            self.duplicated += 1;
+            let copy = message.clone();
+            self.schedule(now_us, copy);
+        }
+
+        self.schedule(now_us, message);
+    }
+
+    // Delayed like everything else but never lost, duplicated or reordered,
+    // for things like closing the connection that the transport underneath would guarantee.
+    pub fn send_reliable(&mut self, now_us : u64, message : T) {
+        let id = self.take_id();
+        let deliver_at_us = self.in_order_delivery(now_us, id);
+        self.in_flight.push(InFlight { deliver_at_us, id, message });
+    }
+
+    // Everything due by now, in delivery order.
+    pub fn poll(&mut self, now_us : u64) -> Vec<T> {
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if (self.in_flight[i].deliver_at_us <= now_us) {
+                due.push(self.in_flight.swap_remove(i));
+            }
+            else {
+                i += 1;
+            }
+        }
+
+        due.sort_by_key(|x| (x.deliver_at_us, x.id));
+        due.into_iter().map(|x| x.message).collect()
+    }
+
+    pub fn next_delivery_us(&self) -> Option<u64> {
+        self.in_flight.iter().map(|x| x.deliver_at_us).min()
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn duplicated_count(&self) -> u64 {
+        self.duplicated
+    }
+
+    pub fn reordered_count(&self) -> u64 {
+        self.reordered
+    }
+
+    fn schedule(&mut self, now_us : u64, message : T) {
+        let id = self.take_id();
+        let deliver_at_us = if (self.roll(id, 2) < self.conditions.reorder) {
+            // Hold it back past whatever gets sent in the next worst case delay
+            self.reordered += 1;
+            let max_delay_us = self.conditions.latency_ms.saturating_add(self.conditions.jitter_ms).max(1) as u64 * 1000;
+            self.delay(now_us, id) + max_delay_us
+        }
+        else {
+            self.in_order_delivery(now_us, id)
+        };
+
+        self.in_flight.push(InFlight { deliver_at_us, id, message });
+    }
+
+    fn in_order_delivery(&mut self, now_us : u64, id : u64) -> u64 {
+        let deliver_at_us = self.delay(now_us, id).max(self.last_in_order_us);
+        self.last_in_order_us = deliver_at_us;
+        deliver_at_us
+    }
+
+    fn delay(&self, now_us : u64, id : u64) -> u64 {
+        let jitter_us = (self.roll(id, 3) as f64 * self.conditions.jitter_ms as f64 * 1000.0) as u64;
+        now_us + self.conditions.latency_ms as u64 * 1000 + jitter_us
+    }
+
+    fn take_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn roll(&self, id : u64, salt : u32) -> f32 {
+        self.rng.gen_unit((id, salt)) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver_all(sim : &mut NetworkSimulator<u32>, count : u32) -> Vec<u32> {
+        for i in 0..count {
+            sim.send(i as u64 * 16_666, i);
+        }
+
+        sim.poll(u64::MAX)
+    }
+
+    #[test]
+    fn parse_conditions() {
+        let conditions : NetworkConditions = "latency=120, jitter=30,loss=0.02,dup=0.01,reorder=0.1".parse().unwrap();
+        assert_eq!(conditions.latency_ms, 120);
+        assert_eq!(conditions.jitter_ms, 30);
+        assert_eq!(conditions.loss, 0.02);
+        assert_eq!(conditions.duplicate, 0.01);
+        assert_eq!(conditions.reorder, 0.1);
+
+        assert!("".parse::<NetworkConditions>().unwrap().is_perfect());
+        assert!("latency".parse::<NetworkConditions>().is_err());
+        assert!("loss=2".parse::<NetworkConditions>().is_err());
+        assert!("latency=10000".parse::<NetworkConditions>().is_err());
+        assert!("bandwidth=10".parse::<NetworkConditions>().is_err());
+    }
+
+    #[test]
+    fn perfect_network_passes_through() {
+        let mut sim = NetworkSimulator::new(NetworkConditions::default(), 1);
+        sim.send(100, 7);
+        assert_eq!(sim.poll(100), vec![7]);
+        assert_eq!(sim.in_flight_count(), 0);
+    }
+
+    #[test]
+    fn latency_and_jitter_keep_order() {
+        let conditions = NetworkConditions {
+            latency_ms : 100,
+            jitter_ms : 50,
+            ..Default::default()
+        };
+        let mut sim = NetworkSimulator::new(conditions, 1);
+        sim.send(0, 0);
+        assert!(sim.poll(99_999).is_empty());
+        assert!(sim.next_delivery_us().unwrap() >= 100_000);
+        assert!(sim.next_delivery_us().unwrap() <= 150_000);
+        assert_eq!(sim.poll(150_000), vec![0]);
+
+        let delivered = deliver_all(&mut sim, 100);
+        assert_eq!(delivered, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn loss_and_duplication() {
+        let conditions = NetworkConditions {
+            loss : 0.2,
+            duplicate : 0.2,
+            ..Default::default()
+        };
+        let mut sim = NetworkSimulator::new(conditions, 2);
+        let delivered = deliver_all(&mut sim, 1000);
+
+        assert!(sim.dropped_count() > 100 && sim.dropped_count() < 300, "dropped {}", sim.dropped_count());
+        assert!(sim.duplicated_count() > 100 && sim.duplicated_count() < 300, "duplicated {}", sim.duplicated_count());
+        assert_eq!(delivered.len() as u64, 1000 - sim.dropped_count() + sim.duplicated_count());
+    }
+
+    #[test]
+    fn reordering() {
+        let conditions = NetworkConditions {
+            latency_ms : 50,
+            reorder : 0.1,
+            ..Default::default()
+        };
+        let mut sim = NetworkSimulator::new(conditions, 3);
+        let delivered = deliver_all(&mut sim, 1000);
+
+        assert_eq!(delivered.len(), 1000);
+        assert!(sim.reordered_count() > 0);
+        assert!(delivered.windows(2).any(|x| x[0] > x[1]));
+    }
+
+    #[test]
+    fn reliable_messages_always_arrive_in_order() {
+        let conditions = NetworkConditions {
+            latency_ms : 20,
+            jitter_ms : 20,
+            loss : 1.0,
+            duplicate : 1.0,
+            reorder : 1.0,
+        };
+        let mut sim = NetworkSimulator::new(conditions, 4);
+        for i in 0..100 {
+            sim.send_reliable(i * 1000, i as u32);
+        }
+
+        assert_eq!(sim.poll(u64::MAX), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn same_seed_same_outcome() {
+        let conditions = NetworkConditions {
+            latency_ms : 30,
+            jitter_ms : 30,
+            loss : 0.1,
+            duplicate : 0.1,
+            reorder : 0.1,
+        };
+        let a = deliver_all(&mut NetworkSimulator::new(conditions, 5), 200);
+        let b = deliver_all(&mut NetworkSimulator::new(conditions, 5), 200);
+        assert_eq!(a, b);
+    }
+}
@@ -1,26 +1,106 @@
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::interop::TelemetryMessage;
+
+// Client telemetry recorded by the server, one json TelemetryRecord per line.
+// Each game writes numbered segments {dir}/{game_id}.{segment}.jsonl, moving onto the next
+// segment once the current one gets too large or too old.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TelemetryRotation
+{
+    pub max_bytes : u64,
+    pub max_age : Duration,
+}
+
+impl Default for TelemetryRotation {
+    fn default() -> Self {
+        Self {
+            max_bytes : 16 * 1024 * 1024,
+            max_age : Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+// Dont hammer the filesystem every tick when we cant open the file.
+const REOPEN_INTERVAL : Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TelemetryRecord
+{
+    pub game_id : String,
+    pub player_id : crate::PlayerId,
+    // Top of the server timeline when the event arrived
+    pub server_frame_id : u32,
+    // Unix time the server received the event
+    pub timestamp_ms : u64,
+    pub event : TelemetryMessage,
+}
 
 pub struct TelemetryTracer
 {
-    file : File,
-    event_buffer : Vec<TelemetryEvent>
+    game_id : String,
+    dir : PathBuf,
+    rotation : TelemetryRotation,
+
+    segment : u32,
+    file : Option<File>,
+    file_bytes : u64,
+    file_opened_at : Instant,
+    last_open_attempt : Option<Instant>,
+
+    dropped_events : u64,
+    event_buffer : Vec<TelemetryRecord>,
 }
 
 impl TelemetryTracer
 {
-    pub fn new(filename : &str) -> Self {
-        let file = OpenOptions::new().write(true).append(true).create(true).open(filename).unwrap();
+    // Never fails, if we cant write telemetry we log it and drop events until we can.
+    pub fn new(dir : impl AsRef<Path>, game_id : &str, rotation : TelemetryRotation) -> Self {
+        let now = Instant::now();
+        let mut tracer = Self {
+            game_id : game_id.to_owned(),
+            dir : dir.as_ref().to_owned(),
+            rotation,
+            segment : 0,
+            file : None,
+            file_bytes : 0,
+            file_opened_at : now,
+            last_open_attempt : None,
+            dropped_events : 0,
+            event_buffer : Default::default(),
+        };
 
-        Self {
-            file,
-            event_buffer: Default::default(),
-        }
+        tracer.try_open(now);
+        tracer
+    }
+
+    pub fn segment_path(dir : impl AsRef<Path>, game_id : &str, segment : u32) -> PathBuf {
+        dir.as_ref().join(format!("{}.{}.jsonl", game_id, segment))
+    }
+
+    pub fn current_path(&self) -> PathBuf {
+        Self::segment_path(&self.dir, &self.game_id, self.segment)
+    }
 
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events
     }
 
     pub fn push(&mut self, event : TelemetryEvent) {
-        self.event_buffer.push(event);
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0);
+        self.event_buffer.push(TelemetryRecord {
+            game_id : self.game_id.clone(),
+            player_id : event.player_id,
+            server_frame_id : event.server_frame_id,
+            timestamp_ms,
+            event : event.event,
+        });
     }
 
     pub fn flush(&mut self) {
@@ -28,11 +108,78 @@ impl TelemetryTracer
             return;
         }
 
-        let mut events = Vec::new();
-        std::mem::swap(&mut events, &mut self.event_buffer);
+        let now = Instant::now();
+        if (self.file.is_some() && self.should_rotate(now)) {
+            self.segment += 1;
+            self.file = None;
+            self.try_open(now);
+        }
+
+        if (self.file.is_none()) {
+            let retry = self.last_open_attempt.map(|x| now.saturating_duration_since(x) >= REOPEN_INTERVAL).unwrap_or(true);
+            if (retry) {
+                self.try_open(now);
+            }
+        }
+
+        let events = std::mem::take(&mut self.event_buffer);
+        if (self.file.is_none()) {
+            self.dropped_events += events.len() as u64;
+            return;
+        }
+
+        let mut out = String::new();
+        let mut written = 0;
+        for event in &events {
+            match serde_json::to_string(event) {
+                Ok(line) => {
+                    out.push_str(&line);
+                    out.push('\n');
+                    written += 1;
+                }
+                Err(e) => {
+                    debug_log!("Unable to serialize telemetry {:?}: {}", event, e);
+                    self.dropped_events += 1;
+                }
+            }
+        }
 
-        for event in events.into_iter() {
-            writeln!(self.file, "{:?} - {:#?}", event.player_id, event.event).unwrap();
+        let result = self.file.as_mut().unwrap().write_all(out.as_bytes());
+        match result {
+            Ok(_) => {
+                self.file_bytes += out.len() as u64;
+            }
+            Err(e) => {
+                debug_log!("Telemetry write to {:?} failed, dropping {} events: {}", self.current_path(), written, e);
+                self.dropped_events += written;
+                self.file = None;
+            }
+        }
+    }
+
+    fn should_rotate(&self, now : Instant) -> bool {
+        self.file_bytes >= self.rotation.max_bytes
+            || now.saturating_duration_since(self.file_opened_at) >= self.rotation.max_age
+    }
+
+    fn try_open(&mut self, now : Instant) {
+        self.last_open_attempt = Some(now);
+
+        let path = self.current_path();
+        let file = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| OpenOptions::new().append(true).create(true).open(&path));
+
+        match file {
+            Ok(file) => {
+                // Segments can already exist if a game id gets reused, carry on from their size
+                self.file_bytes = file.metadata().map(|x| x.len()).unwrap_or(0);
+                self.file_opened_at = now;
+                self.file = Some(file);
+            }
+            Err(e) => {
+                debug_log!("Unable to open telemetry file {:?}: {}", path, e);
+                self.file = None;
+            }
         }
     }
 }
@@ -41,5 +188,91 @@ impl TelemetryTracer
 pub struct TelemetryEvent
 {
     pub player_id : crate::PlayerId,
-    pub event : crate::interop::TelemetryMessage
-}
\ No newline at end of file
+    pub server_frame_id : u32,
+    pub event : TelemetryMessage,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interop::Telemetry_LatencyEstimate;
+
+    fn test_dir(name : &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crossy_telemetry_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn event(server_frame_id : u32) -> TelemetryEvent {
+        TelemetryEvent {
+            player_id : crate::PlayerId(1),
+            server_frame_id,
+            event : TelemetryMessage::LatencyEstimate(Telemetry_LatencyEstimate {
+                estimated_latency_us : 50_000,
+                estimated_frame_delta : 3,
+                estimated_server_current_frame_id : server_frame_id,
+            }),
+        }
+    }
+
+    fn read_records(path : &Path) -> Vec<TelemetryRecord> {
+        std::fs::read_to_string(path).unwrap()
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let dir = test_dir("lines");
+        let mut tracer = TelemetryTracer::new(&dir, "game", TelemetryRotation::default());
+        tracer.push(event(10));
+        tracer.push(event(11));
+        tracer.flush();
+
+        let records = read_records(&TelemetryTracer::segment_path(&dir, "game", 0));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].game_id, "game");
+        assert_eq!(records[1].server_frame_id, 11);
+        assert!(records[0].timestamp_ms > 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = test_dir("rotate");
+        let rotation = TelemetryRotation {
+            max_bytes : 1,
+            ..Default::default()
+        };
+
+        let mut tracer = TelemetryTracer::new(&dir, "game", rotation);
+        for i in 0..3 {
+            tracer.push(event(i));
+            tracer.flush();
+        }
+
+        for segment in 0..3 {
+            let records = read_records(&TelemetryTracer::segment_path(&dir, "game", segment));
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].server_frame_id, segment);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unopenable_file_drops_events() {
+        // A file where we want a directory
+        let dir = test_dir("blocked");
+        std::fs::write(&dir, "").unwrap();
+
+        let mut tracer = TelemetryTracer::new(&dir, "game", TelemetryRotation::default());
+        tracer.push(event(1));
+        tracer.flush();
+        assert_eq!(tracer.dropped_events(), 1);
+
+        let _ = std::fs::remove_file(&dir);
+    }
+}
@@ -121,6 +121,14 @@ impl Timeline {
         }
     }
 
+    // Apply a change to the rules across all of history.
+    // Only changing the top state would get undone the next time we resimulate from an older frame.
+    pub fn modify_rules_state<F : Fn(&mut RulesState)>(&mut self, f : F) {
+        for state in self.states.iter_mut() {
+            f(&mut state.rules_state);
+        }
+    }
+
     pub fn top_state(&self) -> &GameState {
         self.states.get(0).unwrap()
     }
@@ -415,4 +423,17 @@ mod tests {
             states : timeline.states.clone(),
         }
     }
+
+    #[test]
+    fn modified_rules_survive_ticks() {
+        let mut timeline = Timeline::from_seed(GameConfig::default(), "test");
+        for _ in 0..10 {
+            timeline.tick(None, TICK_INTERVAL_US);
+        }
+
+        timeline.modify_rules_state(|x| x.host = Some(PlayerId(1)));
+        timeline.tick(None, TICK_INTERVAL_US);
+
+        assert!(timeline.states.iter().all(|x| x.rules_state.host == Some(PlayerId(1))));
+    }
 }
@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::interop::{ClientTick, CrossyMessage, MAX_CLIENT_TICKS_PER_MESSAGE};
+
+// Framing for native clients that talk to the server over UDP rather than a websocket.
+// Each datagram is one packet wrapping ordinary CrossyMessages, so the game itself cant tell the difference.
+//
+// Datagrams get lost, duplicated and reordered, so
+// - every packet has a sequence number, anything not newer than the last one accepted is dropped
+// - clients put every input the server hasnt acked yet into each packet, so a lost packet costs nothing
+//   as long as a later one arrives
+// Only inputs are resent, chat and the rest are best effort. Server ticks carry everything since
+// the last known good state so losing some of those is fine too.
+
+// Biggest payload a UDP datagram can carry
+pub const MAX_DATAGRAM_BYTES : usize = 65_507;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UdpClientPacket {
+    // All from /join or /rejoin, checked on every packet since anyone can send us a datagram
+    pub game_id : String,
+    pub socket_id : u32,
+    pub session_token : String,
+
+    pub seq : u32,
+    pub messages : Vec<CrossyMessage>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UdpServerPacket {
+    pub seq : u32,
+    // Newest input frame the server has from this client, it can stop resending anything up to here
+    pub input_ack_frame_id : Option<u32>,
+    pub messages : Vec<CrossyMessage>,
+}
+
+// Hands out sequence numbers for outgoing packets, starting from 1
+#[derive(Debug, Default)]
+pub struct SequenceCounter {
+    latest : u32,
+}
+
+impl SequenceCounter {
+    pub fn next_seq(&mut self) -> u32 {
+        self.latest = self.latest.wrapping_add(1);
+        self.latest
+    }
+}
+
+// Drops duplicated and out of date packets
+#[derive(Debug, Default)]
+pub struct SequenceFilter {
+    latest : Option<u32>,
+    dropped : u32,
+}
+
+impl SequenceFilter {
+    pub fn accept(&mut self, seq : u32) -> bool {
+        // Compare as a signed difference so we survive wrapping, not that anyone plays for two years
+        let newer = match self.latest {
+            Some(latest) => (seq.wrapping_sub(latest) as i32) > 0,
+            None => true,
+        };
+
+        if (newer) {
+            self.latest = Some(seq);
+        }
+        else {
+            self.dropped += 1;
+        }
+
+        newer
+    }
+
+    pub fn dropped_count(&self) -> u32 {
+        self.dropped
+    }
+}
+
+// Client side, holds on to inputs until the server acks them so each packet can carry all of them.
+#[derive(Debug, Default)]
+pub struct InputResender {
+    unacked : VecDeque<ClientTick>,
+    acked_frame_id : Option<u32>,
+}
+
+impl InputResender {
+    pub fn push(&mut self, ticks : impl IntoIterator<Item = ClientTick>) {
+        for tick in ticks {
+            let already_sent = self.unacked.back().map(|x| tick.frame_id <= x.frame_id).unwrap_or(false);
+            let already_acked = self.acked_frame_id.map(|x| tick.frame_id <= x).unwrap_or(false);
+            if (already_sent || already_acked) {
+                continue;
+            }
+
+            self.unacked.push_back(tick);
+        }
+
+        // The server rejects anything past its lkg window anyway, no point holding more than fits in a message
+        while (self.unacked.len() > MAX_CLIENT_TICKS_PER_MESSAGE) {
+            self.unacked.pop_front();
+        }
+    }
+
+    pub fn ack(&mut self, frame_id : u32) {
+        if (self.acked_frame_id.map(|x| frame_id <= x).unwrap_or(false)) {
+            // Acks can arrive out of order too
+            return;
+        }
+
+        self.acked_frame_id = Some(frame_id);
+        while let Some(front) = self.unacked.front() {
+            if (front.frame_id <= frame_id) {
+                self.unacked.pop_front();
+            }
+            else {
+                break;
+            }
+        }
+    }
+
+    // Everything to put in the next packet, oldest first
+    pub fn pending(&self) -> Vec<ClientTick> {
+        self.unacked.iter().cloned().collect()
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.unacked.len()
+    }
+}
+
+// Server side, lets each input frame through once however many times it is resent.
+#[derive(Debug, Default)]
+pub struct InputDedup {
+    latest_frame_id : Option<u32>,
+}
+
+impl InputDedup {
+    pub fn filter(&mut self, ticks : Vec<ClientTick>) -> Vec<ClientTick> {
+        let mut fresh = Vec::with_capacity(ticks.len());
+        for tick in ticks {
+            if (self.latest_frame_id.map(|x| tick.frame_id <= x).unwrap_or(false)) {
+                continue;
+            }
+
+            self.latest_frame_id = Some(tick.frame_id);
+            fresh.push(tick);
+        }
+
+        fresh
+    }
+
+    // What to ack back to the client
+    pub fn latest_frame_id(&self) -> Option<u32> {
+        self.latest_frame_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Input;
+    use crate::timeline::TICK_INTERVAL_US;
+
+    fn tick(frame_id : u32) -> ClientTick {
+        ClientTick {
+            time_us : frame_id * TICK_INTERVAL_US,
+            frame_id,
+            input : if frame_id.is_multiple_of(10) { Input::Up } else { Input::None },
+        }
+    }
+
+    fn frame_ids(ticks : &[ClientTick]) -> Vec<u32> {
+        ticks.iter().map(|x| x.frame_id).collect()
+    }
+
+    #[test]
+    fn sequence_filter_drops_old_and_duplicate() {
+        let mut filter = SequenceFilter::default();
+        assert!(filter.accept(5));
+        assert!(!filter.accept(5));
+        assert!(!filter.accept(3));
+        assert!(filter.accept(9));
+        assert_eq!(filter.dropped_count(), 2);
+
+        // Across the wrap
+        let mut filter = SequenceFilter::default();
+        assert!(filter.accept(u32::MAX - 1));
+        assert!(filter.accept(1));
+        assert!(!filter.accept(u32::MAX));
+    }
+
+    #[test]
+    fn resender_keeps_inputs_until_acked() {
+        let mut resender = InputResender::default();
+        resender.push((1..=5).map(tick));
+        assert_eq!(frame_ids(&resender.pending()), vec![1, 2, 3, 4, 5]);
+
+        // Overlapping pushes dont duplicate
+        resender.push((4..=7).map(tick));
+        assert_eq!(frame_ids(&resender.pending()), vec![1, 2, 3, 4, 5, 6, 7]);
+
+        resender.ack(4);
+        assert_eq!(frame_ids(&resender.pending()), vec![5, 6, 7]);
+
+        // Stale ack after a newer one
+        resender.ack(2);
+        assert_eq!(frame_ids(&resender.pending()), vec![5, 6, 7]);
+
+        // Already acked frames are not taken back
+        resender.push((3..=8).map(tick));
+        assert_eq!(frame_ids(&resender.pending()), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn resender_is_bounded() {
+        let mut resender = InputResender::default();
+        resender.push((0..1000).map(tick));
+        let pending = resender.pending();
+        assert_eq!(pending.len(), MAX_CLIENT_TICKS_PER_MESSAGE);
+        assert_eq!(pending.last().unwrap().frame_id, 999);
+        assert!(CrossyMessage::ClientTick(pending).within_collection_limits());
+    }
+
+    #[test]
+    fn lossy_link_delivers_every_input_once() {
+        let mut resender = InputResender::default();
+        let mut dedup = InputDedup::default();
+        let mut received = Vec::new();
+
+        for frame_id in 0..200 {
+            resender.push(std::iter::once(tick(frame_id)));
+
+            // Lose most packets, but never so many in a row that inputs fall out of the resend window
+            let lost = !frame_id.is_multiple_of(3);
+            if (!lost) {
+                received.extend(dedup.filter(resender.pending()));
+            }
+
+            // And only sometimes hear the ack back
+            if (frame_id.is_multiple_of(7)) {
+                if let Some(ack) = dedup.latest_frame_id() {
+                    resender.ack(ack);
+                }
+            }
+        }
+
+        // Flush what was still in flight
+        received.extend(dedup.filter(resender.pending()));
+        assert_eq!(frame_ids(&received), (0..200).collect::<Vec<_>>());
+        assert!(resender.pending_count() < 20);
+    }
+}
@@ -0,0 +1,618 @@
+use std::collections::VecDeque;
+
+use crossy_multi_core::ai::AIAgent;
+use crossy_multi_core::clock_sync::ClockSync;
+use crossy_multi_core::crossy_ruleset::RulesState;
+use crossy_multi_core::interop::{self, CrossyMessage};
+use crossy_multi_core::player_id_map::PlayerIdMap;
+use crossy_multi_core::timeline::{Timeline, TICK_INTERVAL_US};
+use crossy_multi_core::{Input, PlayerId, PlayerInputs};
+
+use crate::clock::Clock;
+use crate::transport::Transport;
+
+//const TIME_REQUEST_INTERVAL : u32 = 13;
+const TIME_REQUEST_INTERVAL : u32 = 2;
+
+const RUN_TELEMETRY : bool = true;
+const RUN_PING_LATENCY_UPDATES : bool = true;
+
+const MAX_RECEIVED_CHAT : usize = 64;
+
+#[derive(Debug)]
+struct LocalPlayerInfo {
+    player_id : PlayerId,
+    buffered_input : Input,
+}
+
+// What happened to the server messages on a tick, for debug graphs.
+#[derive(Debug, Clone, Copy)]
+pub struct TickReport {
+    // How far our top frame is ahead of the newest server message, None if there wasnt one
+    pub server_frame_delta : Option<f32>,
+    pub queued_server_messages : usize,
+}
+
+pub struct NetClient {
+    clock : Box<dyn Clock>,
+    // Wall clock estimate of when the server started, relative to the clock's start
+    server_start_date_us : i64,
+
+    clock_sync : ClockSync,
+    // Set once the first ping comes back, we dont tick before we know what time it is
+    synced : bool,
+
+    timeline : Timeline,
+
+    local_player_info : Option<LocalPlayerInfo>,
+    last_sent_frame_id : u32,
+
+    // This seems like a super hacky solution
+    untrusted_rules_state : Option<RulesState>,
+    lkg_rules_state : Option<RulesState>,
+
+    queued_time_info : Option<interop::TimeRequestEnd>,
+
+    queued_server_linden_messages : VecDeque<interop::LindenServerTick>,
+
+    roster : PlayerIdMap<interop::PlayerRosterEntry>,
+
+    // Chat received since the last call to take_chat
+    received_chat : VecDeque<interop::ServerChat>,
+
+    kicked : bool,
+
+    ai_agent : Option<Box<dyn AIAgent>>,
+
+    telemetry_buffer : TelemetryBuffer,
+
+    tick_id : u32,
+}
+
+impl NetClient {
+    // Seed and frame come from the server, along with the latency estimated when joining.
+    pub fn new(seed : &str, server_frame_id : u32, estimated_latency_us : i32, clock : Box<dyn Clock>) -> Self {
+        let estimated_frame_delta = estimated_latency_us / TICK_INTERVAL_US as i32;
+        let estimated_server_current_frame_id = (server_frame_id as i32 + estimated_frame_delta) as u32;
+        let estimated_server_time_us = estimated_server_current_frame_id * TICK_INTERVAL_US;
+        let timeline = Timeline::from_server_parts(seed, 0, 0, Default::default(), RulesState::new(Default::default()));
+
+        log!("Constructing client : estimated latency {}, server frame_id {}, estimated now server_frame_id {}", estimated_latency_us, server_frame_id, estimated_server_current_frame_id);
+
+        let mut telemetry_buffer = TelemetryBuffer::new(RUN_TELEMETRY);
+        telemetry_buffer.push(interop::TelemetryMessage::LatencyEstimate(interop::Telemetry_LatencyEstimate {
+            estimated_latency_us,
+            estimated_frame_delta,
+            estimated_server_current_frame_id,
+        }));
+
+        // Only a wall clock guess, the real server start comes from the first ping.
+        let server_start_date_us = clock.date_now_us() as i64 - estimated_server_time_us as i64;
+
+        Self {
+            clock,
+            server_start_date_us,
+            clock_sync : ClockSync::default(),
+            synced : false,
+            timeline,
+            local_player_info : None,
+            last_sent_frame_id : server_frame_id,
+            untrusted_rules_state : None,
+            lkg_rules_state : None,
+            queued_time_info : None,
+            queued_server_linden_messages : Default::default(),
+            roster : Default::default(),
+            received_chat : Default::default(),
+            kicked : false,
+            ai_agent : None,
+            telemetry_buffer,
+            tick_id : 0,
+        }
+    }
+
+    pub fn join(&mut self, player_id : PlayerId) {
+        self.local_player_info = Some(LocalPlayerInfo {
+            player_id,
+            buffered_input : Input::None,
+        })
+    }
+
+    // Applied on the next frame the local player can move, later inputs are dropped until then
+    pub fn buffer_input(&mut self, input : Input) {
+        if let Some(x) = self.local_player_info.as_mut() {
+            if input != Input::None && x.buffered_input == Input::None {
+                x.buffered_input = input;
+            }
+        }
+    }
+
+    // Lets an AI play as the local player instead of buffered inputs
+    pub fn set_ai_agent(&mut self, ai_agent : Option<Box<dyn AIAgent>>) {
+        self.ai_agent = ai_agent;
+    }
+
+    pub fn ai_agent(&self) -> Option<&dyn AIAgent> {
+        self.ai_agent.as_deref()
+    }
+
+    pub fn local_player_id(&self) -> Option<PlayerId> {
+        self.local_player_info.as_ref().map(|x| x.player_id)
+    }
+
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    pub fn timeline_mut(&mut self) -> &mut Timeline {
+        &mut self.timeline
+    }
+
+    pub fn roster(&self) -> &PlayerIdMap<interop::PlayerRosterEntry> {
+        &self.roster
+    }
+
+    // Rules from the latest server message, can still be rolled back
+    pub fn untrusted_rules_state(&self) -> Option<&RulesState> {
+        self.untrusted_rules_state.as_ref()
+    }
+
+    // Rules from the server's last known good state, wont change
+    pub fn lkg_rules_state(&self) -> Option<&RulesState> {
+        self.lkg_rules_state.as_ref()
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    pub fn is_kicked(&self) -> bool {
+        self.kicked
+    }
+
+    pub fn take_chat(&mut self) -> Vec<interop::ServerChat> {
+        self.received_chat.drain(..).collect()
+    }
+
+    // Receive everything waiting, tick, then send what we have for the server.
+    pub fn pump<T : Transport>(&mut self, transport : &mut T) -> Option<TickReport> {
+        while let Some(message) = transport.try_recv() {
+            self.recv(message);
+        }
+
+        let report = self.tick();
+
+        let client_tick = self.client_tick_message();
+        if let CrossyMessage::ClientTick(ticks) = &client_tick {
+            if (!ticks.is_empty()) {
+                transport.send(&client_tick);
+            }
+        }
+
+        if (self.has_telemetry()) {
+            transport.send(&self.telemetry_message());
+        }
+
+        if (self.should_send_time_request()) {
+            transport.send(&self.time_request_message());
+        }
+
+        report
+    }
+
+    // Steps the local timeline up to our estimate of the server time then applies server messages.
+    // Returns None until the clock is synced.
+    pub fn tick(&mut self) -> Option<TickReport> {
+        self.tick_id += 1;
+
+        let mut report = None;
+        if (self.synced) {
+            loop {
+                let current_time_us = self.server_time_us().unwrap_or(0);
+                let last_time = self.timeline.top_state().time_us;
+                if (current_time_us > last_time)
+                {
+                    self.tick_inner();
+                }
+                else
+                {
+                    break;
+                }
+            }
+
+            let server_frame_delta = self.queued_server_linden_messages.front().map(|top_linden_message| {
+                self.timeline.top_state().frame_id as f32 - top_linden_message.latest.frame_id as f32
+            });
+
+            report = Some(TickReport {
+                server_frame_delta,
+                queued_server_messages : self.queued_server_linden_messages.len(),
+            });
+
+            let mut requeued_server_messages = VecDeque::new();
+
+            while let Some(linden_server_tick) = self.queued_server_linden_messages.pop_back() {
+                let delta_input_server_frame_times = linden_server_tick.delta_inputs.iter().map(|x| x.frame_id).collect::<Vec<_>>();
+
+                self.telemetry_buffer.push(interop::TelemetryMessage::ClientReceiveEvent(interop::Telemetry_ClientReceiveEvent {
+                    server_send_frame_id : linden_server_tick.latest.frame_id,
+                    receive_frame_id : self.timeline.top_state().frame_id,
+                    delta_input_server_frame_times_count: delta_input_server_frame_times.len() as u32,
+                    delta_input_server_frame_times_min: delta_input_server_frame_times.first().cloned(),
+                    delta_input_server_frame_times_max: delta_input_server_frame_times.last().cloned(),
+                }));
+
+                if (!self.try_process_linden_server_message(&linden_server_tick)) {
+                    requeued_server_messages.push_front(linden_server_tick);
+                }
+            }
+
+            self.queued_server_linden_messages = requeued_server_messages;
+        }
+
+        self.process_time_info();
+
+        report
+    }
+
+    fn tick_inner(&mut self) {
+        let mut player_inputs = PlayerInputs::new();
+
+        if let Some(local_player_id) = self.local_player_id() {
+            let can_move = self.timeline.top_state().get_player(local_player_id).map(|x| x.can_move()).unwrap_or(false);
+
+            let mut local_input = Input::None;
+            if (can_move)
+            {
+                if let Some(ai) = self.ai_agent.as_mut() {
+                    local_input = ai.think(self.timeline.top_state(), &self.timeline.map);
+                }
+                else
+                {
+                    let local_player_info = self.local_player_info.as_mut().unwrap();
+                    if (local_player_info.buffered_input != Input::None)
+                    {
+                        local_input = local_player_info.buffered_input;
+                        local_player_info.buffered_input = Input::None;
+                    }
+                }
+            }
+
+            player_inputs.set(local_player_id, local_input);
+        }
+
+        self.timeline.tick(Some(player_inputs), TICK_INTERVAL_US)
+    }
+
+    // Our estimate of the server's clock right now
+    pub fn server_time_us(&self) -> Option<u32> {
+        let time_now_us = self.clock.now_us() as i64;
+        self.clock_sync.server_time_us(time_now_us).map(|x| x.max(0) as u32)
+    }
+
+    fn process_time_info(&mut self)
+    {
+        if (!RUN_PING_LATENCY_UPDATES) {
+            self.queued_time_info = None;
+            return;
+        }
+
+        let time_now_us = self.clock.now_us() as i64;
+        let sample = self.queued_time_info.take().and_then(|x| self.clock_sync.add_time_request(&x));
+
+        // Slew every tick rather than only when a ping comes back so corrections are spread out
+        self.clock_sync.update(time_now_us);
+        let server_time_us = match self.clock_sync.server_time_us(time_now_us) {
+            Some(x) => x.max(0),
+            None => return,
+        };
+
+        if (!self.synced)
+        {
+            log!("Setting latency up: server_time_ms: {}, estimated_latency_ms: {}", server_time_us / 1000, self.clock_sync.latency_us().unwrap_or(0) / 1000);
+            self.synced = true;
+        }
+
+        if let Some(sample) = sample {
+            let latency_us = self.clock_sync.latency_us().unwrap_or(sample.latency_us()) as f32;
+
+            // Where the estimate says we should be, the client clock may still be slewing towards it
+            let estimated_server_time_us = (time_now_us + self.clock_sync.target_offset_us().unwrap_or(0)).max(0) as u32;
+
+            let current_client_time_us = server_time_us as u32;
+            let current_client_date_time_us = (self.clock.date_now_us() as i64 - self.server_start_date_us).max(0) as u32;
+
+            self.telemetry_buffer.push(interop::TelemetryMessage::PingOutcome(interop::Telemetry_PingOutcome {
+                unlerped_estimated_latency_us : sample.latency_us(),
+                unlerped_estimated_frame_delta : sample.latency_us() / 16_666,
+                estimated_latency_us : latency_us,
+                estimated_frame_delta : latency_us / 16_666.0,
+
+                estimated_server_time_us,
+                estimated_server_current_frame_id : estimated_server_time_us / 16_666,
+
+                current_client_time_ms : current_client_time_us / 1000,
+                current_client_date_time_ms : current_client_date_time_us / 1000,
+            }));
+        }
+    }
+
+    fn try_process_linden_server_message(&mut self, linden_server_tick : &interop::LindenServerTick) -> bool
+    {
+        if let Some(client_state_at_lkg_time) = self.timeline.try_get_state(linden_server_tick.lkg_state.frame_id)
+        {
+            let mismatch_player_states = linden_server_tick.lkg_state.player_states != client_state_at_lkg_time.player_states;
+            let mismatch_rulestate = linden_server_tick.lkg_state.rules_state != client_state_at_lkg_time.rules_state;
+            if (mismatch_player_states || mismatch_rulestate)
+            {
+                log!("Tick Id: {}", self.tick_id);
+
+                if (mismatch_player_states)
+                {
+                    log!("Mismatch in LKG! frame_id {}", client_state_at_lkg_time.frame_id);
+                    log!("Local at lkg time {:#?}", client_state_at_lkg_time.player_states);
+                    log!("LKG {:#?}", linden_server_tick.lkg_state.player_states);
+                    log!("Rebasing... {:?}", linden_server_tick.lkg_state);
+                }
+                else
+                {
+                    log!("Mismatch in rules\n\nlocal:\n{:#?} \n\n lkg:\n {:#?}", client_state_at_lkg_time.rules_state, linden_server_tick.lkg_state.rules_state);
+                }
+
+                // TODO We do a ton of extra work, we recalculate from lkg with current inputs then run propate inputs from server.
+                self.timeline = self.timeline.rebase(&linden_server_tick.lkg_state);
+            }
+        }
+
+        if !self.timeline.try_propagate_inputs(linden_server_tick.delta_inputs.clone(), false) {
+            return false;
+        }
+
+        self.untrusted_rules_state = Some(linden_server_tick.rules_state.clone());
+        self.lkg_rules_state = Some(linden_server_tick.lkg_state.rules_state.clone());
+        self.roster = linden_server_tick.roster.clone();
+
+        true
+    }
+
+    pub fn recv(&mut self, message : CrossyMessage)
+    {
+        let client_receive_time_us = self.clock.now_us() as u32;
+        match message {
+            CrossyMessage::TimeResponsePacket(time_info) => {
+                self.queued_time_info = Some(interop::TimeRequestEnd {
+                    client_receive_time_us,
+                    client_send_time_us : time_info.client_send_time_us,
+                    server_receive_time_us : time_info.server_receive_time_us,
+                    server_send_time_us : time_info.server_send_time_us,
+                });
+            },
+            CrossyMessage::LindenServerTick(linden_server_tick) => {
+                self.queued_server_linden_messages.push_front(linden_server_tick);
+            }
+            CrossyMessage::ServerChat(chat) => {
+                if (self.received_chat.len() >= MAX_RECEIVED_CHAT) {
+                    self.received_chat.pop_front();
+                }
+                self.received_chat.push_back(chat);
+            }
+            CrossyMessage::PlayerKicked(player_kicked) if self.local_player_id() == Some(player_kicked.player_id) => {
+                log!("Kicked by the host");
+                self.kicked = true;
+            }
+            CrossyMessage::MessageRejected(rejected) => {
+                log!("Server rejected our message {:?}", rejected.reason);
+            }
+            _ => {},
+        }
+    }
+
+    // Every frame simulated since the last call, with what the local player pressed on it
+    pub fn client_tick_message(&mut self) -> CrossyMessage
+    {
+        let mut ticks = Vec::new();
+
+        while self.last_sent_frame_id <= self.timeline.top_state().frame_id {
+            if let Some(timeline_state) = self.timeline.try_get_state(self.last_sent_frame_id)
+            {
+                let input = self.local_player_info
+                    .as_ref()
+                    .map(|x| timeline_state.player_inputs.get(x.player_id))
+                    .unwrap_or(Input::None);
+
+                ticks.push(interop::ClientTick {
+                    time_us: timeline_state.time_us,
+                    frame_id: timeline_state.frame_id,
+                    input,
+                });
+            }
+
+            self.last_sent_frame_id += 1;
+        }
+
+        // After a long stall only send the most recent ticks, the server would reject anything older anyway.
+        if (ticks.len() > interop::MAX_CLIENT_TICKS_PER_MESSAGE) {
+            let excess = ticks.len() - interop::MAX_CLIENT_TICKS_PER_MESSAGE;
+            ticks.drain(..excess);
+        }
+
+        CrossyMessage::ClientTick(ticks)
+    }
+
+    pub fn chat_message(&self, text : &str) -> CrossyMessage
+    {
+        CrossyMessage::ClientChat(interop::ClientChat {
+            frame_id : self.timeline.top_state().frame_id,
+            content : interop::ChatContent::Text(interop::sanitize_chat_text(text)),
+        })
+    }
+
+    // None for unknown emote ids
+    pub fn emote_message(&self, emote_id : u8) -> Option<CrossyMessage>
+    {
+        let emote = interop::Emote::from_id(emote_id)?;
+        Some(CrossyMessage::ClientChat(interop::ClientChat {
+            frame_id : self.timeline.top_state().frame_id,
+            content : interop::ChatContent::Emote(emote),
+        }))
+    }
+
+    pub fn should_send_time_request(&self) -> bool {
+        let frame_id = self.timeline.top_state().frame_id;
+        frame_id.is_multiple_of(TIME_REQUEST_INTERVAL)
+    }
+
+    pub fn time_request_message(&self) -> CrossyMessage
+    {
+        let client_send_time_us = self.clock.now_us() as u32;
+        CrossyMessage::TimeRequestPacket(interop::TimeRequestPacket {
+            client_send_time_us,
+        })
+    }
+
+    pub fn has_telemetry(&self) -> bool {
+        !self.telemetry_buffer.buffer.is_empty()
+    }
+
+    pub fn telemetry_message(&mut self) -> CrossyMessage
+    {
+        let events = std::mem::take(&mut self.telemetry_buffer.buffer);
+        CrossyMessage::TelemetryMessagePackage(interop::TelemetryMessagePackage {
+            messages: events,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TelemetryBuffer
+{
+    enabled : bool,
+    buffer: Vec<interop::TelemetryMessage>
+}
+
+impl TelemetryBuffer
+{
+    fn new(enabled : bool) -> Self {
+        Self { enabled, buffer: Default::default() }
+    }
+
+    fn push(&mut self, message: interop::TelemetryMessage) {
+        if (self.enabled) {
+            self.buffer.push(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use crate::transport::QueueTransport;
+
+    // Server started 10 seconds before the client
+    const SERVER_AHEAD_US : u64 = 10_000_000;
+
+    struct ManualClock(Rc<Cell<u64>>);
+
+    impl Clock for ManualClock {
+        fn now_us(&self) -> u64 {
+            self.0.get()
+        }
+
+        fn date_now_us(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    fn client() -> (NetClient, Rc<Cell<u64>>) {
+        let time = Rc::new(Cell::new(0));
+        let client = NetClient::new("net_client_test", 600, 30_000, Box::new(ManualClock(time.clone())));
+        (client, time)
+    }
+
+    // Answers a time request the way the server would with 30ms each way
+    fn time_response(request : &CrossyMessage, now_us : u64) -> CrossyMessage {
+        let client_send_time_us = match request {
+            CrossyMessage::TimeRequestPacket(x) => x.client_send_time_us,
+            _ => panic!("Expected a time request, got {:?}", request),
+        };
+
+        let server_receive_time_us = (now_us + SERVER_AHEAD_US + 30_000) as u32;
+        CrossyMessage::TimeResponsePacket(interop::TimeResponsePacket {
+            client_send_time_us,
+            server_receive_time_us,
+            server_send_time_us : server_receive_time_us + 100,
+        })
+    }
+
+    #[test]
+    fn waits_for_clock_sync() {
+        let (mut client, time) = client();
+        let mut transport = QueueTransport::default();
+
+        assert!(client.pump(&mut transport).is_none());
+        assert_eq!(client.timeline().top_state().frame_id, 0);
+
+        let request = transport.outgoing.iter().find(|x| matches!(x, CrossyMessage::TimeRequestPacket(_))).unwrap().clone();
+        time.set(60_000);
+        transport.incoming.push_back(time_response(&request, 0));
+        client.pump(&mut transport);
+        assert!(client.is_synced());
+
+        // Now ticks up to about where the server is
+        time.set(100_000);
+        assert!(client.pump(&mut transport).is_some());
+        let expected_frame_id = ((100_000 + SERVER_AHEAD_US) / TICK_INTERVAL_US as u64) as i64;
+        let frame_id = client.timeline().top_state().frame_id as i64;
+        assert!((frame_id - expected_frame_id).abs() <= 1, "frame {} expected {}", frame_id, expected_frame_id);
+    }
+
+    #[test]
+    fn sends_local_inputs() {
+        let (mut client, time) = client();
+        let mut transport = QueueTransport::default();
+        client.join(PlayerId(1));
+
+        client.pump(&mut transport);
+        let request = transport.outgoing.iter().find(|x| matches!(x, CrossyMessage::TimeRequestPacket(_))).unwrap().clone();
+        time.set(60_000);
+        transport.incoming.push_back(time_response(&request, 0));
+        client.pump(&mut transport);
+        transport.outgoing.clear();
+
+        time.set(120_000);
+        client.pump(&mut transport);
+        let sent_frames : Vec<u32> = transport.outgoing.iter()
+            .filter_map(|x| match x {
+                CrossyMessage::ClientTick(ticks) => Some(ticks.iter().map(|t| t.frame_id).collect::<Vec<_>>()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+
+        // Never more than the server accepts in one message, ending on our top frame
+        assert!(!sent_frames.is_empty());
+        assert!(sent_frames.len() <= interop::MAX_CLIENT_TICKS_PER_MESSAGE);
+        assert_eq!(*sent_frames.last().unwrap(), client.timeline().top_state().frame_id);
+    }
+
+    #[test]
+    fn keeps_chat_and_kicks() {
+        let (mut client, _) = client();
+        client.join(PlayerId(2));
+
+        client.recv(CrossyMessage::ServerChat(interop::ServerChat {
+            player_id : PlayerId(1),
+            frame_id : 0,
+            content : interop::ChatContent::Text("hi".to_owned()),
+        }));
+        client.recv(CrossyMessage::PlayerKicked(interop::PlayerKicked {
+            player_id : PlayerId(2),
+            socket_id : 0,
+        }));
+
+        assert_eq!(client.take_chat().len(), 1);
+        assert!(client.take_chat().is_empty());
+        assert!(client.is_kicked());
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock {
+    // Microseconds since the client started, never goes backwards
+    fn now_us(&self) -> u64;
+
+    // Wall clock microseconds since the client started. Can jump about, only used in telemetry
+    // to spot the two clocks drifting apart.
+    fn date_now_us(&self) -> u64;
+}
+
+// For native builds, std::time::Instant panics on wasm so the web client brings its own.
+pub struct StdClock {
+    start : Instant,
+    start_date : SystemTime,
+}
+
+impl StdClock {
+    pub fn new() -> Self {
+        Self {
+            start : Instant::now(),
+            start_date : SystemTime::now(),
+        }
+    }
+}
+
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for StdClock {
+    fn now_us(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    fn date_now_us(&self) -> u64 {
+        let since_epoch = |x : SystemTime| x.duration_since(UNIX_EPOCH).map(|x| x.as_micros() as u64).unwrap_or(0);
+        since_epoch(SystemTime::now()).saturating_sub(since_epoch(self.start_date))
+    }
+}
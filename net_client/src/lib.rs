@@ -0,0 +1,24 @@
+#![allow(unused_parens)]
+
+// Client side netcode shared by the wasm client and anything native, eg the windows build or tests.
+//
+// NetClient keeps a local Timeline running ahead of the server, sends the local player's inputs,
+// folds in everyone else's as the server reports them and keeps the clock synced.
+// It doesnt know how messages get to the server, hosts either implement Transport and call pump,
+// or like the wasm client move the messages themselves. Native clients can use UdpTransport.
+
+macro_rules! log {
+    ( $( $t:tt )* ) => {
+        crossy_multi_core::debug_logline(&format!( $( $t )* ))
+    }
+}
+
+mod clock;
+mod client;
+mod transport;
+mod udp;
+
+pub use clock::{Clock, StdClock};
+pub use client::{NetClient, TickReport};
+pub use transport::{deserialize_message, serialize_message, QueueTransport, Transport};
+pub use udp::UdpTransport;
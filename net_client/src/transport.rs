@@ -0,0 +1,40 @@
+use std::collections::VecDeque;
+
+use crossy_multi_core::interop::CrossyMessage;
+use serde::Deserialize;
+
+// Whatever carries messages between a NetClient and the server, eg a websocket.
+// Sending should never block, anything that cant go out yet is the transport's to queue.
+pub trait Transport {
+    fn send(&mut self, message : &CrossyMessage);
+
+    // Next message from the server, None when there is nothing waiting
+    fn try_recv(&mut self) -> Option<CrossyMessage>;
+}
+
+// Messages go over the wire as flexbuffers, same as the server expects.
+pub fn serialize_message(message : &CrossyMessage) -> Vec<u8> {
+    flexbuffers::to_vec(message).unwrap()
+}
+
+pub fn deserialize_message(buffer : &[u8]) -> Option<CrossyMessage> {
+    let reader = flexbuffers::Reader::get_root(buffer).map_err(|e| log!("{:?}", e)).ok()?;
+    CrossyMessage::deserialize(reader).map_err(|e| log!("{:?}", e)).ok()
+}
+
+// Plain queues, for hosts that move the messages themselves and for tests.
+#[derive(Debug, Default)]
+pub struct QueueTransport {
+    pub incoming : VecDeque<CrossyMessage>,
+    pub outgoing : VecDeque<CrossyMessage>,
+}
+
+impl Transport for QueueTransport {
+    fn send(&mut self, message : &CrossyMessage) {
+        self.outgoing.push_back(message.clone());
+    }
+
+    fn try_recv(&mut self) -> Option<CrossyMessage> {
+        self.incoming.pop_front()
+    }
+}
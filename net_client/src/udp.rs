@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use crossy_multi_core::interop::CrossyMessage;
+use crossy_multi_core::udp_protocol::{InputResender, SequenceCounter, SequenceFilter, UdpClientPacket, UdpServerPacket, MAX_DATAGRAM_BYTES};
+use serde::Deserialize;
+
+use crate::transport::Transport;
+
+// Talks to the server's udp port instead of a websocket, for native clients.
+// Join over http first, the game id, socket id and session token from /join go on every packet.
+// Inputs are resent until the server acks them, see core/src/udp_protocol.rs.
+pub struct UdpTransport {
+    socket : UdpSocket,
+
+    game_id : String,
+    socket_id : u32,
+    session_token : String,
+
+    outgoing_seq : SequenceCounter,
+    incoming_seq : SequenceFilter,
+    inputs : InputResender,
+
+    incoming : VecDeque<CrossyMessage>,
+    buffer : Vec<u8>,
+}
+
+impl UdpTransport {
+    pub fn connect(server : SocketAddr, game_id : &str, socket_id : u32, session_token : &str) -> io::Result<Self> {
+        let bind_addr = if (server.is_ipv4()) { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(server)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            game_id : game_id.to_owned(),
+            socket_id,
+            session_token : session_token.to_owned(),
+            outgoing_seq : Default::default(),
+            incoming_seq : Default::default(),
+            inputs : Default::default(),
+            incoming : Default::default(),
+            buffer : vec![0; MAX_DATAGRAM_BYTES],
+        })
+    }
+
+    // Inputs we are still waiting on the server to ack
+    pub fn unacked_input_count(&self) -> usize {
+        self.inputs.pending_count()
+    }
+
+    // Server packets that arrived duplicated or behind a newer one
+    pub fn dropped_packet_count(&self) -> u32 {
+        self.incoming_seq.dropped_count()
+    }
+
+    fn send_packet(&mut self, messages : Vec<CrossyMessage>) {
+        let packet = UdpClientPacket {
+            game_id : self.game_id.clone(),
+            socket_id : self.socket_id,
+            session_token : self.session_token.clone(),
+            seq : self.outgoing_seq.next_seq(),
+            messages,
+        };
+
+        // Fire and forget, inputs get resent and nothing else needs to arrive
+        let serialized = flexbuffers::to_vec(&packet).unwrap();
+        if let Err(e) = self.socket.send(&serialized) {
+            log!("UDP send error {:?}", e);
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, message : &CrossyMessage) {
+        match message {
+            CrossyMessage::ClientTick(ticks) => {
+                self.inputs.push(ticks.iter().cloned());
+                let pending = self.inputs.pending();
+                self.send_packet(vec![CrossyMessage::ClientTick(pending)]);
+            }
+            _ => {
+                self.send_packet(vec![message.clone()]);
+            }
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<CrossyMessage> {
+        loop {
+            if let Some(message) = self.incoming.pop_front() {
+                return Some(message);
+            }
+
+            let len = match self.socket.recv(&mut self.buffer) {
+                Ok(x) => x,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    // Eg connection refused while the server restarts, try again next time
+                    log!("UDP receive error {:?}", e);
+                    return None;
+                }
+            };
+
+            let packet = flexbuffers::Reader::get_root(&self.buffer[..len])
+                .map_err(|e| log!("{:?}", e))
+                .ok()
+                .and_then(|x| UdpServerPacket::deserialize(x).map_err(|e| log!("{:?}", e)).ok());
+
+            if let Some(packet) = packet {
+                if (!self.incoming_seq.accept(packet.seq)) {
+                    continue;
+                }
+
+                if let Some(ack) = packet.input_ack_frame_id {
+                    self.inputs.ack(ack);
+                }
+
+                self.incoming.extend(packet.messages);
+            }
+        }
+    }
+}
+
+impl Drop for UdpTransport {
+    // Lets the server drop our player now rather than waiting to time us out
+    fn drop(&mut self) {
+        self.send_packet(vec![CrossyMessage::ClientDrop()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossy_multi_core::interop::ClientTick;
+    use crossy_multi_core::Input;
+
+    fn recv_client_packet(server : &UdpSocket) -> UdpClientPacket {
+        let mut buffer = vec![0; MAX_DATAGRAM_BYTES];
+        let (len, _) = server.recv_from(&mut buffer).unwrap();
+        UdpClientPacket::deserialize(flexbuffers::Reader::get_root(&buffer[..len]).unwrap()).unwrap()
+    }
+
+    fn sent_frames(packet : &UdpClientPacket) -> Vec<u32> {
+        match &packet.messages[..] {
+            [CrossyMessage::ClientTick(ticks)] => ticks.iter().map(|x| x.frame_id).collect(),
+            _ => panic!("Expected a client tick, got {:?}", packet.messages),
+        }
+    }
+
+    fn client_tick(frame_id : u32) -> CrossyMessage {
+        CrossyMessage::ClientTick(vec![ClientTick {
+            time_us : frame_id * 16_666,
+            frame_id,
+            input : Input::None,
+        }])
+    }
+
+    #[test]
+    fn resends_until_acked() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let mut transport = UdpTransport::connect(server.local_addr().unwrap(), "game", 3, "token").unwrap();
+
+        transport.send(&client_tick(10));
+        transport.send(&client_tick(11));
+
+        let first = recv_client_packet(&server);
+        assert_eq!((first.game_id.as_str(), first.socket_id, first.session_token.as_str()), ("game", 3, "token"));
+        assert_eq!(sent_frames(&first), vec![10]);
+        let second = recv_client_packet(&server);
+        assert_eq!(sent_frames(&second), vec![10, 11]);
+        assert!(second.seq > first.seq);
+
+        // Ack the first frame along with a message
+        let client_addr = transport.socket.local_addr().unwrap();
+        let ack = UdpServerPacket {
+            seq : 1,
+            input_ack_frame_id : Some(10),
+            messages : vec![CrossyMessage::GoodBye()],
+        };
+        server.send_to(&flexbuffers::to_vec(&ack).unwrap(), client_addr).unwrap();
+
+        // Nonblocking so wait for it to arrive
+        let mut received = None;
+        for _ in 0..500 {
+            received = transport.try_recv();
+            if (received.is_some()) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(matches!(received, Some(CrossyMessage::GoodBye())));
+        assert_eq!(transport.unacked_input_count(), 1);
+
+        // Replayed server packet is ignored
+        server.send_to(&flexbuffers::to_vec(&ack).unwrap(), client_addr).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(transport.try_recv().is_none());
+        assert_eq!(transport.dropped_packet_count(), 1);
+
+        transport.send(&client_tick(12));
+        assert_eq!(sent_frames(&recv_client_packet(&server)), vec![11, 12]);
+
+        drop(transport);
+        assert!(matches!(recv_client_packet(&server).messages[..], [CrossyMessage::ClientDrop()]));
+    }
+}
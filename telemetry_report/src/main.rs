@@ -0,0 +1,375 @@
+#![allow(unused_parens)]
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crossy_multi_core::interop::TelemetryMessage;
+use crossy_multi_core::telemetry::TelemetryRecord;
+use crossy_multi_core::timeline::TICK_INTERVAL_US;
+use serde::Serialize;
+
+// Reads the telemetry the server writes to logs/{game_id}.{n}.jsonl and reports how well
+// each client is keeping in sync with the server.
+//
+// telemetry_report [--late-frames 100] [--bucket-secs 10] [--json report.json] logs/ [more.jsonl ...]
+//
+// Directories are searched for .jsonl files, so pointing it at logs/ covers every game.
+
+const USAGE: &str = "usage: telemetry_report [--late-frames n] [--bucket-secs n] [--json path] path...";
+
+const FRAMES_PER_SEC: f64 = 1_000_000.0 / TICK_INTERVAL_US as f64;
+
+struct Options {
+    paths: Vec<PathBuf>,
+    // Matches the servers LKG_WINDOW_FRAMES, inputs older than this get dropped as stale
+    late_frames: i64,
+    bucket_frames: u32,
+    json_path: Option<String>,
+}
+
+impl Options {
+    fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            paths: Vec::new(),
+            late_frames: 100,
+            bucket_frames: (10.0 * FRAMES_PER_SEC) as u32,
+            json_path: None,
+        };
+
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].as_str();
+            if (!arg.starts_with("--")) {
+                options.paths.push(PathBuf::from(arg));
+                i += 1;
+                continue;
+            }
+
+            if (arg == "--help") {
+                return Err(USAGE.to_owned());
+            }
+
+            let value = args.get(i + 1).ok_or_else(|| format!("Missing value for {}\n{}", arg, USAGE))?;
+            match arg {
+                "--late-frames" => options.late_frames = parse_value(arg, value)?,
+                "--bucket-secs" => {
+                    let secs: f64 = parse_value(arg, value)?;
+                    if (secs <= 0.0) {
+                        return Err("bucket-secs must be positive".to_owned());
+                    }
+                    options.bucket_frames = ((secs * FRAMES_PER_SEC) as u32).max(1);
+                },
+                "--json" => options.json_path = Some(value.clone()),
+                _ => return Err(format!("Unknown flag {}\n{}", arg, USAGE)),
+            }
+
+            i += 2;
+        }
+
+        if (options.paths.is_empty()) {
+            return Err(format!("No telemetry files given\n{}", USAGE));
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if (path.is_dir()) {
+        let entries = std::fs::read_dir(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+            let entry_path = entry.path();
+            if (entry_path.is_dir() || entry_path.extension().map(|x| x == "jsonl").unwrap_or(false)) {
+                collect_files(&entry_path, files)?;
+            }
+        }
+    }
+    else {
+        files.push(path.to_owned());
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Percentiles {
+    count: usize,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Percentiles {
+    fn from_values(mut values: Vec<f64>) -> Self {
+        if (values.is_empty()) {
+            return Self::default();
+        }
+
+        values.sort_by(|a, b| a.total_cmp(b));
+        let at = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+        Self {
+            count: values.len(),
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: values[values.len() - 1],
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FrameDeltaBucket {
+    // Start of the bucket in seconds of server time
+    server_secs: f64,
+    samples: usize,
+    mean: f64,
+    min: i64,
+    max: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct PlayerReport {
+    game_id: String,
+    player_id: u8,
+    records: usize,
+    first_server_frame: u32,
+    last_server_frame: u32,
+
+    // Estimate made when the client joined
+    initial_latency_ms: Option<f64>,
+    // One way latency from each ping, before the client smooths it
+    latency_ms: Percentiles,
+
+    // Difference between the clients running server clock and what each new ping says it should be
+    clock_offset_mean_ms: Option<f64>,
+    clock_offset_sd_ms: Option<f64>,
+    // How fast that difference changes, a well synced client stays near zero
+    clock_drift_ms_per_min: Option<f64>,
+
+    // Client frame when a server tick arrived minus the frame the server sent it on
+    frame_delta: Percentiles,
+    frame_delta_over_time: Vec<FrameDeltaBucket>,
+
+    // Telemetry the server received from further in the past than it keeps inputs for,
+    // any input sent alongside it would have been dropped as stale
+    arrivals: usize,
+    late_arrivals: usize,
+    late_fraction: f64,
+}
+
+#[derive(Default)]
+struct PlayerData {
+    records: Vec<TelemetryRecord>,
+}
+
+fn analyse(game_id: &str, player_id: u8, mut data: PlayerData, options: &Options) -> PlayerReport {
+    data.records.sort_by_key(|x| (x.server_frame_id, x.timestamp_ms));
+
+    let mut initial_latency_ms = None;
+    let mut latencies = Vec::new();
+    // (server secs, offset ms)
+    let mut offsets = Vec::new();
+    // (server frame, delta)
+    let mut frame_deltas = Vec::new();
+    let mut arrivals = 0;
+    let mut late_arrivals = 0;
+
+    for record in &data.records {
+        // Client frame the event was generated on, to compare with when the server got it
+        let client_frame = match &record.event {
+            TelemetryMessage::LatencyEstimate(x) => {
+                initial_latency_ms.get_or_insert(x.estimated_latency_us as f64 / 1000.0);
+                None
+            },
+            TelemetryMessage::PingOutcome(x) => {
+                latencies.push(x.unlerped_estimated_latency_us as f64 / 1000.0);
+
+                let offset_ms = x.estimated_server_time_us as f64 / 1000.0 - x.current_client_time_ms as f64;
+                offsets.push((record.server_frame_id as f64 / FRAMES_PER_SEC, offset_ms));
+
+                Some(x.current_client_time_ms as i64 * 1000 / TICK_INTERVAL_US as i64)
+            },
+            TelemetryMessage::ClientReceiveEvent(x) => {
+                frame_deltas.push((x.server_send_frame_id, x.receive_frame_id as i64 - x.server_send_frame_id as i64));
+                Some(x.receive_frame_id as i64)
+            },
+        };
+
+        if let Some(client_frame) = client_frame {
+            arrivals += 1;
+            if (record.server_frame_id as i64 - client_frame > options.late_frames) {
+                late_arrivals += 1;
+            }
+        }
+    }
+
+    let (clock_offset_mean_ms, clock_offset_sd_ms) = mean_sd(offsets.iter().map(|x| x.1));
+
+    PlayerReport {
+        game_id: game_id.to_owned(),
+        player_id,
+        records: data.records.len(),
+        first_server_frame: data.records.first().map(|x| x.server_frame_id).unwrap_or(0),
+        last_server_frame: data.records.last().map(|x| x.server_frame_id).unwrap_or(0),
+        initial_latency_ms,
+        latency_ms: Percentiles::from_values(latencies),
+        clock_offset_mean_ms,
+        clock_offset_sd_ms,
+        clock_drift_ms_per_min: slope(&offsets).map(|x| x * 60.0),
+        frame_delta: Percentiles::from_values(frame_deltas.iter().map(|x| x.1 as f64).collect()),
+        frame_delta_over_time: bucket_frame_deltas(&frame_deltas, options.bucket_frames),
+        arrivals,
+        late_arrivals,
+        late_fraction: late_arrivals as f64 / arrivals.max(1) as f64,
+    }
+}
+
+fn mean_sd(values: impl Iterator<Item = f64> + Clone) -> (Option<f64>, Option<f64>) {
+    let count = values.clone().count();
+    if (count == 0) {
+        return (None, None);
+    }
+
+    let mean = values.clone().sum::<f64>() / count as f64;
+    let variance = values.map(|x| (x - mean) * (x - mean)).sum::<f64>() / count as f64;
+    (Some(mean), Some(variance.sqrt()))
+}
+
+// Least squares fit of y against x
+fn slope(points: &[(f64, f64)]) -> Option<f64> {
+    if (points.len() < 2) {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|x| x.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|x| x.1).sum::<f64>() / n;
+    let covariance: f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance_x: f64 = points.iter().map(|(x, _)| (x - mean_x) * (x - mean_x)).sum();
+
+    if (variance_x == 0.0) {
+        None
+    }
+    else {
+        Some(covariance / variance_x)
+    }
+}
+
+fn bucket_frame_deltas(frame_deltas: &[(u32, i64)], bucket_frames: u32) -> Vec<FrameDeltaBucket> {
+    let mut buckets: BTreeMap<u32, Vec<i64>> = BTreeMap::new();
+    for (frame, delta) in frame_deltas {
+        buckets.entry(frame / bucket_frames).or_default().push(*delta);
+    }
+
+    buckets.into_iter().map(|(bucket, deltas)| FrameDeltaBucket {
+        server_secs: (bucket * bucket_frames) as f64 / FRAMES_PER_SEC,
+        samples: deltas.len(),
+        mean: deltas.iter().sum::<i64>() as f64 / deltas.len() as f64,
+        min: *deltas.iter().min().unwrap(),
+        max: *deltas.iter().max().unwrap(),
+    }).collect()
+}
+
+fn format_opt(x: Option<f64>) -> String {
+    x.map(|x| format!("{:.1}", x)).unwrap_or_else(|| "-".to_owned())
+}
+
+fn print_report(report: &PlayerReport) {
+    println!();
+    println!("== {} player {} ({} records, server {:.0}s - {:.0}s)",
+        report.game_id,
+        report.player_id,
+        report.records,
+        report.first_server_frame as f64 / FRAMES_PER_SEC,
+        report.last_server_frame as f64 / FRAMES_PER_SEC);
+
+    let latency = &report.latency_ms;
+    println!("  latency ms      initial {}  p50 {:.1}  p90 {:.1}  p99 {:.1}  max {:.1}  ({} pings)",
+        format_opt(report.initial_latency_ms), latency.p50, latency.p90, latency.p99, latency.max, latency.count);
+    println!("  clock offset ms mean {}  sd {}  drift {} ms/min",
+        format_opt(report.clock_offset_mean_ms),
+        format_opt(report.clock_offset_sd_ms),
+        format_opt(report.clock_drift_ms_per_min));
+
+    let frame_delta = &report.frame_delta;
+    println!("  frame delta     p50 {:.0}  p90 {:.0}  p99 {:.0}  max {:.0}  ({} ticks)",
+        frame_delta.p50, frame_delta.p90, frame_delta.p99, frame_delta.max, frame_delta.count);
+    println!("  late arrivals   {} / {} ({:.1}%)", report.late_arrivals, report.arrivals, report.late_fraction * 100.0);
+
+    if (!report.frame_delta_over_time.is_empty()) {
+        println!("  frame delta over time:");
+        for bucket in &report.frame_delta_over_time {
+            println!("    {:>7.0}s  mean {:>6.1}  min {:>4}  max {:>4}  ({} ticks)", bucket.server_secs, bucket.mean, bucket.min, bucket.max, bucket.samples);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match Options::from_args(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut files = Vec::new();
+    for path in &options.paths {
+        if let Err(e) = collect_files(path, &mut files) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let mut players: BTreeMap<(String, u8), PlayerData> = BTreeMap::new();
+    let mut bad_lines = 0;
+    for file in &files {
+        let contents = match std::fs::read_to_string(file) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Skipping {:?}: {}", file, e);
+                continue;
+            }
+        };
+
+        for line in contents.lines().filter(|x| !x.trim().is_empty()) {
+            match serde_json::from_str::<TelemetryRecord>(line) {
+                Ok(record) => {
+                    let key = (record.game_id.clone(), record.player_id.0);
+                    players.entry(key).or_default().records.push(record);
+                },
+                Err(_) => bad_lines += 1,
+            }
+        }
+    }
+
+    println!("Read {} files, {} players", files.len(), players.len());
+    if (bad_lines > 0) {
+        println!("Skipped {} lines that were not telemetry records", bad_lines);
+    }
+
+    let reports: Vec<PlayerReport> = players.into_iter()
+        .map(|((game_id, player_id), data)| analyse(&game_id, player_id, data, &options))
+        .collect();
+
+    for report in &reports {
+        print_report(report);
+    }
+
+    if let Some(path) = &options.json_path {
+        let json = serde_json::to_string_pretty(&reports).unwrap();
+        if let Err(e) = std::fs::write(path, json) {
+            eprintln!("Unable to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+        println!();
+        println!("Wrote {}", path);
+    }
+}
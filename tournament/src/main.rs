@@ -0,0 +1,634 @@
+#![allow(unused_parens)]
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crossy_multi_core::ai::{self, AIAgent};
+use crossy_multi_core::crossy_ruleset::{self, AliveState, CrossyRulesetFST, DeathCause, GameConfig};
+use crossy_multi_core::player::MoveState;
+use crossy_multi_core::timeline::{RemoteInput, Timeline, TICK_INTERVAL_US};
+use crossy_multi_core::{GameState, Input, PlayerId, Pos};
+use serde::Serialize;
+
+// Runs complete matches between AI agents in process, with no networking or rendering,
+// as fast as we can simulate them. Used to balance map generation and to catch AI regressions.
+// Matches that never finish usually mean every agent is stuck on an impassable section of map.
+//
+// tournament [--agent expert] [--agent novice ...] [--seeds 0..100] [--threads n]
+//            [--required-wins 3] [--max-match-minutes 10] [--outlier-sd 2.5] [--json summary.json]
+
+const USAGE: &str = "usage: tournament [--agent config]... [--seeds start..end] [--threads n] \
+[--required-wins n] [--max-match-minutes n] [--outlier-sd n] [--json path]";
+
+const DEFAULT_AGENTS: [&str; 4] = ["expert", "normal", "novice", "go_up"];
+
+// Deaths this soon after a push get blamed on the push as well.
+const PUSH_BLAME_WINDOW_US: u32 = 1_000_000;
+
+// Outliers printed in the text summary, the json has all of them.
+const MAX_OUTLIERS_PRINTED: usize = 20;
+
+struct Options {
+    agents: Vec<String>,
+    seeds: Range<u32>,
+    threads: usize,
+    required_win_count: u8,
+    max_match_frames: u32,
+    outlier_sd: f64,
+    json_path: Option<String>,
+}
+
+impl Options {
+    fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            agents: Vec::new(),
+            seeds: 0..100,
+            threads: std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1),
+            required_win_count: GameConfig::default().required_win_count,
+            max_match_frames: 10 * 60 * 60,
+            outlier_sd: 2.5,
+            json_path: None,
+        };
+
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].as_str();
+            if (arg == "--help") {
+                return Err(USAGE.to_owned());
+            }
+
+            let value = args.get(i + 1).ok_or_else(|| format!("Missing value for {}\n{}", arg, USAGE))?;
+            match arg {
+                "--agent" => options.agents.push(value.to_lowercase()),
+                "--seeds" => options.seeds = parse_seeds(value)?,
+                "--threads" => options.threads = parse_value(arg, value)?,
+                "--required-wins" => options.required_win_count = parse_value(arg, value)?,
+                "--max-match-minutes" => options.max_match_frames = parse_value::<u32>(arg, value)? * 60 * 60,
+                "--outlier-sd" => options.outlier_sd = parse_value(arg, value)?,
+                "--json" => options.json_path = Some(value.clone()),
+                _ => return Err(format!("Unknown flag {}\n{}", arg, USAGE)),
+            }
+
+            i += 2;
+        }
+
+        if (options.agents.is_empty()) {
+            options.agents = DEFAULT_AGENTS.iter().map(|x| x.to_string()).collect();
+        }
+
+        if (options.agents.len() < 2 || options.agents.len() > crossy_ruleset::MAX_PLAYERS as usize) {
+            return Err(format!("Need between 2 and {} agents", crossy_ruleset::MAX_PLAYERS));
+        }
+
+        for agent in &options.agents {
+            ai::parse_agent(agent).map_err(|e| format!("Bad agent {}: {}", agent, e))?;
+        }
+
+        if (options.seeds.is_empty()) {
+            return Err("Seed range is empty".to_owned());
+        }
+
+        if (options.threads == 0) {
+            return Err("threads must be non zero".to_owned());
+        }
+
+        let config = GameConfig {
+            required_win_count: options.required_win_count,
+            ..GameConfig::default()
+        };
+        config.validate()?;
+
+        Ok(options)
+    }
+}
+
+fn parse_seeds(value: &str) -> Result<Range<u32>, String> {
+    match value.split_once("..") {
+        Some((start, end)) => Ok(parse_value("--seeds", start)?..parse_value("--seeds", end)?),
+        None => {
+            let seed = parse_value("--seeds", value)?;
+            Ok(seed..seed + 1)
+        },
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DeathRecord {
+    agent: usize,
+    // None if the player died in a way we dont understand
+    cause: Option<DeathCause>,
+    pushed: bool,
+    time_into_round_ms: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RoundRecord {
+    seed: u32,
+    round_id: u8,
+    duration_ms: u32,
+    // Agent index, None when everyone died
+    winner: Option<usize>,
+    deaths: Vec<DeathRecord>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MatchRecord {
+    seed: u32,
+    // None if the match hit the frame limit
+    winner: Option<usize>,
+    rounds: Vec<RoundRecord>,
+    simulated_ms: u32,
+}
+
+fn spawn_pos(i: usize) -> Pos {
+    Pos::new_coord(7 + (i % 7) as i32, 7 + (i / 7) as i32)
+}
+
+fn agent_index(player_id: PlayerId) -> usize {
+    player_id.0 as usize - 1
+}
+
+fn run_match(seed: u32, options: &Options) -> MatchRecord {
+    let config = GameConfig {
+        required_win_count: options.required_win_count,
+        bypass_lobby: true,
+        ..GameConfig::default()
+    };
+
+    let mut timeline = Timeline::from_seed(config, &seed.to_string());
+    let mut agents: Vec<(PlayerId, Box<dyn AIAgent>)> = Vec::with_capacity(options.agents.len());
+    for (i, agent) in options.agents.iter().enumerate() {
+        let player_id = PlayerId(i as u8 + 1);
+        timeline.add_player(player_id, spawn_pos(i));
+        agents.push((player_id, ai::create_agent(agent, player_id).unwrap()));
+    }
+
+    let mut tracker = MatchTracker::new(seed, agents.len());
+
+    for _ in 0..options.max_match_frames {
+        timeline.tick(None, TICK_INTERVAL_US);
+
+        let top = timeline.top_state();
+        let mut inputs = Vec::new();
+        for (player_id, agent) in &mut agents {
+            let input = agent.think(top, &timeline.map);
+            if (input != Input::None) {
+                inputs.push(RemoteInput {
+                    time_us: top.time_us,
+                    frame_id: top.frame_id,
+                    input,
+                    player_id: *player_id,
+                });
+            }
+        }
+
+        if (!inputs.is_empty()) {
+            timeline.try_propagate_inputs(inputs, true);
+        }
+
+        if (tracker.observe(timeline.top_state(), &timeline)) {
+            break;
+        }
+    }
+
+    tracker.finish(timeline.top_state().time_us)
+}
+
+// Follows the ruleset state machine through a match, recording rounds and deaths.
+struct MatchTracker {
+    seed: u32,
+    prev: Option<CrossyRulesetFST>,
+    alive: Vec<bool>,
+    last_pushed_us: Vec<Option<u32>>,
+    round_start_us: u32,
+    // Set once the round is decided, players can still die during the cooldown
+    cooldown: Option<RoundRecord>,
+    deaths: Vec<DeathRecord>,
+    rounds: Vec<RoundRecord>,
+    winner: Option<usize>,
+}
+
+impl MatchTracker {
+    fn new(seed: u32, agent_count: usize) -> Self {
+        Self {
+            seed,
+            prev: None,
+            alive: vec![false; agent_count],
+            last_pushed_us: vec![None; agent_count],
+            round_start_us: 0,
+            cooldown: None,
+            deaths: Vec::new(),
+            rounds: Vec::new(),
+            winner: None,
+        }
+    }
+
+    // Returns true once the match is over
+    fn observe(&mut self, state: &GameState, timeline: &Timeline) -> bool {
+        let fst = &state.rules_state.fst;
+        let entered = self.prev.as_ref().map(|x| !x.same_variant(fst)).unwrap_or(true);
+
+        for (id, player_state) in state.player_states.iter() {
+            if let MoveState::Moving(moving_state) = &player_state.move_state {
+                if (moving_state.push_info.pushed_by.is_some()) {
+                    self.last_pushed_us[agent_index(id)] = Some(state.time_us);
+                }
+            }
+        }
+
+        if (entered) {
+            self.close_round();
+        }
+
+        match fst {
+            CrossyRulesetFST::Round(_) if entered => {
+                self.round_start_us = state.time_us;
+                self.deaths.clear();
+                for (i, alive) in self.alive.iter_mut().enumerate() {
+                    *alive = fst.get_player_alive(PlayerId(i as u8 + 1)) == AliveState::Alive;
+                }
+            },
+            CrossyRulesetFST::Round(round_state) => {
+                self.record_deaths(state, timeline, round_state.round_id);
+            },
+            CrossyRulesetFST::RoundCooldown(cooldown) => {
+                // The deaths that end the round happen on the same frame we enter the cooldown
+                self.record_deaths(state, timeline, cooldown.round_state.round_id);
+
+                if (entered) {
+                    self.cooldown = Some(RoundRecord {
+                        seed: self.seed,
+                        round_id: cooldown.round_state.round_id,
+                        duration_ms: state.time_us.saturating_sub(self.round_start_us) / 1000,
+                        winner: None,
+                        deaths: Vec::new(),
+                    });
+                }
+            },
+            CrossyRulesetFST::EndWinner(end_state) => {
+                self.winner = Some(agent_index(end_state.winner_id));
+                return true;
+            },
+            CrossyRulesetFST::EndAllLeft(_) => {
+                return true;
+            },
+            _ => {},
+        }
+
+        self.prev = Some(fst.clone());
+        false
+    }
+
+    fn close_round(&mut self) {
+        if let Some(mut round) = self.cooldown.take() {
+            round.winner = self.alive.iter().position(|x| *x);
+            round.deaths = std::mem::take(&mut self.deaths);
+            self.rounds.push(round);
+        }
+    }
+
+    fn record_deaths(&mut self, state: &GameState, timeline: &Timeline, round_id: u8) {
+        let fst = &state.rules_state.fst;
+        for i in 0..self.alive.len() {
+            let player_id = PlayerId(i as u8 + 1);
+            if (!self.alive[i] || fst.get_player_alive(player_id) == AliveState::Alive) {
+                continue;
+            }
+
+            self.alive[i] = false;
+
+            let cause = state.get_player(player_id).and_then(|player_state| {
+                crossy_ruleset::death_cause(state.time_us, round_id, &timeline.map, player_state, fst.get_screen_y(), fst)
+            });
+
+            let pushed = self.last_pushed_us[i]
+                .map(|x| state.time_us.saturating_sub(x) < PUSH_BLAME_WINDOW_US)
+                .unwrap_or(false);
+
+            self.deaths.push(DeathRecord {
+                agent: i,
+                cause,
+                pushed,
+                time_into_round_ms: state.time_us.saturating_sub(self.round_start_us) / 1000,
+            });
+        }
+    }
+
+    fn finish(self, time_us: u32) -> MatchRecord {
+        MatchRecord {
+            seed: self.seed,
+            winner: self.winner,
+            rounds: self.rounds,
+            simulated_ms: time_us / 1000,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct AgentSummary {
+    config: String,
+    match_wins: u32,
+    match_win_rate: f64,
+    round_wins: u32,
+    round_win_rate: f64,
+    deaths: u32,
+    deaths_by_cause: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Outlier {
+    seed: u32,
+    round_id: u8,
+    duration_ms: u32,
+    // Standard deviations from the mean round length, negative is short
+    deviation: f64,
+}
+
+// Matches that hit the frame limit, kept out of the standings since nobody won them
+#[derive(Debug, Serialize)]
+struct UnfinishedMatch {
+    seed: u32,
+    rounds_played: usize,
+    simulated_ms: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    seeds: Range<u32>,
+    matches: usize,
+    // Win rates and round stats only cover these
+    finished_matches: usize,
+    unfinished: Vec<UnfinishedMatch>,
+    rounds: usize,
+    mean_round_ms: f64,
+    round_sd_ms: f64,
+    agents: Vec<AgentSummary>,
+    deaths_by_cause: BTreeMap<String, u32>,
+    deaths_after_push: u32,
+    outliers: Vec<Outlier>,
+    simulated_secs: f64,
+    wall_secs: f64,
+    matches_detail: Vec<MatchRecord>,
+}
+
+fn cause_name(cause: Option<DeathCause>) -> String {
+    match cause {
+        Some(x) => format!("{:?}", x),
+        None => "Unknown".to_owned(),
+    }
+}
+
+fn summarise(options: &Options, matches: Vec<MatchRecord>, wall_secs: f64) -> Summary {
+    let mut agents: Vec<AgentSummary> = options.agents.iter().map(|x| AgentSummary {
+        config: x.clone(),
+        ..Default::default()
+    }).collect();
+
+    let mut deaths_by_cause = BTreeMap::new();
+    let mut deaths_after_push = 0;
+    let mut durations = Vec::new();
+
+    let (finished, unfinished): (Vec<&MatchRecord>, Vec<&MatchRecord>) = matches.iter().partition(|x| x.winner.is_some());
+
+    for m in &finished {
+        if let Some(winner) = m.winner {
+            agents[winner].match_wins += 1;
+        }
+
+        for round in &m.rounds {
+            durations.push(round.duration_ms as f64);
+            if let Some(winner) = round.winner {
+                agents[winner].round_wins += 1;
+            }
+
+            for death in &round.deaths {
+                let name = cause_name(death.cause);
+                *deaths_by_cause.entry(name.clone()).or_insert(0) += 1;
+
+                let agent = &mut agents[death.agent];
+                agent.deaths += 1;
+                *agent.deaths_by_cause.entry(name).or_insert(0) += 1;
+
+                if (death.pushed) {
+                    deaths_after_push += 1;
+                }
+            }
+        }
+    }
+
+    let round_count = durations.len();
+    for agent in &mut agents {
+        agent.match_win_rate = agent.match_wins as f64 / finished.len().max(1) as f64;
+        agent.round_win_rate = agent.round_wins as f64 / round_count.max(1) as f64;
+    }
+
+    let mean = durations.iter().sum::<f64>() / round_count.max(1) as f64;
+    let variance = durations.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / round_count.max(1) as f64;
+    let sd = variance.sqrt();
+
+    let mut outliers: Vec<Outlier> = finished.iter()
+        .flat_map(|m| m.rounds.iter())
+        .filter_map(|round| {
+            let deviation = if (sd > 0.0) { (round.duration_ms as f64 - mean) / sd } else { 0.0 };
+            (deviation.abs() > options.outlier_sd).then_some(Outlier {
+                seed: round.seed,
+                round_id: round.round_id,
+                duration_ms: round.duration_ms,
+                deviation,
+            })
+        })
+        .collect();
+    outliers.sort_by(|a, b| b.deviation.abs().total_cmp(&a.deviation.abs()));
+
+    Summary {
+        seeds: options.seeds.clone(),
+        matches: matches.len(),
+        finished_matches: finished.len(),
+        unfinished: unfinished.iter().map(|x| UnfinishedMatch {
+            seed: x.seed,
+            rounds_played: x.rounds.len(),
+            simulated_ms: x.simulated_ms,
+        }).collect(),
+        rounds: round_count,
+        mean_round_ms: mean,
+        round_sd_ms: sd,
+        agents,
+        deaths_by_cause,
+        deaths_after_push,
+        outliers,
+        simulated_secs: matches.iter().map(|x| x.simulated_ms as f64 / 1000.0).sum(),
+        wall_secs,
+        matches_detail: matches,
+    }
+}
+
+fn print_summary(summary: &Summary) {
+    println!();
+    println!("Seeds {}..{}: {} matches, {} finished with {} rounds", summary.seeds.start, summary.seeds.end, summary.matches, summary.finished_matches, summary.rounds);
+    println!("Simulated {:.0}s in {:.1}s ({:.0}x real time)",
+        summary.simulated_secs,
+        summary.wall_secs,
+        summary.simulated_secs / summary.wall_secs.max(0.001));
+
+    if (!summary.unfinished.is_empty()) {
+        println!();
+        println!("{} unfinished (hit the frame limit), not counted below", summary.unfinished.len());
+        for m in &summary.unfinished {
+            println!("  seed {:>6}: {} rounds in {:.1}s", m.seed, m.rounds_played, m.simulated_ms as f64 / 1000.0);
+        }
+    }
+
+    println!();
+    println!("{:<3} {:<32} {:>10} {:>10} {:>10}  Deaths", "#", "Agent", "Match win", "Round win", "Deaths");
+    for (i, agent) in summary.agents.iter().enumerate() {
+        let causes: Vec<String> = agent.deaths_by_cause.iter().map(|(cause, count)| format!("{} {}", cause, count)).collect();
+        println!("{:<3} {:<32} {:>9.1}% {:>9.1}% {:>10}  {}",
+            i,
+            agent.config,
+            agent.match_win_rate * 100.0,
+            agent.round_win_rate * 100.0,
+            agent.deaths,
+            causes.join(", "));
+    }
+
+    println!();
+    println!("Round length {:.1}s average, {:.1}s standard deviation", summary.mean_round_ms / 1000.0, summary.round_sd_ms / 1000.0);
+
+    let total_deaths: u32 = summary.deaths_by_cause.values().sum();
+    println!("Death causes:");
+    for (cause, count) in &summary.deaths_by_cause {
+        println!("  {:<12} {:>6} ({:.1}%)", cause, count, *count as f64 * 100.0 / total_deaths.max(1) as f64);
+    }
+    println!("  {} deaths within {}ms of being pushed", summary.deaths_after_push, PUSH_BLAME_WINDOW_US / 1000);
+
+    println!();
+    println!("{} unusual rounds", summary.outliers.len());
+    for outlier in summary.outliers.iter().take(MAX_OUTLIERS_PRINTED) {
+        let kind = if (outlier.deviation < 0.0) { "fast" } else { "slow" };
+        println!("  seed {:>6} round {:>2}: {:>6.1}s ({}, {:+.1} sd)",
+            outlier.seed,
+            outlier.round_id,
+            outlier.duration_ms as f64 / 1000.0,
+            kind,
+            outlier.deviation);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match Options::from_args(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("Running seeds {}..{} with agents {:?} on {} threads", options.seeds.start, options.seeds.end, options.agents, options.threads);
+
+    let start = Instant::now();
+    let matches = run_seeds(&options);
+    let summary = summarise(&options, matches, start.elapsed().as_secs_f64());
+    print_summary(&summary);
+
+    if let Some(path) = &options.json_path {
+        let json = serde_json::to_string_pretty(&summary).unwrap();
+        if let Err(e) = std::fs::write(path, json) {
+            eprintln!("Unable to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+        println!();
+        println!("Wrote {}", path);
+    }
+}
+
+// Every seed in the range, spread over the threads. Sorted by seed so the output doesnt depend on scheduling.
+fn run_seeds(options: &Options) -> Vec<MatchRecord> {
+    let next_seed = AtomicU32::new(options.seeds.start);
+    let results = Mutex::new(Vec::with_capacity(options.seeds.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..options.threads {
+            scope.spawn(|| {
+                loop {
+                    let seed = next_seed.fetch_add(1, Ordering::Relaxed);
+                    if (seed >= options.seeds.end) {
+                        break;
+                    }
+
+                    let result = run_match(seed, options);
+                    results.lock().unwrap().push(result);
+                }
+            });
+        }
+    });
+
+    let mut matches = results.into_inner().unwrap();
+    matches.sort_by_key(|x| x.seed);
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        Options::from_args(&args).unwrap()
+    }
+
+    fn standings(summary: &Summary) -> Vec<(String, u32, u32)> {
+        summary.agents.iter().map(|x| (x.config.clone(), x.match_wins, x.round_wins)).collect()
+    }
+
+    #[test]
+    fn small_seeds_give_stable_standings() {
+        let options = options(&["--agent", "expert", "--agent", "novice", "--seeds", "0..2", "--required-wins", "1", "--threads", "2"]);
+
+        let first = summarise(&options, run_seeds(&options), 0.0);
+        assert_eq!(first.matches, 2);
+        assert!(first.unfinished.is_empty());
+
+        // Same seeds play out the same whichever thread picks them up
+        let second = summarise(&options, run_seeds(&options), 0.0);
+        assert_eq!(standings(&first), standings(&second));
+        assert_eq!(serde_json::to_string(&first.matches_detail).unwrap(), serde_json::to_string(&second.matches_detail).unwrap());
+
+        assert_eq!(standings(&first), vec![
+            ("expert".to_owned(), 2, 2),
+            ("novice".to_owned(), 0, 0),
+        ]);
+    }
+
+    #[test]
+    fn unfinished_matches_are_kept_out_of_win_rates() {
+        let options = options(&["--agent", "expert", "--agent", "novice"]);
+        let record = |seed, winner| MatchRecord {
+            seed,
+            winner,
+            rounds: vec![RoundRecord {
+                seed,
+                round_id: 1,
+                duration_ms: 10_000,
+                winner,
+                deaths: Vec::new(),
+            }],
+            simulated_ms: 20_000,
+        };
+
+        let summary = summarise(&options, vec![record(0, Some(1)), record(1, None), record(2, None)], 0.0);
+        assert_eq!(summary.matches, 3);
+        assert_eq!(summary.finished_matches, 1);
+        assert_eq!(summary.rounds, 1);
+        assert_eq!(summary.agents[1].match_win_rate, 1.0);
+        assert_eq!(summary.unfinished.iter().map(|x| x.seed).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(summary.unfinished[0].rounds_played, 1);
+    }
+}
@@ -80,6 +80,9 @@ export function create_game(ctx, client, ws, key_event_source) {
                 else if (this.current_input === "2") {
                     this.client.set_ai("back_and_forth");
                 }
+                else if (this.current_input === "3") {
+                    this.client.set_ai("pathfind");
+                }
                 else
                 {
                     this.client.buffer_input_json('"' + this.current_input + '"');
@@ -192,7 +195,7 @@ export function create_game(ctx, client, ws, key_event_source) {
                     this.entities = create_entities_container();
                     let winner_name = "";
                     if (this.rules_state && this.rules_state.fst.type === "EndWinner") {
-                        winner_name = this.players.get(this.rules_state.fst.winner_id).name;
+                        winner_name = this.client.get_player_name(this.rules_state.fst.winner_id);
                     }
                     else
                     {
@@ -200,7 +203,7 @@ export function create_game(ctx, client, ws, key_event_source) {
                         const local_player_id = this.client.get_local_player_id();
                         if (local_player_id >= 0)
                         {
-                            winner_name = this.players.get(this.client.get_local_player_id()).name;
+                            winner_name = this.client.get_player_name(local_player_id);
                         }
                     }
 
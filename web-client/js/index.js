@@ -12,12 +12,22 @@ const query_string = window.location.search;
 const url_params = new URLSearchParams(query_string);
 var game_id = url_params.get('game_id');
 var debug_bypass_lobby = url_params.get('debug_bypass_lobby');
-
-var player_name = "Dan";
+var public_game = url_params.get('public');
+var bots = url_params.get('bots');
+var bot_ai = url_params.get('bot_ai');
+// eg ?net_sim=latency=120,jitter=30,loss=0.02, needs the server running with allow_net_sim_query
+var net_sim = url_params.get('net_sim');
+
+var player_name = url_params.get('name') || "";
+var client_id = get_client_id();
 var socket_id = 0;
+var session_token = undefined;
+var reconnect_attempts = 0;
+const MAX_RECONNECT_ATTEMPTS = 5;
 
 var client = undefined;
 var ws = undefined;
+var game = undefined;
 var estimated_latency_us = 0;
 
 var endpoint = "";
@@ -40,6 +50,22 @@ else {
     ws_endpoint = 'wss://roadtoads.io';
 }
 
+// Random id we keep between visits so the server can track ratings
+function get_client_id()
+{
+    const CLIENT_ID_KEY = 'client_id';
+    let id = window.localStorage.getItem(CLIENT_ID_KEY);
+    if (!id)
+    {
+        const bytes = new Uint8Array(16);
+        window.crypto.getRandomValues(bytes);
+        id = Array.from(bytes, x => x.toString(16).padStart(2, '0')).join('');
+        window.localStorage.setItem(CLIENT_ID_KEY, id);
+    }
+
+    return id;
+}
+
 export function fetch_json(url) {
     return fetch(endpoint + url, {
         headers: {  'Accept': 'application/json' },
@@ -82,12 +108,30 @@ function start_game() {
     {
         //fetch_json('/new')
         var url = '/new'
+        var params = [];
         if (debug_bypass_lobby) {
-            url += '?debug_bypass_lobby=true';
+            params.push('debug_bypass_lobby=true');
+        }
+        if (public_game) {
+            params.push('public=true');
+        }
+        if (bots) {
+            params.push('bots=' + encodeURIComponent(bots));
+        }
+        if (bot_ai) {
+            params.push('bot_ai=' + encodeURIComponent(bot_ai));
+        }
+        if (params.length > 0) {
+            url += '?' + params.join('&');
         }
         fetch_json(url)
         .then(response => response.json())
         .then(x => {
+            if (x.reason) {
+                console.log("Unable to create game: " + x.reason);
+                return;
+            }
+
             console.log("Created game ");
             console.log(x);
             game_id = x.game_id;
@@ -103,12 +147,18 @@ function join() {
 
     console.log("Calling join...");
 
-    fetch_json('/join?game_id=' + game_id + '&name=' + player_name)
+    fetch_json('/join?game_id=' + game_id + '&name=' + encodeURIComponent(player_name) + '&client_id=' + client_id)
         .then(response => response.json())
         .then(response => {
             console.log("/join response");
             console.log(response);
+            if (response.reason) {
+                console.log("Unable to join game: " + response.reason);
+                return;
+            }
+
             socket_id = response.socket_id;
+            session_token = response.session_token;
 
             console.log("Creating client");
             console.log("JS server_ms=" + response.server_time_us / 1000 + " estimated_latency=" + estimated_latency_us / 1000);
@@ -133,12 +183,26 @@ function play() {
 }
 
 function connect_ws() {
-    ws = new WebSocket(ws_endpoint + "/ws?game_id=" + game_id + '&socket_id=' + socket_id);
+    var ws_url = ws_endpoint + "/ws?game_id=" + game_id + '&socket_id=' + socket_id;
+    if (net_sim)
+    {
+        ws_url += '&net_sim=' + encodeURIComponent(net_sim);
+    }
+    ws = new WebSocket(ws_url);
     ws.binaryType = "arraybuffer";
     console.log("Opening ws");
 
     ws.onopen = () => {
-        setup_game();
+        if (game)
+        {
+            // Reconnected, point the running game at the new socket
+            game.ws = ws;
+        }
+        else
+        {
+            setup_game();
+        }
+        reconnect_attempts = 0;
         console.log("WS Open");
     };
 
@@ -152,9 +216,39 @@ function connect_ws() {
 
     ws.onclose = () => {
         console.log("WS closed");
+        rejoin();
     };
 }
 
+function rejoin() {
+    if (!session_token || reconnect_attempts >= MAX_RECONNECT_ATTEMPTS)
+    {
+        return;
+    }
+
+    if (client && client.is_kicked())
+    {
+        console.log("Kicked from the game, not rejoining");
+        return;
+    }
+
+    reconnect_attempts += 1;
+    console.log("Trying to rejoin, attempt " + reconnect_attempts);
+
+    fetch_json('/rejoin?game_id=' + game_id + '&session_token=' + session_token)
+        .then(response => response.json())
+        .then(response => {
+            console.log("/rejoin response");
+            console.log(response);
+            socket_id = response.socket_id;
+            connect_ws();
+        })
+        .catch(e => {
+            console.log("Rejoin failed " + e);
+            setTimeout(rejoin, 1000 * reconnect_attempts);
+        });
+}
+
 /////////////////////////////////////////////////////////////////////////////////////
 
 let key_event_source = {
@@ -229,7 +323,7 @@ const desired_fps = 60;
 const ms_per_frame = 1000 / desired_fps;
 
 function setup_game() {
-    let game = create_game(ctx, client, ws, key_event_source);
+    game = create_game(ctx, client, ws, key_event_source);
 
     let tick = () => {
         window.requestAnimationFrame(tick);
@@ -38,8 +38,8 @@ impl ClientSeenPushManager {
                         let pushee_pos = &state.player_states.get(*pushee_id).unwrap().pos;
                         let push_data = PushData {
                             state: PushDataState::Valid,
-                            pusher_pos : timeline.map.realise_pos(state.time_us, pusher_pos),
-                            pushee_pos: timeline.map.realise_pos(state.time_us, pushee_pos),
+                            pusher_pos : timeline.map.realise_pos(state.time_us, pusher_pos, &state.rules_state.fst),
+                            pushee_pos: timeline.map.realise_pos(state.time_us, pushee_pos, &state.rules_state.fst),
                         };
 
                         _ = self.pushes.insert(push_triple, push_data);
@@ -1,5 +0,0 @@
-
-#[derive(Default)]
-pub struct RoundEndPredictor
-{
-}
\ No newline at end of file
@@ -115,4 +115,28 @@ impl ops::Add<Duration> for WasmDateInstant { type Output = WasmDateInstant; fn
 impl ops::Sub<Duration> for WasmDateInstant { type Output = WasmDateInstant; fn sub(self, other: Duration) -> WasmDateInstant { self.checked_sub(other).unwrap() } }
 impl ops::Sub<WasmDateInstant>  for WasmDateInstant { type Output = Duration; fn sub(self, other: WasmDateInstant) -> Duration { self.duration_since(other) } }
 impl ops::AddAssign<Duration> for WasmDateInstant { fn add_assign(&mut self, other: Duration) { *self = *self + other; } }
-impl ops::SubAssign<Duration> for WasmDateInstant { fn sub_assign(&mut self, other: Duration) { *self = *self - other; } }
\ No newline at end of file
+impl ops::SubAssign<Duration> for WasmDateInstant { fn sub_assign(&mut self, other: Duration) { *self = *self - other; } }
+// Clock for crossy_multi_net_client, counting from when the client was created
+pub struct WasmClock {
+    start : WasmInstant,
+    start_date : WasmDateInstant,
+}
+
+impl WasmClock {
+    pub fn new() -> Self {
+        Self {
+            start : WasmInstant::now(),
+            start_date : WasmDateInstant::now(),
+        }
+    }
+}
+
+impl crossy_multi_net_client::Clock for WasmClock {
+    fn now_us(&self) -> u64 {
+        WasmInstant::now().saturating_duration_since(self.start).as_micros() as u64
+    }
+
+    fn date_now_us(&self) -> u64 {
+        WasmDateInstant::now().saturating_duration_since(self.start_date).as_micros() as u64
+    }
+}
@@ -0,0 +1,95 @@
+use crossy_multi_core::game;
+use crossy_multi_core::interop::{ClientTick, RejectionReason, MAX_CLIENT_MESSAGE_BYTES};
+use crossy_multi_core::map::RowWithY;
+use crossy_multi_core::map::obstacle_row::ObstaclePublic;
+use crossy_multi_core::player::PlayerStatePublic;
+use serde::{Deserialize, Serialize};
+
+// Protocol for bots written outside of this repo, in whatever language.
+//
+// Connect a websocket to /bot?game_id=abc&name=mybot, the server joins a player for the socket
+// and from then on everything is JSON text messages.
+//
+// Every server tick the bot receives
+//   {"type": "view", "frame_id": 1234, "player_id": 2, "phase": "round", "you": {...}, "players": [...], "rows": [...], ...}
+// and can reply with the input it wants to make on the frame it saw, and the time_us it had for that frame
+//   {"frame_id": 1234, "time_us": 20566000, "input": "Up"}
+// Without a time_us we use when the reply arrived, so a bot running far behind gets flagged either way.
+// Replies go through the same validation as human inputs, so stale frames and spamming get dropped.
+// Rows, cars and lillipads only cover the rows around the bot once it has spawned.
+// Problems with a reply are sent back as {"type": "rejected", "reason": "malformed"}.
+
+#[derive(Debug, Serialize)]
+pub struct BotPlayerView {
+    #[serde(flatten)]
+    pub state: PlayerStatePublic,
+    pub name: String,
+    pub is_bot: bool,
+    pub alive: bool,
+    pub can_move: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BotView {
+    pub frame_id: u32,
+    pub time_us: u32,
+    pub player_id: game::PlayerId,
+    // Same names as the /metrics phases, eg "lobby", "round"
+    pub phase: &'static str,
+    pub round_id: u8,
+    pub screen_y: i32,
+    // None until the player has spawned
+    pub you: Option<BotPlayerView>,
+    pub players: Vec<BotPlayerView>,
+    pub rows: Vec<RowWithY>,
+    pub cars: Vec<ObstaclePublic>,
+    pub lillipads: Vec<ObstaclePublic>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BotServerMessage {
+    View(Box<BotView>),
+    Rejected { reason: RejectionReason },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BotReply {
+    frame_id: u32,
+    time_us: Option<u32>,
+    input: game::Input,
+}
+
+pub fn parse_reply(text: &str, receive_time_us: u32) -> Result<ClientTick, RejectionReason> {
+    if (text.len() > MAX_CLIENT_MESSAGE_BYTES) {
+        return Err(RejectionReason::MessageTooLarge);
+    }
+
+    let reply: BotReply = serde_json::from_str(text).map_err(|e| {
+        println!("{e}");
+        RejectionReason::Malformed
+    })?;
+
+    Ok(ClientTick {
+        time_us: reply.time_us.unwrap_or(receive_time_us),
+        frame_id: reply.frame_id,
+        input: reply.input,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_time_falls_back_to_receive_time() {
+        let tick = parse_reply(r#"{"frame_id": 10, "time_us": 1234, "input": "Up"}"#, 99).unwrap();
+        assert_eq!((tick.frame_id, tick.time_us, tick.input), (10, 1234, game::Input::Up));
+
+        let tick = parse_reply(r#"{"frame_id": 10, "input": "Left"}"#, 99).unwrap();
+        assert_eq!(tick.time_us, 99);
+
+        assert_eq!(parse_reply(r#"{"frame_id": 10, "input": "Up", "extra": 1}"#, 99).err(), Some(RejectionReason::Malformed));
+    }
+}
@@ -0,0 +1,221 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use crossy_multi_core::net_sim::NetworkConditions;
+use crossy_multi_core::telemetry::TelemetryRotation;
+use serde::Deserialize;
+
+// Server settings, read from an optional TOML / JSON file then overridden by command line flags.
+//
+// web-server [serve_dir] [--config server.toml] [--serve-dir dir] [--bind 0.0.0.0] [--port 8080]
+//            [--worker-threads 10] [--tick-rate 60] [--empty-game-timeout 20]
+//            [--tls-cert cert.pem --tls-key key.pem] [--results-path results.jsonl] [--ratings-path ratings.json]
+//            [--telemetry-max-file-mb 16] [--telemetry-max-file-mins 60]
+//            [--net-sim latency=120,jitter=30,loss=0.02] [--allow-net-sim-query true] [--udp-port 8081]
+
+const USAGE: &str = "usage: web-server [serve_dir] [--config path] [--serve-dir dir] [--bind addr] [--port port] \
+[--worker-threads n] [--tick-rate hz] [--empty-game-timeout secs] [--tls-cert path --tls-key path] \
+[--results-path path] [--ratings-path path] [--telemetry-max-file-mb n] [--telemetry-max-file-mins n] \
+[--net-sim conditions] [--allow-net-sim-query bool] [--udp-port port]";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub serve_dir: String,
+    pub bind: IpAddr,
+    pub port: u16,
+    pub worker_threads: usize,
+    // How often each game broadcasts state, the simulation itself always steps at 60hz.
+    pub tick_rate_hz: u32,
+    // Games with nobody connected are shut down after this long.
+    pub empty_game_timeout_secs: u32,
+    pub tls: Option<TlsConfig>,
+    pub results_path: String,
+    pub ratings_path: String,
+    // Each game's telemetry moves onto a new file once the current one hits either limit.
+    pub telemetry_max_file_mb: u64,
+    pub telemetry_max_file_mins: u64,
+    // Development only, makes every websocket connection this bad.
+    pub net_sim: NetworkConditions,
+    // Development only, lets clients ask for their own bad network with /ws?net_sim=...
+    pub allow_net_sim_query: bool,
+    // Native clients can send datagrams here instead of using a websocket, off when unset.
+    pub udp_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            serve_dir: String::from("../web-client/dist"),
+            bind: IpAddr::from([0, 0, 0, 0]),
+            port: 8080,
+            worker_threads: 10,
+            tick_rate_hz: 60,
+            empty_game_timeout_secs: 20,
+            tls: None,
+            results_path: String::from(crate::results::RESULTS_PATH),
+            ratings_path: String::from(crate::ratings::RATINGS_PATH),
+            telemetry_max_file_mb: 16,
+            telemetry_max_file_mins: 60,
+            net_sim: NetworkConditions::default(),
+            allow_net_sim_query: false,
+            udp_port: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        // Find the config file first so flags always win over it
+        let mut config = match flag_value(args, "--config")? {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+
+        config.apply_args(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Unable to read config {}: {}", path, e))?;
+
+        let is_json = Path::new(path).extension().map(|x| x == "json").unwrap_or(false);
+        if (is_json) {
+            serde_json::from_str(&contents).map_err(|e| format!("Unable to parse config {}: {}", path, e))
+        }
+        else {
+            toml::from_str(&contents).map_err(|e| format!("Unable to parse config {}: {}", path, e))
+        }
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut tls_cert = None;
+        let mut tls_key = None;
+
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].as_str();
+
+            if (!arg.starts_with("--")) {
+                // Old style, serve dir as the only argument
+                if (i == 0) {
+                    self.serve_dir = arg.to_owned();
+                    i += 1;
+                    continue;
+                }
+
+                return Err(format!("Unexpected argument {}\n{}", arg, USAGE));
+            }
+
+            if (arg == "--help") {
+                return Err(USAGE.to_owned());
+            }
+
+            let value = args.get(i + 1).ok_or_else(|| format!("Missing value for {}\n{}", arg, USAGE))?;
+            match arg {
+                "--config" => {},
+                "--serve-dir" => self.serve_dir = value.clone(),
+                "--bind" => self.bind = parse_value(arg, value)?,
+                "--port" => self.port = parse_value(arg, value)?,
+                "--worker-threads" => self.worker_threads = parse_value(arg, value)?,
+                "--tick-rate" => self.tick_rate_hz = parse_value(arg, value)?,
+                "--empty-game-timeout" => self.empty_game_timeout_secs = parse_value(arg, value)?,
+                "--tls-cert" => tls_cert = Some(value.clone()),
+                "--tls-key" => tls_key = Some(value.clone()),
+                "--results-path" => self.results_path = value.clone(),
+                "--ratings-path" => self.ratings_path = value.clone(),
+                "--telemetry-max-file-mb" => self.telemetry_max_file_mb = parse_value(arg, value)?,
+                "--telemetry-max-file-mins" => self.telemetry_max_file_mins = parse_value(arg, value)?,
+                "--net-sim" => self.net_sim = value.parse().map_err(|e| format!("Invalid value for {}: {}", arg, e))?,
+                "--allow-net-sim-query" => self.allow_net_sim_query = parse_value(arg, value)?,
+                "--udp-port" => self.udp_port = Some(parse_value(arg, value)?),
+                _ => return Err(format!("Unknown flag {}\n{}", arg, USAGE)),
+            }
+
+            i += 2;
+        }
+
+        match (tls_cert, tls_key) {
+            (Some(cert_path), Some(key_path)) => self.tls = Some(TlsConfig { cert_path, key_path }),
+            (None, None) => {},
+            _ => return Err("--tls-cert and --tls-key must be given together".to_owned()),
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if (!Path::new(&self.serve_dir).is_dir()) {
+            return Err(format!("serve_dir {} is not a directory", self.serve_dir));
+        }
+
+        if (self.port == 0) {
+            return Err("port must be non zero".to_owned());
+        }
+
+        if (self.udp_port == Some(0)) {
+            return Err("udp_port must be non zero".to_owned());
+        }
+
+        if (self.worker_threads == 0 || self.worker_threads > 256) {
+            return Err(format!("worker_threads must be between 1 and 256, got {}", self.worker_threads));
+        }
+
+        if (self.tick_rate_hz == 0 || self.tick_rate_hz > 240) {
+            return Err(format!("tick_rate_hz must be between 1 and 240, got {}", self.tick_rate_hz));
+        }
+
+        if (self.empty_game_timeout_secs == 0) {
+            return Err("empty_game_timeout_secs must be non zero".to_owned());
+        }
+
+        if (self.telemetry_max_file_mb == 0 || self.telemetry_max_file_mins == 0) {
+            return Err("telemetry file limits must be non zero".to_owned());
+        }
+
+        self.net_sim.validate().map_err(|e| format!("net_sim: {}", e))?;
+
+        if let Some(tls) = &self.tls {
+            for path in [&tls.cert_path, &tls.key_path] {
+                if (!Path::new(path).is_file()) {
+                    return Err(format!("TLS file {} does not exist", path));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn server_settings(&self) -> crate::crossy_server::ServerSettings {
+        let tick_time = Duration::from_secs(1) / self.tick_rate_hz;
+        crate::crossy_server::ServerSettings {
+            tick_time,
+            empty_ticks_threshold: self.empty_game_timeout_secs * self.tick_rate_hz,
+            telemetry_rotation: TelemetryRotation {
+                max_bytes: self.telemetry_max_file_mb * 1024 * 1024,
+                max_age: Duration::from_secs(self.telemetry_max_file_mins * 60),
+            },
+            ..Default::default()
+        }
+    }
+}
+
+fn flag_value(args: &[String], flag: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|x| x == flag) {
+        Some(i) => args.get(i + 1).cloned().map(Some).ok_or_else(|| format!("Missing value for {}", flag)),
+        None => Ok(None),
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
@@ -1,31 +1,247 @@
 use std::io::Write;
 
 use chrono::prelude::*;
-use crossy_multi_core::crossy_ruleset::GameConfig;
+use crossy_multi_core::crossy_ruleset::{AliveState, CrossyRulesetFST, GameConfig, MAX_PLAYERS};
 use serde::{Deserialize, Serialize};
 use warp::hyper::client;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
+use crossy_multi_core::ai::{self, AIAgent};
 use crossy_multi_core::game;
 use crossy_multi_core::interop::*;
 use crossy_multi_core::player_id_map::PlayerIdMap;
+use crossy_multi_core::telemetry::{TelemetryEvent, TelemetryRotation, TelemetryTracer};
+
+use crate::bot_protocol::{BotPlayerView, BotView};
+use crate::metrics;
+use crate::rate_limit::WindowLimiter;
+use crate::ratings::{PlayerIdentity, RatingsStore};
+use crate::results::{MatchTracker, ResultsStore};
+use std::path::PathBuf;
+use std::sync::Arc;
 use crossy_multi_core::timeline::{RemoteInput, RemoteTickState, Timeline, TICK_INTERVAL_US};
 
 const SERVER_VERSION: u8 = 1;
-const DESIRED_TICK_TIME: Duration = Duration::from_nanos(16_666_666);
+
+#[derive(Clone, Debug)]
+pub struct ServerSettings {
+    // How long we aim for each iteration of the run loop to take
+    pub tick_time: Duration,
+    // Shut the game down after this many ticks with nobody connected
+    pub empty_ticks_threshold: u32,
+    // Per game telemetry segments go in here, named after the game id.
+    pub telemetry_dir: PathBuf,
+    pub telemetry_rotation: TelemetryRotation,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            tick_time: Duration::from_nanos(16_666_666),
+            empty_ticks_threshold: 60 * 20,
+            telemetry_dir: PathBuf::from("logs"),
+            telemetry_rotation: TelemetryRotation::default(),
+        }
+    }
+}
+
+// How long we hold onto a player after their socket drops before removing them from the game.
+pub const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(15);
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SocketId(pub u32);
 
+// Handed out on /join, a client presents this on /rejoin to reclaim its player after a dropped socket.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionToken(pub String);
+
+// Anyone reading the logs could otherwise take over the player
+impl std::fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionToken(..)")
+    }
+}
+
+impl SessionToken {
+    fn generate() -> Self {
+        SessionToken(format!("{:016x}", rand::random::<u64>()))
+    }
+}
+
+// Allow short bursts of chat but stop anyone flooding the game.
+const CHAT_RATE_LIMIT_COUNT: usize = 5;
+const CHAT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(5);
+
+// How far behind the top of the timeline the state we send clients as lkg is.
+const LKG_WINDOW_FRAMES: u32 = 100;
+
+// Real players cant get anywhere near this many moves into a second.
+const INPUT_WINDOW_FRAMES: u32 = 60;
+const MAX_INPUTS_PER_WINDOW: usize = 20;
+
+// How many rows either side of a bot it gets told about
+const BOT_VIEW_ROWS: i32 = 6;
+
+// Clients time_us should line up with the frame they claim, allow some slack for rounding / clock sync.
+const MAX_CLIENT_TIME_DRIFT_US: u32 = 250_000;
+
+// Clients run ahead of the server by their latency, anything further ahead than this is made up.
+const MAX_FUTURE_FRAMES: u32 = 30;
+// Future inputs are held until their frame, never keep more than this around
+const MAX_FUTURE_INPUTS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputViolation {
+    TooManyInputs,
+    StaleFrame,
+    TimeDrift,
+    FutureFrame,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct InputViolationCounts {
+    pub too_many_inputs: u32,
+    pub stale_frame: u32,
+    pub time_drift: u32,
+    pub future_frame: u32,
+}
+
+impl InputViolationCounts {
+    fn add(&mut self, violation: InputViolation) {
+        match violation {
+            InputViolation::TooManyInputs => self.too_many_inputs += 1,
+            InputViolation::StaleFrame => self.stale_frame += 1,
+            InputViolation::TimeDrift => self.time_drift += 1,
+            InputViolation::FutureFrame => self.future_frame += 1,
+        }
+    }
+
+    fn total(&self) -> u32 {
+        self.too_many_inputs + self.stale_frame + self.time_drift + self.future_frame
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PlayerViolations {
+    pub player_id: game::PlayerId,
+    pub name: String,
+    #[serde(flatten)]
+    pub counts: InputViolationCounts,
+}
+
 struct PlayerClient {
     id: game::PlayerId,
     last_tick_us: u32,
+    chat_rate_limiter: WindowLimiter,
+
+    // Server frame ids when recent inputs were accepted, for rate limiting
+    recent_input_frames: std::collections::VecDeque<u32>,
+    violations: InputViolationCounts,
+}
+
+impl PlayerClient {
+    fn new(id: game::PlayerId) -> Self {
+        Self {
+            id,
+            last_tick_us: 0,
+            chat_rate_limiter: WindowLimiter::new(CHAT_RATE_LIMIT_COUNT, CHAT_RATE_LIMIT_WINDOW),
+            recent_input_frames: Default::default(),
+            violations: Default::default(),
+        }
+    }
+
+    // Returns whether the tick should be applied, any violations are recorded against the player.
+    fn validate_tick(&mut self, tick: &ClientTick, top_frame_id: u32, top_time_us: u32) -> bool {
+        // Frames are a fixed interval apart so we know what time the client should have had.
+        let expected_time_us = top_time_us as i64 + (tick.frame_id as i64 - top_frame_id as i64) * TICK_INTERVAL_US as i64;
+        if ((tick.time_us as i64 - expected_time_us).unsigned_abs() > MAX_CLIENT_TIME_DRIFT_US as u64) {
+            // Flag but let it through, the frame id is what gets simulated
+            self.record_violation(InputViolation::TimeDrift);
+        }
+
+        let lkg_frame_id = top_frame_id.saturating_sub(LKG_WINDOW_FRAMES);
+        if (tick.frame_id < lkg_frame_id) {
+            // Clients resend empty ticks after reconnecting, only count real inputs against them.
+            if (tick.input != game::Input::None) {
+                self.record_violation(InputViolation::StaleFrame);
+            }
+            return false;
+        }
+
+        if (tick.frame_id > top_frame_id + MAX_FUTURE_FRAMES) {
+            self.record_violation(InputViolation::FutureFrame);
+            return false;
+        }
+
+        if (tick.input == game::Input::None) {
+            return true;
+        }
+
+        // Count against our frame not the claimed one, or spreading frame ids out would never fill the window
+        let window_start = top_frame_id.saturating_sub(INPUT_WINDOW_FRAMES);
+        self.recent_input_frames.retain(|x| *x > window_start);
+        if (self.recent_input_frames.len() >= MAX_INPUTS_PER_WINDOW) {
+            self.record_violation(InputViolation::TooManyInputs);
+            return false;
+        }
+
+        self.recent_input_frames.push_back(top_frame_id);
+        true
+    }
+
+    fn record_violation(&mut self, violation: InputViolation) {
+        self.violations.add(violation);
+        metrics::INPUT_VIOLATIONS.inc();
+
+        // Dont spam the log for clients that are constantly misbehaving
+        let total = self.violations.total();
+        if (total.is_power_of_two()) {
+            println!("[{:?}] Input violation {:?}, {} total", self.id, violation, total);
+        }
+    }
+}
+
+// Player controlled by the server, gets its input from an AIAgent each tick.
+#[derive(Debug)]
+struct ServerBot {
+    player_id: game::PlayerId,
+    agent: Box<dyn AIAgent>,
 }
 
 struct Client {
     player_client: Option<PlayerClient>,
     socket_id: SocketId,
+    session_token: SessionToken,
+    name: String,
+    identity: Option<PlayerIdentity>,
+
+    // Set when the socket drops, the player stays in the game until the grace period runs out.
+    disconnected_at: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamePhase {
+    Lobby,
+    InRound,
+    GameOver,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GameSummary {
+    pub player_count: u32,
+    pub phase: GamePhase,
+    pub round_id: u8,
+    pub locked: bool,
+    pub created_utc: String,
+    // Average rating of players that sent a client id
+    pub average_rating: Option<f64>,
+}
+
+pub struct RejoinInfo {
+    pub socket_id: SocketId,
+    pub player_id: game::PlayerId,
 }
 
 pub struct Server {
@@ -34,56 +250,62 @@ pub struct Server {
 
     outbound_tx: tokio::sync::broadcast::Sender<CrossyMessage>,
     outbound_rx: tokio::sync::broadcast::Receiver<CrossyMessage>,
+
+    results: Arc<ResultsStore>,
+    ratings: Arc<RatingsStore>,
+    settings: ServerSettings,
 }
 
 pub struct ServerInner {
     game_id: crate::GameId,
     empty_ticks: u32,
     new_players: Vec<game::PlayerId>,
+    // Inputs for frames we havent simulated yet, already validated so they arent counted against the client twice
+    future_inputs: Vec<(RemoteInput, Instant)>,
     start: Instant,
     start_utc: DateTime<Utc>,
 
     clients: Vec<Client>,
+    bots: Vec<ServerBot>,
     next_socket_id: SocketId,
+    last_player_id: u8,
     pub ended: bool,
 
-    tracer : crossy_multi_core::telemetry::TelemetryTracer,
-    tracer_tmp_file : std::fs::File,
+    tracer : TelemetryTracer,
+    tracer_tmp_file : Option<std::fs::File>,
 
     timeline: Timeline,
     input_history : InputHistory,
+    roster: PlayerIdMap<PlayerRosterEntry>,
+    match_tracker: MatchTracker,
 }
 
 impl Server {
-    pub fn new(config : GameConfig, id: &crate::GameId) -> Self {
+    pub fn new(config : GameConfig, id: &crate::GameId, settings: ServerSettings, results: Arc<ResultsStore>, ratings: Arc<RatingsStore>) -> Self {
         let start = Instant::now();
         let start_utc = Utc::now();
         let (outbound_tx, outbound_rx) = tokio::sync::broadcast::channel(1024);
 
-        let tracer = crossy_multi_core::telemetry::TelemetryTracer::new(&format!("logs/{}.log", &id.0));
+        let tracer = TelemetryTracer::new(&settings.telemetry_dir, &id.0, settings.telemetry_rotation);
 
         // @TMP
-        let tracer_tmp_file = std::fs::OpenOptions::new().write(true).append(true).create(true).open(&format!("logs/TMP_{}.log", &id.0)).unwrap();
-
-        /*
-        tracer.push(crossy_multi_core::telemetry::TelemetryEvent {
-           player_id: crossy_multi_core::PlayerId(100),
-           event : crossy_multi_core::interop::TelemetryMessage::ClientReceiveEvent(crossy_multi_core::interop::Telemetry_ClientReceiveEvent{
-                client_send_frame_id: 100,
-                receive_frame_id: 100,
-           })
-        });
-        */
+        let tracer_tmp_file = std::fs::OpenOptions::new().append(true).create(true).open(settings.telemetry_dir.join(format!("TMP_{}.log", &id.0))).ok();
 
         Server {
             queued_messages: Mutex::new(Vec::new()),
             outbound_tx,
             outbound_rx,
+            results,
+            ratings,
+            settings,
             inner: Mutex::new(ServerInner {
                 game_id: id.clone(),
                 empty_ticks: 0,
                 clients: Vec::new(),
+                bots: Vec::new(),
+                last_player_id: 0,
                 new_players: Vec::new(),
+                future_inputs: Vec::new(),
 
                 start,
                 start_utc,
@@ -95,6 +317,8 @@ impl Server {
 
                 timeline: Timeline::from_seed(config, &id.0),
                 input_history: Default::default(),
+                roster: PlayerIdMap::new(),
+                match_tracker: Default::default(),
             }),
         }
     }
@@ -134,11 +358,48 @@ impl Server {
         }
     }
 
-    pub async fn join(&self) -> SocketId {
+    pub async fn join(&self, name: &str, client_id: Option<&str>) -> (SocketId, SessionToken) {
         let mut inner = self.inner.lock().await;
-        let new_socket = inner.add_client();
+        let name = sanitize_player_name(name);
+        let identity = client_id.and_then(|x| self.ratings.identity(&name, x));
+        let (new_socket, session_token) = inner.add_client(name, identity);
         println!("[{:?}] /join - player_id {:?}", inner.game_id, new_socket);
-        new_socket
+        (new_socket, session_token)
+    }
+
+    pub async fn rejoin(&self, session_token: &SessionToken) -> Option<RejoinInfo> {
+        let mut inner = self.inner.lock().await;
+
+        // Give the client a fresh socket id so any messages still in flight from the old socket
+        // (eg its ClientDrop) no longer map onto the player.
+        let socket_id = inner.next_socket_id;
+
+        let client = inner.clients.iter_mut().find(|x| x.session_token == *session_token)?;
+        let player_id = client.player_client.as_ref()?.id;
+
+        client.socket_id = socket_id;
+        client.disconnected_at = None;
+
+        inner.next_socket_id = SocketId(socket_id.0 + 1);
+        println!("[{:?}] /rejoin - player_id {:?} now on {:?}", inner.game_id, player_id, socket_id);
+
+        Some(RejoinInfo {
+            socket_id,
+            player_id,
+        })
+    }
+
+    // Connectionless transports present the session token with the socket id, so nobody can speak for another socket.
+    pub async fn check_session(&self, socket_id: SocketId, session_token: &SessionToken) -> bool {
+        let inner = self.inner.lock().await;
+        inner.get_client_by_addr(socket_id).map(|x| x.session_token == *session_token).unwrap_or(false)
+    }
+
+    // False once the socket has dropped, even while its player is held for a /rejoin
+    #[cfg(test)]
+    pub async fn is_connected(&self, socket_id: SocketId) -> bool {
+        let inner = self.inner.lock().await;
+        inner.get_client_by_addr(socket_id).map(|x| x.disconnected_at.is_none()).unwrap_or(false)
     }
 
     pub async fn time_since(&self) -> Duration {
@@ -152,6 +413,54 @@ impl Server {
         inner.timeline.top_state().frame_id
     }
 
+    pub async fn get_summary(&self) -> GameSummary {
+        let inner = self.inner.lock().await;
+        let rules_state = &inner.timeline.top_state().rules_state;
+
+        let phase = match &rules_state.fst {
+            CrossyRulesetFST::Lobby { .. } => GamePhase::Lobby,
+            CrossyRulesetFST::RoundWarmup(_) | CrossyRulesetFST::Round(_) | CrossyRulesetFST::RoundCooldown(_) => GamePhase::InRound,
+            CrossyRulesetFST::EndWinner(_) | CrossyRulesetFST::EndAllLeft(_) => GamePhase::GameOver,
+        };
+
+        GameSummary {
+            player_count: inner.roster.count_populated() as u32,
+            phase,
+            round_id: rules_state.fst.get_round_id(),
+            locked: rules_state.locked,
+            created_utc: inner.start_utc.to_rfc3339(),
+            average_rating: self.ratings.average_rating(inner.identities().iter().map(|(_, x)| x)),
+        }
+    }
+
+    // Ruleset phase name and player count, for /metrics
+    pub async fn get_phase_player_count(&self) -> (&'static str, u32) {
+        let inner = self.inner.lock().await;
+        let phase = phase_name(&inner.timeline.top_state().rules_state.fst);
+        (phase, inner.roster.count_populated() as u32)
+    }
+
+    pub async fn get_violations(&self) -> Vec<PlayerViolations> {
+        let inner = self.inner.lock().await;
+        inner.clients.iter().filter_map(|client| {
+            client.player_client.as_ref().map(|player_client| PlayerViolations {
+                player_id: player_client.id,
+                name: client.name.clone(),
+                counts: player_client.violations.clone(),
+            })
+        }).collect()
+    }
+
+    pub async fn is_full(&self) -> bool {
+        let inner = self.inner.lock().await;
+        inner.is_full()
+    }
+
+    pub async fn is_locked(&self) -> bool {
+        let inner = self.inner.lock().await;
+        inner.timeline.top_state().rules_state.locked
+    }
+
     pub async fn get_start_time_utc(&self) -> String {
         println!("/start_time_utc");
         let inner = self.inner.lock().await;
@@ -162,7 +471,7 @@ impl Server {
         &self,
         hello: &ClientHello,
         socket_id: SocketId,
-    ) -> Option<InitServerResponse> {
+    ) -> Result<InitServerResponse, &'static str> {
         let mut inner = self.inner.lock().await;
 
         println!(
@@ -173,29 +482,91 @@ impl Server {
             hello.check(1)
         );
 
-        let client_id = game::PlayerId(inner.clients.len() as u8);
-        inner.new_players.push(client_id);
+        let (client_id, player_name) = inner.start_playing(socket_id, false)?;
 
-        // Fails if socket_id not found
-        // In prod version dont crash here?
-        let mut client = inner
-            .get_client_mut_by_addr(socket_id)
-            .expect("client tried to /play without calling /join");
-        client.player_client = Some(PlayerClient {
-            id: client_id,
-            last_tick_us: 0,
-        });
-
-        Some(InitServerResponse {
+        Ok(InitServerResponse {
             server_version: SERVER_VERSION,
             //player_count: inner.timeline.player_count,
             // unused I think, clean up
             player_count: 0,
             seed: inner.timeline.map.get_seed(),
             player_id: client_id,
+            player_name,
+            roster: inner.roster.clone(),
         })
     }
 
+    // External bots skip /play, they get a player as soon as their socket connects.
+    pub async fn join_bot(&self, name: &str, client_id: Option<&str>) -> Result<(SocketId, game::PlayerId), &'static str> {
+        let mut inner = self.inner.lock().await;
+        if (inner.is_full()) {
+            return Err("game is full");
+        }
+
+        let name = sanitize_player_name(name);
+        let identity = client_id.and_then(|x| self.ratings.identity(&name, x));
+        let (socket_id, _) = inner.add_client(name, identity);
+        let (player_id, _) = inner.start_playing(socket_id, true)?;
+        println!("[{:?}] /bot - {:?} on {:?}", inner.game_id, player_id, socket_id);
+        Ok((socket_id, player_id))
+    }
+
+    pub async fn get_bot_view(&self, player_id: game::PlayerId) -> BotView {
+        let inner = self.inner.lock().await;
+        let top_state = inner.timeline.top_state();
+        let fst = &top_state.rules_state.fst;
+        let round_id = fst.get_round_id();
+        let map = &inner.timeline.map;
+
+        let to_view = |player_state: &crossy_multi_core::player::PlayerState| {
+            let roster_entry = inner.roster.get(player_state.id);
+            BotPlayerView {
+                state: player_state.to_public(round_id, top_state.time_us, map, fst),
+                name: roster_entry.map(|x| x.name.clone()).unwrap_or_default(),
+                is_bot: roster_entry.map(|x| x.is_bot).unwrap_or(false),
+                alive: fst.get_player_alive(player_state.id) == AliveState::Alive,
+                can_move: player_state.can_move(),
+            }
+        };
+
+        let players: Vec<BotPlayerView> = top_state.get_valid_player_states().iter().map(to_view).collect();
+        let you = top_state.get_player(player_id).map(to_view);
+
+        // Just what is around the bot, before it spawns whatever is on screen
+        let is_near = |y: i32| you.as_ref().map(|x| (x.state.y - y).abs() <= BOT_VIEW_ROWS).unwrap_or(true);
+        let mut rows = map.get_row_view(round_id, fst.get_screen_y());
+        rows.retain(|x| is_near(x.y));
+        let mut cars = map.get_cars(round_id, top_state.time_us);
+        cars.retain(|x| is_near(x.1));
+        let mut lillipads = map.get_lillipads(round_id, top_state.time_us);
+        lillipads.retain(|x| is_near(x.1));
+
+        BotView {
+            frame_id: top_state.frame_id,
+            time_us: top_state.time_us,
+            player_id,
+            phase: phase_name(fst),
+            round_id,
+            screen_y: fst.get_screen_y(),
+            you,
+            players,
+            rows,
+            cars,
+            lillipads,
+        }
+    }
+
+    // Bots join like any other player, so are limited by the same cap.
+    pub async fn add_bots(&self, count: u8, ai_config: &str) {
+        let mut inner = self.inner.lock().await;
+        for _ in 0..count {
+            if let Err(e) = inner.add_bot(ai_config) {
+                println!("[{:?}] Unable to add bot: {}", inner.game_id, e);
+                break;
+            }
+        }
+    }
+
     pub fn get_listener(&self) -> tokio::sync::broadcast::Receiver<CrossyMessage> {
         self.outbound_tx.subscribe()
     }
@@ -210,203 +581,253 @@ impl Server {
         inner.timeline.top_state().time_us
     }
 
+    // None for frames older than the history we keep, or newer than the top state.
+    pub async fn get_state(&self, frame_id: u32) -> Option<crossy_multi_core::game::GameState> {
+        let inner = self.inner.lock().await;
+        if (frame_id > inner.timeline.top_state().frame_id) {
+            return None;
+        }
+        inner.timeline.try_get_state(frame_id).cloned()
+    }
+
     pub async fn run(&self) {
         loop {
             let tick_start = Instant::now();
-            let mut new_players = Vec::new();
+            let current_time_us = self.time_since().await.as_micros() as u32;
 
-            {
-                let mut inner = self.inner.lock().await;
+            if (!self.step(current_time_us, tick_start).await) {
+                return;
+            }
 
-                // Fetch + clear list of new players
-                new_players = std::mem::take(&mut inner.new_players);
+            let now = Instant::now();
+            let elapsed_time = now.saturating_duration_since(tick_start);
+            metrics::TICK_DURATION_SECONDS.observe(elapsed_time.as_secs_f64());
+            if let Some(sleep_time) = self.settings.tick_time.checked_sub(elapsed_time) {
+                tokio::time::sleep(sleep_time).await;
+            }
+        }
+    }
 
-                // Do simulations
-                let current_time = inner.start.elapsed();
-                let current_time_us = current_time.as_micros() as u32;
+    // One iteration of the game loop, simulating up to current_time_us and broadcasting the result.
+    // Driven by run in real time, or directly by tests with a made up clock.
+    // Returns false once the game has shut down.
+    pub async fn step(&self, current_time_us: u32, tick_start: Instant) -> bool {
+        let mut new_players = Vec::new();
 
-                loop {
-                    let last_time = inner.timeline.top_state().time_us;
-                    let delta_time = current_time_us.saturating_sub(last_time);
-                    if (delta_time > TICK_INTERVAL_US)
-                    {
-                        inner.timeline.tick(None, TICK_INTERVAL_US);
-                    }
-                    else
+        {
+            let mut inner = self.inner.lock().await;
+
+            // Fetch + clear list of new players
+            new_players = std::mem::take(&mut inner.new_players);
+
+            // Do simulations
+            loop {
+                let last_time = inner.timeline.top_state().time_us;
+                let delta_time = current_time_us.saturating_sub(last_time);
+                if (delta_time > TICK_INTERVAL_US)
+                {
+                    inner.timeline.tick(None, TICK_INTERVAL_US);
+                }
+                else
+                {
+                    // @TMP DAN REMOVE MEE
+                    const DEBUG_LOG_ALL_STATES : bool = false;
+                    if (DEBUG_LOG_ALL_STATES)
                     {
-                        // @TMP DAN REMOVE MEE
-                        const DEBUG_LOG_ALL_STATES : bool = false;
-                        if (DEBUG_LOG_ALL_STATES)
-                        {
-                            let glah = inner.timeline.top_state().clone();
-                            writeln!(inner.tracer_tmp_file, "LOOP START \n {:#?}", glah).unwrap();
+                        let glah = inner.timeline.top_state().clone();
+                        if let Some(file) = inner.tracer_tmp_file.as_mut() {
+                            writeln!(file, "LOOP START \n {:#?}", glah).unwrap();
                         }
-
-                        break;
                     }
+
+                    break;
                 }
             }
+        }
 
-            let (client_updates, dropped_players) = self.receive_updates().await;
-            let mut inner = self.inner.lock().await;
+        let received_updates = self.receive_updates().await;
+        let mut inner = self.inner.lock().await;
 
-            let mut nonempty_updates = Vec::with_capacity(client_updates.len());
+        // Held back from earlier ticks, drop any from players that have since gone
+        let mut client_updates = std::mem::take(&mut inner.future_inputs);
+        client_updates.retain(|(update, _)| inner.get_socket_id_by_player(update.player_id).is_some());
+        client_updates.extend(received_updates);
 
-            let current_frame_id = inner.timeline.top_state().frame_id;
+        let dropped_players = inner.take_expired_disconnects(tick_start);
 
-            for (update, time) in client_updates.iter() {
+        let mut nonempty_updates = Vec::with_capacity(client_updates.len());
 
-                if (update.input == game::Input::None) {
-                    continue;
-                }
+        let current_frame_id = inner.timeline.top_state().frame_id;
 
-                if (update.frame_id > current_frame_id)
-                {
-                    println!("WARNING: Future input, can happen due to latency approximations. Sending back to queue");
-                    let socket_id = inner.get_socket_id_by_player(update.player_id).unwrap();
-                    self.queue_message(CrossyMessage::ClientTick(vec![
-                        crossy_multi_core::interop::ClientTick {
-                            time_us: update.time_us,
-                            frame_id: update.frame_id,
-                            input : update.input,
-                        }
-                    ]), socket_id).await;
-
-                    /*
-                    panic!("Got client update with frame id in the future!!\n\n frame_id {}\n top state {:?}\n\n update {:?}",
-                        update.frame_id,
-                        inner.timeline.top_state(),
-                        update);
-                        */
-                }
-                else
-                {
-                    nonempty_updates.push((update.clone(), time));
-                }
-            }
+        for (update, time) in client_updates.iter() {
 
-            for (update, receive_time) in &nonempty_updates {
-                let receive_time_us = receive_time
-                    .saturating_duration_since(inner.start)
-                    .as_micros() as u32;
-                let delta = (update.time_us as f32 - receive_time_us as f32) / 1000.;
-                //let delta = (update.time_us as i32 - inner.timeline.top_state().time_us as i32) / 1000;
-                println!(
-                    "[{:?}] Update - {:?} at client time {}ms, receive_time {}ms, delta {}ms",
-                    update.player_id,
-                    update.input,
-                    update.time_us / 1000,
-                    receive_time_us / 1000,
-                    delta.floor()
-                );
+            if (update.input == game::Input::None) {
+                continue;
             }
 
-            if (nonempty_updates.len() > 0) {
-                let propagate_result = inner.timeline.try_propagate_inputs(nonempty_updates.into_iter().map(|(x, _)| x).collect(), true);
-                assert!(propagate_result);
-            }
+            if (update.frame_id > current_frame_id)
+            {
+                if (inner.future_inputs.len() >= MAX_FUTURE_INPUTS) {
+                    println!("WARNING: Dropping future input, already holding {}", inner.future_inputs.len());
+                    continue;
+                }
 
-            for new_player in new_players.iter().cloned() {
-                // We need to make sure this gets propagated properly
-                // Weird edge case bugs
-                println!(
-                    "[{:?}] In run, adding a new player {:?}",
-                    inner.game_id, new_player
-                );
-                let spawn_pos = find_spawn_pos(inner.timeline.top_state());
-                println!(
-                    "[{:?}] Spawning new player at {:?}",
-                    inner.game_id, spawn_pos
-                );
-
-                inner.timeline.add_player(new_player, spawn_pos);
-            }
+                println!("WARNING: Future input, can happen due to latency approximations. Holding until next tick");
+                metrics::FUTURE_INPUT_REQUEUES.inc();
+                inner.future_inputs.push((update.clone(), *time));
 
-            for dropped_player in dropped_players {
-                println!("[{:?}] Dropping player {:?}", inner.game_id, dropped_player);
-                inner.timeline.remove_player(dropped_player);
+                /*
+                panic!("Got client update with frame id in the future!!\n\n frame_id {}\n top state {:?}\n\n update {:?}",
+                    update.frame_id,
+                    inner.timeline.top_state(),
+                    update);
+                    */
             }
-
-            // Generate last sent times
-            let mut last_client_sent = PlayerIdMap::new();
-            for client in (&inner.clients)
-                .iter()
-                .filter_map(|x| x.player_client.as_ref())
+            else
             {
-                inner
-                    .timeline
-                    .get_state_before_eq_us(client.last_tick_us)
-                    .map(|x| {
-                        last_client_sent.set(
-                            client.id,
-                            RemoteTickState {
-                                frame_id: x.frame_id,
-                                time_us: x.time_us,
-                                states: x.get_valid_player_states(),
-                            },
-                        );
-                    });
+                nonempty_updates.push((update.clone(), time));
             }
+        }
 
+        for (update, receive_time) in &nonempty_updates {
+            let receive_time_us = receive_time
+                .saturating_duration_since(inner.start)
+                .as_micros() as u32;
+            let delta = (update.time_us as f32 - receive_time_us as f32) / 1000.;
+            //let delta = (update.time_us as i32 - inner.timeline.top_state().time_us as i32) / 1000;
+            println!(
+                "[{:?}] Update - {:?} at client time {}ms, receive_time {}ms, delta {}ms",
+                update.player_id,
+                update.input,
+                update.time_us / 1000,
+                receive_time_us / 1000,
+                delta.floor()
+            );
+        }
 
-            // Send responses
-            let top_state = inner.timeline.top_state();
+        if (nonempty_updates.len() > 0) {
+            let propagate_result = inner.timeline.try_propagate_inputs(nonempty_updates.into_iter().map(|(x, _)| x).collect(), true);
+            assert!(propagate_result);
+        }
 
-            // FIXME: We currently take -100 frames, we could do something smarter with the min last send time of clients 
-            // Do we need to be smart?
-            let lkg_frame_id = inner.timeline.top_state().frame_id.saturating_sub(100);
-            let delta_inputs = inner.timeline.inputs_since_frame(lkg_frame_id);
+        // Bots look at the latest state and act on the current frame, so only the top frame gets resimulated
+        let bot_inputs = inner.think_bots();
+        if (!bot_inputs.is_empty()) {
+            let propagate_result = inner.timeline.try_propagate_inputs(bot_inputs, true);
+            assert!(propagate_result);
+        }
 
-            let lkg_state = inner.timeline.try_get_state(lkg_frame_id).unwrap();
+        for new_player in new_players.iter().cloned() {
+            // We need to make sure this gets propagated properly
+            // Weird edge case bugs
+            println!(
+                "[{:?}] In run, adding a new player {:?}",
+                inner.game_id, new_player
+            );
+            let spawn_pos = find_spawn_pos(inner.timeline.top_state());
+            println!(
+                "[{:?}] Spawning new player at {:?}",
+                inner.game_id, spawn_pos
+            );
+
+            inner.timeline.add_player(new_player, spawn_pos);
+        }
 
-            let mut last_client_frame_id = PlayerIdMap::new();
-            for (pid, state) in last_client_sent.iter() {
-                last_client_frame_id.set(pid, state.frame_id);
-            }
+        for dropped_player in dropped_players {
+            println!("[{:?}] Dropping player {:?}", inner.game_id, dropped_player);
+            inner.remove_player(dropped_player);
+        }
 
-            let linden_tick = CrossyMessage::LindenServerTick(LindenServerTick {
-                latest : RemoteTickState::from_gamestate(top_state),
-                lkg_state : lkg_state.clone(),
-                delta_inputs: delta_inputs.iter().cloned().collect(),
-                last_client_frame_id,
-                rules_state: top_state.get_rule_state().clone(),
-            });
+        // Generate last sent times
+        let mut last_client_sent = PlayerIdMap::new();
+        for client in (&inner.clients)
+            .iter()
+            .filter_map(|x| x.player_client.as_ref())
+        {
+            inner
+                .timeline
+                .get_state_before_eq_us(client.last_tick_us)
+                .map(|x| {
+                    last_client_sent.set(
+                        client.id,
+                        RemoteTickState {
+                            frame_id: x.frame_id,
+                            time_us: x.time_us,
+                            states: x.get_valid_player_states(),
+                        },
+                    );
+                });
+        }
 
-            self.outbound_tx.send(linden_tick).unwrap();
 
-            // Timeout logic for when there are no players
-            if (self.outbound_tx.receiver_count() <= 1) {
-                inner.empty_ticks += 1;
-            } else {
-                inner.empty_ticks = 0;
-            }
+        // Send responses
+        let top_state = inner.timeline.top_state();
 
-            inner.tracer.flush();
+        // FIXME: We currently take -100 frames, we could do something smarter with the min last send time of clients 
+        // Do we need to be smart?
+        let lkg_frame_id = inner.timeline.top_state().frame_id.saturating_sub(LKG_WINDOW_FRAMES);
+        let delta_inputs = inner.timeline.inputs_since_frame(lkg_frame_id);
 
-            const EMPTY_TICKS_THRESHOLD: u32 = 60 * 20;
-            if (inner.empty_ticks > EMPTY_TICKS_THRESHOLD) {
-                // Noone left listening, shut down
-                println!("[{:?}] Shutting down game", inner.game_id);
-                self.outbound_tx.send(CrossyMessage::GoodBye()).unwrap();
-                inner.ended = true;
-                return;
-            }
+        let lkg_state = inner.timeline.try_get_state(lkg_frame_id).unwrap();
 
-            let now = Instant::now();
-            let elapsed_time = now.saturating_duration_since(tick_start);
-            if let Some(sleep_time) = DESIRED_TICK_TIME.checked_sub(elapsed_time) {
-                tokio::time::sleep(sleep_time).await;
-            }
+        let mut last_client_frame_id = PlayerIdMap::new();
+        for (pid, state) in last_client_sent.iter() {
+            last_client_frame_id.set(pid, state.frame_id);
+        }
+
+        let linden_tick = CrossyMessage::LindenServerTick(LindenServerTick {
+            latest : RemoteTickState::from_gamestate(top_state),
+            lkg_state : lkg_state.clone(),
+            delta_inputs: delta_inputs.iter().cloned().collect(),
+            last_client_frame_id,
+            rules_state: top_state.get_rule_state().clone(),
+            roster: inner.roster.clone(),
+        });
+
+        self.outbound_tx.send(linden_tick).unwrap();
+
+        // Record finished matches, using the lkg state so we dont write out anything that gets resimulated
+        let (lkg_fst, lkg_time_us) = {
+            let lkg_state = inner.timeline.try_get_state(lkg_frame_id).unwrap();
+            (lkg_state.get_rule_state().fst.clone(), lkg_state.time_us)
+        };
+
+        if let Some(completed) = inner.match_tracker.observe(&lkg_fst, lkg_time_us) {
+            let result = completed.into_result(&inner.game_id.0, inner.timeline.map.get_seed(), &inner.roster, &inner.identities());
+            println!("[{:?}] Match finished, winner {:?}", inner.game_id, result.winner);
+            // Both write files which can stall, keep them off the game lock
+            let results = self.results.clone();
+            let ratings = self.ratings.clone();
+            tokio::task::spawn_blocking(move || {
+                ratings.record_match(&result);
+                results.push(result);
+            });
         }
+
+        // Timeout logic for when there are no players
+        if (self.outbound_tx.receiver_count() <= 1) {
+            inner.empty_ticks += 1;
+        } else {
+            inner.empty_ticks = 0;
+        }
+
+        inner.tracer.flush();
+
+        if (inner.empty_ticks > self.settings.empty_ticks_threshold) {
+            // Noone left listening, shut down
+            println!("[{:?}] Shutting down game", inner.game_id);
+            self.outbound_tx.send(CrossyMessage::GoodBye()).unwrap();
+            inner.ended = true;
+            return false;
+        }
+
+        true
     }
 
 
     async fn receive_updates(
         &self,
-    ) -> (
-        Vec<(RemoteInput, Instant)>,
-        Vec<game::PlayerId>,
-    ) {
+    ) -> Vec<(RemoteInput, Instant)> {
         let mut queued_messages = Vec::with_capacity(8);
 
         let mut guard = self.queued_messages.lock().await;
@@ -416,40 +837,100 @@ impl Server {
         let mut client_updates = Vec::new();
 
         let mut inner = self.inner.lock().await;
-        let mut dropped_players = vec![];
 
         while let Some((message, socket_id, receive_time)) = queued_messages.pop() {
             match message {
-                CrossyMessage::ClientTick(client_ticks) => match inner.get_client_mut_by_addr(socket_id) {
-                    Some(client) => {
-                        if let Some(player_client) = client.player_client.as_mut() {
-                            for t in client_ticks
-                            {
-                                let client_time = t.time_us;
-                                player_client.last_tick_us = player_client.last_tick_us.max(client_time);
-
-                                client_updates.push((
-                                    RemoteInput {
-                                        time_us: client_time,
-                                        frame_id: t.frame_id,
-                                        input: t.input,
-                                        player_id: player_client.id,
-                                    },
-                                    receive_time,
-                                ));
+                CrossyMessage::ClientTick(client_ticks) => {
+                    let top_frame_id = inner.timeline.top_state().frame_id;
+                    let top_time_us = inner.timeline.top_state().time_us;
+                    match inner.get_client_mut_by_addr(socket_id) {
+                        Some(client) => {
+                            if let Some(player_client) = client.player_client.as_mut() {
+                                for t in client_ticks
+                                {
+                                    if (!player_client.validate_tick(&t, top_frame_id, top_time_us)) {
+                                        continue;
+                                    }
+
+                                    let client_time = t.time_us;
+                                    player_client.last_tick_us = player_client.last_tick_us.max(client_time);
+
+                                    client_updates.push((
+                                        RemoteInput {
+                                            time_us: client_time,
+                                            frame_id: t.frame_id,
+                                            input: t.input,
+                                            player_id: player_client.id,
+                                        },
+                                        receive_time,
+                                    ));
+                                }
+                            } else {
+                                println!("Received client update from client who has not called /play");
                             }
-                        } else {
-                            println!("Received client update from client who has not called /play");
+                        }
+                        None => {
+                            println!("Did not recognise addr {:?}", &socket_id);
                         }
                     }
-                    None => {
-                        println!("Did not recognise addr {:?}", &socket_id);
-                    }
-                },
+                }
                 CrossyMessage::ClientDrop() => {
                     if let Some(client) = inner.get_client_mut_by_addr(socket_id) {
                         if let Some(player_client) = client.player_client.as_ref() {
-                            dropped_players.push(player_client.id);
+                            // Hold the player in the game, they get removed if they dont /rejoin in time.
+                            let player_id = player_client.id;
+                            println!("Socket {:?} dropped, holding player {:?} for reconnect", socket_id, player_id);
+                            client.disconnected_at = Some(receive_time);
+
+                            // Dont leave the lobby without a host for the whole grace period
+                            if (inner.timeline.top_state().rules_state.host == Some(player_id)) {
+                                if let Some(new_host) = inner.find_new_host(player_id) {
+                                    println!("[{:?}] Host {:?} dropped, new host {:?}", inner.game_id, player_id, new_host);
+                                    inner.timeline.modify_rules_state(|x| x.host = Some(new_host));
+                                }
+                            }
+                        }
+                    }
+                }
+                CrossyMessage::ClientChat(chat) => {
+                    let current_frame_id = inner.timeline.top_state().frame_id;
+                    let m_player_client = inner.get_client_mut_by_addr(socket_id).and_then(|x| x.player_client.as_mut());
+                    if let Some(player_client) = m_player_client {
+                        if (!player_client.chat_rate_limiter.try_request(receive_time)) {
+                            println!("Dropping chat from {:?}, rate limited", player_client.id);
+                            continue;
+                        }
+
+                        let content = match chat.content {
+                            ChatContent::Text(text) => {
+                                let sanitized = sanitize_chat_text(&text);
+                                if (sanitized.is_empty()) {
+                                    continue;
+                                }
+                                ChatContent::Text(sanitized)
+                            },
+                            emote => emote,
+                        };
+
+                        // Clients run slightly ahead of the server so clamp to a frame we know about.
+                        let _ = self.outbound_tx.send(CrossyMessage::ServerChat(ServerChat {
+                            player_id: player_client.id,
+                            frame_id: chat.frame_id.min(current_frame_id),
+                            content,
+                        }));
+                    }
+                }
+                CrossyMessage::HostCommand(command) => {
+                    let m_player_id = inner.get_client_by_addr(socket_id).and_then(|x| x.player_client.as_ref()).map(|x| x.id);
+                    if let Some(player_id) = m_player_id {
+                        match inner.apply_host_command(player_id, command) {
+                            Ok(Some(kicked)) => {
+                                let _ = self.outbound_tx.send(CrossyMessage::PlayerKicked(kicked));
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                println!("[{:?}] Rejected host command from {:?}: {}", inner.game_id, player_id, e);
+                            }
                         }
                     }
                 }
@@ -460,32 +941,273 @@ impl Server {
                         .unwrap();
                 }
                 CrossyMessage::TelemetryMessagePackage(telemetry_messages) => {
-                    let player_id = inner.get_client_by_addr(socket_id).unwrap().player_client.as_ref().unwrap().id;
-                    for message in &telemetry_messages.messages {
-                        inner.tracer.push(crossy_multi_core::telemetry::TelemetryEvent {
-                            player_id,
-                            event: message.clone(),
-                        });
+                    let m_player_id = inner.get_client_by_addr(socket_id).and_then(|x| x.player_client.as_ref()).map(|x| x.id);
+                    if let Some(player_id) = m_player_id {
+                        let server_frame_id = inner.timeline.top_state().frame_id;
+                        for message in telemetry_messages.messages {
+                            inner.tracer.push(TelemetryEvent {
+                                player_id,
+                                server_frame_id,
+                                event: message,
+                            });
+                        }
                     }
                 }
                 _ => {}
             }
         }
 
-        (client_updates, dropped_players)
+        client_updates
     }
 }
 
 impl ServerInner {
-    fn add_client(&mut self) -> SocketId {
+    fn add_client(&mut self, name: String, identity: Option<PlayerIdentity>) -> (SocketId, SessionToken) {
         let socket_id = self.next_socket_id;
         self.next_socket_id = SocketId(socket_id.0 + 1);
+
+        let session_token = SessionToken::generate();
         self.clients.push(Client {
             player_client: None,
             socket_id,
+            session_token: session_token.clone(),
+            name,
+            identity,
+            disconnected_at: None,
+        });
+
+        (socket_id, session_token)
+    }
+
+    // Gives the client a player, they spawn on the next tick.
+    fn start_playing(&mut self, socket_id: SocketId, is_bot: bool) -> Result<(game::PlayerId, String), &'static str> {
+        if (self.get_client_by_addr(socket_id).is_none()) {
+            println!("[{:?}] {:?} tried to /play without calling /join", self.game_id, socket_id);
+            return Err("not joined");
+        }
+
+        if (self.get_client_by_addr(socket_id).unwrap().player_client.is_some()) {
+            println!("[{:?}] {:?} called /play twice", self.game_id, socket_id);
+            return Err("already playing");
+        }
+
+        if (self.is_full()) {
+            println!("[{:?}] Refusing to add {:?}, game is full", self.game_id, socket_id);
+            return Err("game is full");
+        }
+
+        let client_id = self.allocate_player_id().ok_or("no free player ids")?;
+        self.new_players.push(client_id);
+
+        let client = self.get_client_mut_by_addr(socket_id).unwrap();
+        client.player_client = Some(PlayerClient::new(client_id));
+
+        let player_name = if client.name.is_empty() {
+            format!("Player {}", client_id.0 + 1)
+        }
+        else {
+            client.name.clone()
+        };
+
+        self.roster.set(client_id, PlayerRosterEntry {
+            name: player_name.clone(),
+            is_bot,
         });
 
-        socket_id
+        // The first human to /play becomes host rather than whoever called /new.
+        // /new is also how matchmaking and bot tooling open games, and the caller doesnt get a socket from it,
+        // so there is no player to tie the creator to. Bots cant be host.
+        if (!is_bot && self.timeline.top_state().rules_state.host.is_none()) {
+            println!("[{:?}] Setting host to {:?}", self.game_id, client_id);
+            self.timeline.modify_rules_state(|x| x.host = Some(client_id));
+        }
+
+        Ok((client_id, player_name))
+    }
+
+    // Held players waiting to /rejoin still count
+    fn is_full(&self) -> bool {
+        self.roster.count_populated() >= MAX_PLAYERS as usize
+    }
+
+    fn identities(&self) -> PlayerIdMap<PlayerIdentity> {
+        let mut identities = PlayerIdMap::new();
+        for client in &self.clients {
+            if let (Some(player_client), Some(identity)) = (&client.player_client, &client.identity) {
+                identities.set(player_client.id, identity.clone());
+            }
+        }
+
+        identities
+    }
+
+    // Fresh ids while we have them so clients dont mix up a new player with one that just left,
+    // then reuse whatever has been freed up. Only fails if every id is somehow still in use.
+    fn allocate_player_id(&mut self) -> Option<game::PlayerId> {
+        if let Some(next) = self.last_player_id.checked_add(1) {
+            self.last_player_id = next;
+            return Some(game::PlayerId(next));
+        }
+
+        (1..=u8::MAX).map(game::PlayerId).find(|id| {
+            self.roster.get(*id).is_none()
+                && !self.new_players.contains(id)
+                && !self.clients.iter().any(|x| x.player_client.as_ref().map(|p| p.id == *id).unwrap_or(false))
+        })
+    }
+
+    fn think_bots(&mut self) -> Vec<RemoteInput> {
+        let top_state = self.timeline.top_state();
+        let mut inputs = Vec::new();
+
+        for bot in &mut self.bots {
+            let input = bot.agent.think(top_state, &self.timeline.map);
+            if (input != game::Input::None) {
+                inputs.push(RemoteInput {
+                    time_us: top_state.time_us,
+                    frame_id: top_state.frame_id,
+                    input,
+                    player_id: bot.player_id,
+                });
+            }
+        }
+
+        inputs
+    }
+
+    fn add_bot(&mut self, ai_config: &str) -> Result<game::PlayerId, &'static str> {
+        if (self.is_full()) {
+            return Err("game is full");
+        }
+
+        let player_id = self.allocate_player_id().ok_or("no free player ids")?;
+        let agent = ai::create_agent(ai_config, player_id).map_err(|e| {
+            println!("[{:?}] Bad ai config {:?}: {}", self.game_id, ai_config, e);
+            "invalid ai config"
+        })?;
+
+        println!("[{:?}] Adding bot {:?} ({})", self.game_id, player_id, ai_config);
+
+        self.new_players.push(player_id);
+        self.roster.set(player_id, PlayerRosterEntry {
+            name: format!("Bot {}", self.bots.len() + 1),
+            is_bot: true,
+        });
+        self.bots.push(ServerBot {
+            player_id,
+            agent,
+        });
+
+        Ok(player_id)
+    }
+
+    fn remove_player(&mut self, player_id: game::PlayerId) {
+        self.timeline.remove_player(player_id);
+        self.roster.remove(player_id);
+        self.bots.retain(|x| x.player_id != player_id);
+
+        if (self.timeline.top_state().rules_state.host == Some(player_id)) {
+            let new_host = self.find_new_host(player_id);
+            println!("[{:?}] Host {:?} left, new host {:?}", self.game_id, player_id, new_host);
+            self.timeline.modify_rules_state(|x| x.host = new_host);
+        }
+    }
+
+    // Whoever has been here longest, preferring players who are still connected. Bots cant be host.
+    fn find_new_host(&self, leaving: game::PlayerId) -> Option<game::PlayerId> {
+        let candidates: Vec<game::PlayerId> = self.roster.iter()
+            .filter(|(id, x)| !x.is_bot && *id != leaving)
+            .map(|(id, _)| id)
+            .collect();
+
+        let connected = candidates.iter().copied().find(|id| {
+            self.clients.iter().any(|x| x.disconnected_at.is_none() && x.player_client.as_ref().map(|p| p.id == *id).unwrap_or(false))
+        });
+
+        connected.or_else(|| candidates.first().copied())
+    }
+
+    fn apply_host_command(&mut self, sender: game::PlayerId, command: HostCommand) -> Result<Option<PlayerKicked>, &'static str> {
+        let rules_state = &self.timeline.top_state().rules_state;
+        if (rules_state.host != Some(sender)) {
+            return Err("not the host");
+        }
+
+        if (!rules_state.fst.in_lobby()) {
+            return Err("can only change the game while in the lobby");
+        }
+
+        println!("[{:?}] Applying host command {:?}", self.game_id, command);
+
+        match command {
+            HostCommand::SetRequiredWinCount(required_win_count) => {
+                let mut config = rules_state.config;
+                config.required_win_count = required_win_count;
+                config.validate()?;
+                self.timeline.modify_rules_state(|x| x.config = config);
+            }
+            HostCommand::SetMinimumPlayers(minimum_players) => {
+                let mut config = rules_state.config;
+                config.minimum_players = minimum_players;
+                config.validate()?;
+                self.timeline.modify_rules_state(|x| x.config = config);
+            }
+            HostCommand::SetLocked(locked) => {
+                self.timeline.modify_rules_state(|x| x.locked = locked);
+            }
+            HostCommand::AddBot(ai_config) => {
+                self.add_bot(&ai_config)?;
+            }
+            HostCommand::TransferHost(new_host) => {
+                match self.roster.get(new_host) {
+                    Some(entry) if !entry.is_bot => {},
+                    Some(_) => return Err("bots cannot be host"),
+                    None => return Err("no such player"),
+                }
+                self.timeline.modify_rules_state(|x| x.host = Some(new_host));
+            }
+            HostCommand::Kick(kicked_id) => {
+                if (kicked_id == sender) {
+                    return Err("cannot kick yourself");
+                }
+
+                if (self.bots.iter().any(|x| x.player_id == kicked_id)) {
+                    // Clients see the bot disappear from the roster, no socket to tell
+                    self.remove_player(kicked_id);
+                    return Ok(None);
+                }
+
+                let socket_id = self.get_socket_id_by_player(kicked_id).ok_or("no such player")?;
+
+                // Forgetting the client stops them from using /play or /rejoin with the old socket
+                self.clients.retain(|x| x.socket_id != socket_id);
+                self.remove_player(kicked_id);
+
+                return Ok(Some(PlayerKicked {
+                    player_id: kicked_id,
+                    socket_id: socket_id.0,
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn take_expired_disconnects(&mut self, now: Instant) -> Vec<game::PlayerId> {
+        let mut expired = Vec::new();
+
+        for client in &mut self.clients {
+            if let Some(disconnected_at) = client.disconnected_at {
+                if (now.saturating_duration_since(disconnected_at) > RECONNECT_GRACE_PERIOD) {
+                    client.disconnected_at = None;
+                    if let Some(player_client) = client.player_client.take() {
+                        expired.push(player_client.id);
+                    }
+                }
+            }
+        }
+
+        expired
     }
 
     fn get_client_mut_by_addr(&mut self, id: SocketId) -> Option<&mut Client> {
@@ -520,6 +1242,17 @@ impl ServerInner {
     }
 }
 
+fn phase_name(fst: &CrossyRulesetFST) -> &'static str {
+    match fst {
+        CrossyRulesetFST::Lobby { .. } => "lobby",
+        CrossyRulesetFST::RoundWarmup(_) => "round_warmup",
+        CrossyRulesetFST::Round(_) => "round",
+        CrossyRulesetFST::RoundCooldown(_) => "round_cooldown",
+        CrossyRulesetFST::EndWinner(_) => "end_winner",
+        CrossyRulesetFST::EndAllLeft(_) => "end_all_left",
+    }
+}
+
 fn find_spawn_pos(game_state: &crossy_multi_core::game::GameState) -> crossy_multi_core::Pos {
     for x in 7..=13 {
         for y in 7..=13 {
@@ -559,4 +1292,77 @@ impl InputHistory {
 
         &self.sorted_inputs[index..]
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::Loopback;
+
+    #[tokio::test]
+    async fn play_is_refused_once_full() {
+        let mut loopback = Loopback::new("full", GameConfig::default(), 1);
+        for i in 0..MAX_PLAYERS {
+            loopback.connect(&format!("p{}", i)).await;
+        }
+
+        let server = &loopback.server;
+        let (socket_id, _) = server.join("late", None).await;
+        assert_eq!(server.play(&ClientHello::default(), socket_id).await.err(), Some("game is full"));
+        assert_eq!(server.join_bot("bot", None).await.err(), Some("game is full"));
+        assert_eq!(server.inner.lock().await.add_bot("normal").err(), Some("game is full"));
+        assert_eq!(server.inner.lock().await.roster.count_populated(), MAX_PLAYERS as usize);
+
+        assert_eq!(server.play(&ClientHello::default(), SocketId(1000)).await.err(), Some("not joined"));
+    }
+
+    #[tokio::test]
+    async fn player_ids_are_recycled_after_wrapping() {
+        let mut loopback = Loopback::new("recycle", GameConfig::default(), 1);
+        let a = loopback.connect("a").await;
+        let first_id = loopback.clients[a].player_id;
+
+        // As if this lobby has been running for a very long time
+        loopback.server.inner.lock().await.last_player_id = u8::MAX - 1;
+        let b = loopback.connect("b").await;
+        assert_eq!(loopback.clients[b].player_id, game::PlayerId(u8::MAX));
+
+        // Out of fresh ids, takes the lowest one nobody holds
+        let c = loopback.connect("c").await;
+        let c_id = loopback.clients[c].player_id;
+        assert!(c_id != first_id && c_id != game::PlayerId(u8::MAX));
+        assert_eq!(c_id, game::PlayerId(2));
+    }
+
+    #[tokio::test]
+    async fn play_once_and_kicked_players_stay_out() {
+        let mut loopback = Loopback::new("kick", GameConfig::default(), 1);
+        let host = loopback.connect("host").await;
+        let kicked = loopback.connect("kicked").await;
+        let host_id = loopback.clients[host].player_id;
+        let kicked_id = loopback.clients[kicked].player_id;
+        let kicked_socket = loopback.clients[kicked].socket_id;
+
+        let server = &loopback.server;
+        assert_eq!(server.play(&ClientHello::default(), kicked_socket).await.err(), Some("already playing"));
+        assert_eq!(server.inner.lock().await.roster.count_populated(), 2);
+
+        let kick = server.inner.lock().await.apply_host_command(host_id, HostCommand::Kick(kicked_id)).unwrap();
+        assert_eq!(kick.map(|x| x.socket_id), Some(kicked_socket.0));
+        assert_eq!(server.play(&ClientHello::default(), kicked_socket).await.err(), Some("not joined"));
+        assert_eq!(server.inner.lock().await.roster.count_populated(), 1);
+    }
+
+    #[tokio::test]
+    async fn bot_view_only_covers_nearby_rows() {
+        let mut loopback = Loopback::new("bot_view", GameConfig::default(), 1);
+        let a = loopback.connect("a").await;
+        loopback.step_n(5).await;
+
+        let view = loopback.server.get_bot_view(loopback.clients[a].player_id).await;
+        let y = view.you.as_ref().unwrap().state.y;
+        assert!(!view.rows.is_empty());
+        assert!(view.rows.iter().all(|x| (x.y - y).abs() <= BOT_VIEW_ROWS));
+        assert!(view.cars.iter().chain(view.lillipads.iter()).all(|x| (x.1 - y).abs() <= BOT_VIEW_ROWS));
+    }
+}
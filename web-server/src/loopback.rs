@@ -0,0 +1,461 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossy_multi_core::crossy_ruleset::{GameConfig, RulesState};
+use crossy_multi_core::interop::{ClientHello, ClientTick, CrossyMessage, LindenServerTick, MAX_CLIENT_TICKS_PER_MESSAGE};
+use crossy_multi_core::timeline::{Timeline, TICK_INTERVAL_US};
+use crossy_multi_core::{GameState, Input, PlayerId, PlayerInputs};
+use tokio::sync::broadcast;
+
+use crate::crossy_server::{Server, ServerSettings, SocketId};
+use crate::ratings::RatingsStore;
+use crate::results::ResultsStore;
+use crate::{GameDb, GameId};
+
+// In memory stand in for the websocket transport, so tests can drive a Server without warp.
+//
+// Everything runs off a made up clock that moves a tick per step, and messages take a fixed
+// number of ticks each way, so a run always plays out the same.
+// Clients do what the wasm client does with their own Timeline, running ahead of the server
+// by the one way latency and rolling back when the server tells them something different.
+
+pub struct LoopbackClient {
+    pub socket_id: SocketId,
+    pub player_id: PlayerId,
+    pub timeline: Timeline,
+    connected: bool,
+
+    listener: broadcast::Receiver<CrossyMessage>,
+    // Messages in flight with the tick they arrive on
+    to_server: VecDeque<(u32, CrossyMessage)>,
+    from_server: VecDeque<(u32, LindenServerTick)>,
+    // Server ticks the client couldnt apply yet
+    requeued: VecDeque<LindenServerTick>,
+
+    buffered_input: Input,
+    last_sent_frame_id: u32,
+}
+
+impl LoopbackClient {
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    // Pressed on the next frame the player can move, like a key press on the real client
+    pub fn press(&mut self, input: Input) {
+        self.buffered_input = input;
+    }
+
+    fn receive(&mut self, tick_id: u32, latency_ticks: u32) {
+        loop {
+            match self.listener.try_recv() {
+                Ok(CrossyMessage::LindenServerTick(linden_server_tick)) => {
+                    self.from_server.push_back((tick_id + latency_ticks, linden_server_tick));
+                }
+                Ok(_) => {}
+                Err(broadcast::error::TryRecvError::Lagged(x)) => {
+                    panic!("Loopback client {:?} lagged by {} messages", self.socket_id, x);
+                }
+                Err(_) => break,
+            }
+        }
+
+        while let Some((arrive_tick_id, _)) = self.from_server.front() {
+            if (*arrive_tick_id > tick_id) {
+                break;
+            }
+
+            let (_, linden_server_tick) = self.from_server.pop_front().unwrap();
+            self.requeued.push_back(linden_server_tick);
+        }
+
+        let mut still_queued = VecDeque::new();
+        while let Some(linden_server_tick) = self.requeued.pop_front() {
+            if (!self.try_process_linden_server_message(&linden_server_tick)) {
+                still_queued.push_back(linden_server_tick);
+            }
+        }
+        self.requeued = still_queued;
+    }
+
+    // Same as the wasm client, rebase on the server's lkg state if we disagree then apply everyone's inputs.
+    fn try_process_linden_server_message(&mut self, linden_server_tick: &LindenServerTick) -> bool {
+        if let Some(client_state_at_lkg_time) = self.timeline.try_get_state(linden_server_tick.lkg_state.frame_id) {
+            let mismatch_player_states = linden_server_tick.lkg_state.player_states != client_state_at_lkg_time.player_states;
+            let mismatch_rulestate = linden_server_tick.lkg_state.rules_state != client_state_at_lkg_time.rules_state;
+            if (mismatch_player_states || mismatch_rulestate) {
+                self.timeline = self.timeline.rebase(&linden_server_tick.lkg_state);
+            }
+        }
+
+        self.timeline.try_propagate_inputs(linden_server_tick.delta_inputs.clone(), false)
+    }
+
+    fn advance(&mut self, client_time_us: u32) {
+        while (self.timeline.top_state().time_us < client_time_us) {
+            let mut player_inputs = PlayerInputs::new();
+            let can_move = self.timeline.top_state().get_player(self.player_id).map(|x| x.can_move()).unwrap_or(false);
+            if (can_move && self.buffered_input != Input::None) {
+                player_inputs.set(self.player_id, self.buffered_input);
+                self.buffered_input = Input::None;
+            }
+
+            self.timeline.tick(Some(player_inputs), TICK_INTERVAL_US);
+        }
+    }
+
+    fn send_ticks(&mut self, arrive_tick_id: u32) {
+        let mut ticks = Vec::new();
+        while self.last_sent_frame_id <= self.timeline.top_state().frame_id {
+            if let Some(state) = self.timeline.try_get_state(self.last_sent_frame_id) {
+                ticks.push(ClientTick {
+                    time_us: state.time_us,
+                    frame_id: state.frame_id,
+                    input: state.player_inputs.get(self.player_id),
+                });
+            }
+
+            self.last_sent_frame_id += 1;
+        }
+
+        if (ticks.len() > MAX_CLIENT_TICKS_PER_MESSAGE) {
+            let excess = ticks.len() - MAX_CLIENT_TICKS_PER_MESSAGE;
+            ticks.drain(..excess);
+        }
+
+        if (!ticks.is_empty()) {
+            self.to_server.push_back((arrive_tick_id, CrossyMessage::ClientTick(ticks)));
+        }
+    }
+}
+
+// Fresh scratch directory per test, so results, ratings and telemetry dont land next to the real ones
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("crossy_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::create_dir_all(&dir);
+    dir
+}
+
+// A whole GameDb for tests that go through /new, /join and friends rather than a single Server
+pub fn test_db(name: &str) -> GameDb {
+    let dir = test_dir(name);
+    let config = crate::config::ServerConfig {
+        results_path: dir.join("results.jsonl").to_string_lossy().into_owned(),
+        ratings_path: dir.join("ratings.json").to_string_lossy().into_owned(),
+        ..Default::default()
+    };
+
+    let mut db = GameDb::new(&config);
+    db.server_settings.telemetry_dir = dir;
+    db
+}
+
+pub struct Loopback {
+    pub server: Arc<Server>,
+    pub clients: Vec<LoopbackClient>,
+
+    game_id: GameId,
+    config: GameConfig,
+    // One way, in ticks
+    latency_ticks: u32,
+    tick_id: u32,
+    start: Instant,
+    // Results, ratings and telemetry go here rather than next to the real ones
+    dir: PathBuf,
+}
+
+impl Loopback {
+    pub fn new(name: &str, config: GameConfig, latency_ticks: u32) -> Self {
+        let dir = test_dir(name);
+
+        let settings = ServerSettings {
+            telemetry_dir: dir.clone(),
+            ..Default::default()
+        };
+
+        let game_id = GameId(name.to_owned());
+        let results = Arc::new(ResultsStore::open(&dir.join("results.jsonl").to_string_lossy()));
+        let ratings = Arc::new(RatingsStore::open(&dir.join("ratings.json").to_string_lossy()));
+        let server = Arc::new(Server::new(config, &game_id, settings, results, ratings));
+
+        Self {
+            server,
+            clients: Vec::new(),
+            game_id,
+            config,
+            latency_ticks,
+            tick_id: 0,
+            start: Instant::now(),
+            dir,
+        }
+    }
+
+    pub fn time_us(&self) -> u32 {
+        self.tick_id * TICK_INTERVAL_US
+    }
+
+    // Joins and plays like the web client does through /join and /play, returns the client index.
+    pub async fn connect(&mut self, name: &str) -> usize {
+        let (socket_id, _) = self.server.join(name, None).await;
+        let listener = self.server.get_listener();
+        let init = self.server.play(&ClientHello::default(), socket_id).await.unwrap();
+
+        // The wasm client starts from nothing and finds out everything else from the server
+        let timeline = Timeline::from_server_parts(&self.game_id.0, 0, 0, Default::default(), RulesState::new(self.config));
+
+        self.clients.push(LoopbackClient {
+            socket_id,
+            player_id: init.player_id,
+            timeline,
+            connected: true,
+            listener,
+            to_server: Default::default(),
+            from_server: Default::default(),
+            requeued: Default::default(),
+            buffered_input: Input::None,
+            last_sent_frame_id: 0,
+        });
+
+        self.clients.len() - 1
+    }
+
+    // The socket closing, the server hears about it after everything already in flight.
+    pub fn disconnect(&mut self, client_index: usize) {
+        let arrive_tick_id = self.tick_id + self.latency_ticks;
+        let client = &mut self.clients[client_index];
+        client.connected = false;
+        client.to_server.push_back((arrive_tick_id, CrossyMessage::ClientDrop()));
+    }
+
+    pub async fn step(&mut self) {
+        self.tick_id += 1;
+        let tick_id = self.tick_id;
+        let time_us = self.time_us();
+
+        for client in &mut self.clients {
+            while let Some((arrive_tick_id, _)) = client.to_server.front() {
+                if (*arrive_tick_id > tick_id) {
+                    break;
+                }
+
+                let (_, message) = client.to_server.pop_front().unwrap();
+                self.server.queue_message(message, client.socket_id).await;
+            }
+        }
+
+        let tick_start = self.start + Duration::from_micros(time_us as u64);
+        assert!(self.server.step(time_us, tick_start).await, "Loopback server shut down");
+
+        let client_time_us = time_us + self.latency_ticks * TICK_INTERVAL_US;
+        let latency_ticks = self.latency_ticks;
+        for client in self.clients.iter_mut().filter(|x| x.connected) {
+            client.receive(tick_id, latency_ticks);
+            client.advance(client_time_us);
+            client.send_ticks(tick_id + latency_ticks);
+        }
+    }
+
+    pub async fn step_n(&mut self, count: u32) {
+        for _ in 0..count {
+            self.step().await;
+        }
+    }
+
+    pub async fn server_top_state(&self) -> GameState {
+        let frame_id = self.server.frame_id().await;
+        self.server.get_state(frame_id).await.unwrap()
+    }
+
+    // Compares every connected client with the server on the server's top frame.
+    // Only expected to pass once inputs have had time to make it everywhere.
+    pub async fn check_converged(&self) -> Result<(), String> {
+        let server_state = self.server_top_state().await;
+        for client in self.clients.iter().filter(|x| x.connected) {
+            let client_state = client.timeline.try_get_state(server_state.frame_id)
+                .ok_or_else(|| format!("{:?} has no state for frame {}", client.player_id, server_state.frame_id))?;
+
+            if (client_state.player_states != server_state.player_states) {
+                return Err(format!("{:?} player states differ on frame {}\nclient {:#?}\nserver {:#?}",
+                    client.player_id, server_state.frame_id, client_state.player_states, server_state.player_states));
+            }
+
+            if (client_state.rules_state != server_state.rules_state) {
+                return Err(format!("{:?} rules differ on frame {}\nclient {:#?}\nserver {:#?}",
+                    client.player_id, server_state.frame_id, client_state.rules_state, server_state.rules_state));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Long enough for anything in flight to reach everyone and settle past the lkg window
+    const SETTLE_TICKS: u32 = 200;
+
+    fn config() -> GameConfig {
+        GameConfig {
+            bypass_lobby: true,
+            ..Default::default()
+        }
+    }
+
+    async fn settle(loopback: &mut Loopback) {
+        loopback.step_n(SETTLE_TICKS).await;
+        if let Err(e) = loopback.check_converged().await {
+            panic!("{}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_converge_after_joining() {
+        let mut loopback = Loopback::new("join", config(), 3);
+        loopback.connect("a").await;
+        loopback.connect("b").await;
+        settle(&mut loopback).await;
+
+        let server_state = loopback.server_top_state().await;
+        assert_eq!(server_state.get_valid_player_states().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn clients_converge_after_inputs() {
+        let mut loopback = Loopback::new("inputs", config(), 3);
+        let a = loopback.connect("a").await;
+        let b = loopback.connect("b").await;
+        settle(&mut loopback).await;
+
+        let a_start = loopback.server_top_state().await.get_player(loopback.clients[a].player_id).unwrap().pos;
+        for i in 0..20 {
+            loopback.clients[a].press(Input::Left);
+            loopback.clients[b].press(if (i % 2 == 0) { Input::Up } else { Input::Right });
+            loopback.step_n(15).await;
+        }
+        settle(&mut loopback).await;
+
+        // Make sure the inputs actually did something rather than everyone agreeing on nothing
+        let a_end = loopback.server_top_state().await.get_player(loopback.clients[a].player_id).unwrap().pos;
+        assert_ne!(a_start, a_end);
+    }
+
+    #[tokio::test]
+    async fn clients_converge_with_late_joiner() {
+        let mut loopback = Loopback::new("late_join", config(), 5);
+        let a = loopback.connect("a").await;
+        loopback.connect("b").await;
+        settle(&mut loopback).await;
+
+        loopback.clients[a].press(Input::Up);
+        loopback.step_n(10).await;
+        loopback.connect("c").await;
+        loopback.clients[a].press(Input::Up);
+        settle(&mut loopback).await;
+
+        assert_eq!(loopback.server_top_state().await.get_valid_player_states().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn clients_converge_after_drop() {
+        let mut loopback = Loopback::new("drop", config(), 3);
+        loopback.connect("a").await;
+        loopback.connect("b").await;
+        let c = loopback.connect("c").await;
+        settle(&mut loopback).await;
+
+        let dropped_id = loopback.clients[c].player_id;
+        loopback.disconnect(c);
+
+        // Held for a while in case they reconnect, then removed
+        let grace_ticks = (crate::crossy_server::RECONNECT_GRACE_PERIOD.as_micros() as u32 / TICK_INTERVAL_US) + 10;
+        loopback.step_n(grace_ticks).await;
+        settle(&mut loopback).await;
+
+        assert!(loopback.server_top_state().await.get_player(dropped_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn host_moves_when_host_drops() {
+        let mut loopback = Loopback::new("host_drop", config(), 3);
+        let a = loopback.connect("a").await;
+        let b = loopback.connect("b").await;
+        settle(&mut loopback).await;
+
+        let (host_id, other_id) = (loopback.clients[a].player_id, loopback.clients[b].player_id);
+        assert_eq!(loopback.server_top_state().await.rules_state.host, Some(host_id));
+
+        // Handed over straight away rather than after the grace period
+        loopback.disconnect(a);
+        loopback.step_n(10).await;
+        assert_eq!(loopback.server_top_state().await.rules_state.host, Some(other_id));
+        settle(&mut loopback).await;
+    }
+
+    #[tokio::test]
+    async fn future_inputs_count_once() {
+        let mut loopback = Loopback::new("future_inputs", config(), 3);
+        let a = loopback.connect("a").await;
+        settle(&mut loopback).await;
+
+        // A client running ahead of the server, with inputs for frames the server hasnt reached yet.
+        // They wait a few ticks each but should only count against the rate limit once.
+        let top = loopback.server_top_state().await;
+        let ticks: Vec<ClientTick> = (1..=15).map(|i| ClientTick {
+            frame_id: top.frame_id + i * 2,
+            time_us: top.time_us + i * 2 * TICK_INTERVAL_US,
+            input: if (i.is_multiple_of(2)) { Input::Left } else { Input::Right },
+        }).collect();
+        loopback.server.queue_message(CrossyMessage::ClientTick(ticks), loopback.clients[a].socket_id).await;
+        loopback.step_n(40).await;
+
+        let violations = loopback.server.get_violations().await;
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].counts.too_many_inputs, 0);
+    }
+
+    #[tokio::test]
+    async fn far_future_inputs_are_refused() {
+        let mut loopback = Loopback::new("far_future_inputs", config(), 3);
+        let a = loopback.connect("a").await;
+        settle(&mut loopback).await;
+
+        // Spreading claimed frames out used to keep each input in its own rate window
+        let top = loopback.server_top_state().await;
+        let ticks: Vec<ClientTick> = (1..=25).map(|i| ClientTick {
+            frame_id: top.frame_id + i * 1000,
+            time_us: top.time_us + i * 1000 * TICK_INTERVAL_US,
+            input: Input::Left,
+        }).collect();
+        loopback.server.queue_message(CrossyMessage::ClientTick(ticks), loopback.clients[a].socket_id).await;
+        loopback.step_n(2).await;
+
+        let violations = loopback.server.get_violations().await;
+        assert_eq!(violations[0].counts.future_frame, 25);
+        assert_eq!(violations[0].counts.too_many_inputs, 0);
+
+        // Within the slack, but still more moves than the window allows
+        let top = loopback.server_top_state().await;
+        let ticks: Vec<ClientTick> = (1..=25).map(|i| ClientTick {
+            frame_id: top.frame_id + i,
+            time_us: top.time_us + i * TICK_INTERVAL_US,
+            input: Input::Left,
+        }).collect();
+        loopback.server.queue_message(CrossyMessage::ClientTick(ticks), loopback.clients[a].socket_id).await;
+        loopback.step_n(2).await;
+
+        let violations = loopback.server.get_violations().await;
+        assert_eq!(violations[0].counts.future_frame, 25);
+        assert_eq!(violations[0].counts.too_many_inputs, 5);
+    }
+}
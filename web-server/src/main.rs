@@ -18,8 +18,19 @@ use serde::{Serialize, Deserialize};
 use tokio::sync::Mutex;
 use futures::{SinkExt, StreamExt};
 
+mod bot_protocol;
+mod config;
 mod crossy_server;
 mod gameid_generator;
+#[cfg(test)]
+mod loopback;
+mod matchmaking;
+mod metrics;
+mod net_sim;
+mod rate_limit;
+mod ratings;
+mod results;
+mod udp;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub struct GameId(String);
@@ -28,42 +39,81 @@ pub struct GameId(String);
 struct GameDbInner {
     id: GameId,
     game : Arc<crossy_server::Server>,
+    // Public games are listed on /games
+    public : bool,
 }
 
+// Each game is its own task ticking at 60hz, dont let anyone spin up an unbounded number.
+const MAX_CONCURRENT_GAMES : usize = 256;
+
+const NEW_GAME_RATE_LIMIT_COUNT : usize = 5;
+const NEW_GAME_RATE_LIMIT_WINDOW : std::time::Duration = std::time::Duration::from_secs(60);
+const JOIN_RATE_LIMIT_COUNT : usize = 20;
+const JOIN_RATE_LIMIT_WINDOW : std::time::Duration = std::time::Duration::from_secs(60);
+
+const DEFAULT_BOT_AI : &str = "normal";
+
 #[derive(Clone)]
 struct GameDb {
     games : Arc<Mutex<Vec<GameDbInner>>>,
     gameid_generator : Arc<Mutex<gameid_generator::GameIdGenerator>>,
+    matchmaking_queue : Arc<std::sync::Mutex<matchmaking::MatchmakingQueue>>,
+    results : Arc<results::ResultsStore>,
+    ratings : Arc<ratings::RatingsStore>,
+    new_game_limiter : Arc<std::sync::Mutex<rate_limit::IpRateLimiter>>,
+    join_limiter : Arc<std::sync::Mutex<rate_limit::IpRateLimiter>>,
+    server_settings : crossy_server::ServerSettings,
+    // Applied to every /ws connection, and whether they can pick their own with ?net_sim=
+    net_sim : crossy_multi_core::net_sim::NetworkConditions,
+    allow_net_sim_query : bool,
+    // Handed to clients on /join so native ones know where to send datagrams
+    udp_port : Option<u16>,
 }
 
 impl GameDb {
-    fn new() -> Self {
+    fn new(config : &config::ServerConfig) -> Self {
         GameDb {
             games: Arc::new(Mutex::new(Vec::new())),
             gameid_generator: Arc::new(Mutex::new(gameid_generator::GameIdGenerator::new())),
+            matchmaking_queue: Default::default(),
+            results: Arc::new(results::ResultsStore::open(&config.results_path)),
+            ratings: Arc::new(ratings::RatingsStore::open(&config.ratings_path)),
+            new_game_limiter: Arc::new(std::sync::Mutex::new(rate_limit::IpRateLimiter::new(NEW_GAME_RATE_LIMIT_COUNT, NEW_GAME_RATE_LIMIT_WINDOW))),
+            join_limiter: Arc::new(std::sync::Mutex::new(rate_limit::IpRateLimiter::new(JOIN_RATE_LIMIT_COUNT, JOIN_RATE_LIMIT_WINDOW))),
+            server_settings: config.server_settings(),
+            net_sim: config.net_sim,
+            allow_net_sim_query: config.allow_net_sim_query,
+            udp_port: config.udp_port,
         }
     }
 
-    async fn new_game(&self, config : GameConfig) -> GameId {
+    // Returns None when we are already running as many games as we allow
+    async fn new_game(&self, config : GameConfig, public : bool) -> Option<GameId> {
         let mut games = self.games.lock().await;
 
+        if (games.len() >= MAX_CONCURRENT_GAMES) {
+            println!("Refusing to create game, already running {}", games.len());
+            return None;
+        }
+
         let id = {
             let mut idgen_lock = self.gameid_generator.lock().await;
             idgen_lock.next()
         };
 
-        let game = Arc::new(crossy_server::Server::new(config, &id));
+        let game = Arc::new(crossy_server::Server::new(config, &id, self.server_settings.clone(), self.results.clone(), self.ratings.clone()));
 
         games.push(GameDbInner {
             id: id.clone(),
             game : game.clone(),
+            public,
         });
 
         tokio::task::spawn(async move {
             game.run().await;
         });
 
-        id
+        Some(id)
     }
 
     async fn cleanup(&self)
@@ -84,6 +134,54 @@ impl GameDb {
         }
 
         *games_inner = games_swap;
+        drop(games_inner);
+
+        let now = std::time::Instant::now();
+        self.new_game_limiter.lock().unwrap().prune(now);
+        self.join_limiter.lock().unwrap().prune(now);
+        self.matchmaking_queue.lock().unwrap().prune(now);
+    }
+
+    async fn list_public(&self) -> Vec<GameListing> {
+        let games = self.games.lock().await;
+        let mut listings = Vec::new();
+
+        for game in games.iter().filter(|x| x.public) {
+            if (game.game.inner.lock().await.ended) {
+                continue;
+            }
+
+            listings.push(GameListing {
+                game_id : game.id.clone(),
+                summary : game.game.get_summary().await,
+            });
+        }
+
+        listings
+    }
+
+    async fn gather_metrics(&self) -> metrics::GameMetrics {
+        let games = self.games.lock().await;
+        let mut players_per_phase: Vec<(&'static str, u32)> = Vec::new();
+        let mut active_games = 0;
+
+        for game in games.iter() {
+            if (game.game.inner.lock().await.ended) {
+                continue;
+            }
+
+            active_games += 1;
+            let (phase, player_count) = game.game.get_phase_player_count().await;
+            match players_per_phase.iter_mut().find(|(x, _)| *x == phase) {
+                Some((_, count)) => *count += player_count,
+                None => players_per_phase.push((phase, player_count)),
+            }
+        }
+
+        metrics::GameMetrics {
+            active_games,
+            players_per_phase,
+        }
     }
 
     async fn get(&self, game_id : GameId) -> Result<GameDbInner, Rejection> {
@@ -100,19 +198,31 @@ impl GameDb {
     }
 }
 
-const SERVE_DIR_DEV : &'static str = "C:\\Users\\Dan\\crossy_multi\\web-client\\dist";
+fn main() {
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    let config = match config::ServerConfig::from_args(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
-async fn main() {
-    let games = GameDb::new();
+    println!("Config {:#?}", config);
 
-    let serve_dir = if let Some(arg) = std::env::args().nth(1) {
-        String::from(arg)
-    }
-    else {
-        String::from(SERVE_DIR_DEV)
-    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(run(config));
+}
 
+async fn run(config : config::ServerConfig) {
+    let games = GameDb::new(&config);
+
+    let serve_dir = config.serve_dir.clone();
     println!("Serving from {}", &serve_dir);
 
     crossy_multi_core::set_debug_logger(Box::new(crossy_multi_core::StdoutLogger()));
@@ -133,16 +243,74 @@ async fn main() {
         .and(warp::get())
         .and(warp::query::<NewGameOptions>())
         .and(with_db(games.clone()))
-        .and(warp::any())
+        .and(warp::addr::remote())
         .and_then(new_game_handler).boxed();
 
-    // GET /join?game_id=1&name=dan
+    // GET /games
+    let get_games = warp::path!("games")
+        .and(warp::get())
+        .and(with_db(games.clone()))
+        .and_then(games_handler).boxed();
+
+    // GET /metrics
+    let get_metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_db(games.clone()))
+        .and_then(metrics_handler).boxed();
+
+    // GET /results?limit=20
+    let get_results = warp::path!("results")
+        .and(warp::get())
+        .and(warp::query::<ResultsOptions>())
+        .and(with_db(games.clone()))
+        .and_then(results_handler).boxed();
+
+    // GET /leaderboard?limit=20
+    let get_leaderboard = warp::path!("leaderboard")
+        .and(warp::get())
+        .and(warp::query::<LeaderboardOptions>())
+        .and(with_db(games.clone()))
+        .and_then(leaderboard_handler).boxed();
+
+    // GET /moderation?game_id=1
+    let get_moderation = warp::path!("moderation")
+        .and(warp::get())
+        .and(warp::query::<ModerationOptions>())
+        .and(with_db(games.clone()))
+        .and_then(moderation_handler).boxed();
+
+    // GET /join?game_id=1&name=dan&client_id=abc
     let get_join = warp::path!("join")
         .and(warp::get())
         .and(warp::query::<JoinOptions>())
         .and(with_db(games.clone()))
+        .and(warp::addr::remote())
         .and_then(join_handler).boxed();
 
+    // WS /bot?game_id=1&name=mybot, see bot_protocol.rs
+    let bot_websocket = warp::path!("bot")
+        .and(warp::ws())
+        .and(warp::query::<JoinOptions>())
+        .and(with_db(games.clone()))
+        .and(warp::addr::remote())
+        .and_then(bot_ws_handler).boxed();
+
+    // GET /quickplay?name=dan
+    let get_quickplay = warp::path!("quickplay")
+        .and(warp::get())
+        .and(warp::query::<QuickPlayOptions>())
+        .and(with_db(games.clone()))
+        .and(warp::addr::remote())
+        .and_then(quickplay_handler).boxed();
+
+    // GET /rejoin?game_id=1&session_token=abc
+    let get_rejoin = warp::path!("rejoin")
+        .and(warp::get())
+        .and(warp::query::<RejoinOptions>())
+        .and(with_db(games.clone()))
+        .and(warp::addr::remote())
+        .and_then(rejoin_handler).boxed();
+
     // GET /play?game_id=1&socket_id=1
     let get_play = warp::path!("play")
         .and(warp::get())
@@ -158,18 +326,56 @@ async fn main() {
         .and_then(ws_handler).boxed();
    
     let routes = get_new
+        .or(get_games)
+        .or(get_metrics)
+        .or(get_results)
+        .or(get_leaderboard)
+        .or(get_moderation)
         .or(get_join)
+        .or(get_quickplay)
+        .or(get_rejoin)
         .or(get_play)
         .or(site)
         .or(websocket)
+        .or(bot_websocket)
         .boxed();
 
-    let serve_from = ([0, 0, 0, 0], 8080);
+    let serve_from = std::net::SocketAddr::new(config.bind, config.port);
     println!("Serving from {:?}", serve_from);
 
-    warp::serve(routes)
-        .run(serve_from)
-        .await;
+    if let Some(udp_port) = config.udp_port {
+        let udp_addr = std::net::SocketAddr::new(config.bind, udp_port);
+        match tokio::net::UdpSocket::bind(udp_addr).await {
+            Ok(socket) => {
+                println!("UDP transport on {:?}", udp_addr);
+                tokio::task::spawn(udp::serve(socket, games.clone()));
+            }
+            Err(e) => {
+                eprintln!("Unable to bind UDP transport on {:?}: {}", udp_addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if (!config.net_sim.is_perfect() || config.allow_net_sim_query) {
+        println!("WARNING simulating bad networks, net_sim {:?} allow_net_sim_query {}", config.net_sim, config.allow_net_sim_query);
+    }
+
+    match &config.tls {
+        Some(tls) => {
+            warp::serve(routes)
+                .tls()
+                .cert_path(&tls.cert_path)
+                .key_path(&tls.key_path)
+                .run(serve_from)
+                .await;
+        }
+        None => {
+            warp::serve(routes)
+                .run(serve_from)
+                .await;
+        }
+    }
 }
 
 fn with_db(db: GameDb) -> impl Filter<Extract = (GameDb,), Error = std::convert::Infallible> + Clone {
@@ -179,6 +385,11 @@ fn with_db(db: GameDb) -> impl Filter<Extract = (GameDb,), Error = std::convert:
 #[derive(Debug, Clone, Deserialize)]
 struct NewGameOptions {
     debug_bypass_lobby : Option<bool>,
+    public : Option<bool>,
+    // Server controlled players so you can start a match on your own
+    bots : Option<u8>,
+    // See ai::parse_agent, eg "novice" or "expert:aggression=1"
+    bot_ai : Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -187,7 +398,22 @@ pub struct NewGameResponse
     pub game_id : GameId,
 }
 
-async fn new_game_handler(options : NewGameOptions, db: GameDb) -> Result<Response, std::convert::Infallible>  {
+fn rejected(reason : &'static str, status : warp::http::StatusCode) -> Response {
+    reply::with_status(reply::json(&RejectedResponse { reason }), status).into_response()
+}
+
+async fn new_game_handler(options : NewGameOptions, db: GameDb, addr : Option<std::net::SocketAddr>) -> Result<Response, std::convert::Infallible>  {
+    if (!db.new_game_limiter.lock().unwrap().try_request(addr, std::time::Instant::now())) {
+        println!("Rate limiting /new from {:?}", addr);
+        return Ok(rejected("rate_limited", warp::http::StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    let bot_ai = options.bot_ai.as_deref().unwrap_or(DEFAULT_BOT_AI).to_lowercase();
+    if let Err(e) = crossy_multi_core::ai::parse_agent(&bot_ai) {
+        println!("Rejecting /new with bot_ai {:?}: {}", bot_ai, e);
+        return Ok(rejected("invalid_ai", warp::http::StatusCode::BAD_REQUEST));
+    }
+
     let mut config = GameConfig::default();
 
     if let Some(true) = options.debug_bypass_lobby {
@@ -195,40 +421,224 @@ async fn new_game_handler(options : NewGameOptions, db: GameDb) -> Result<Respon
         config.minimum_players = 1;
     }
 
-    let game_id = db.new_game(config).await;
+    let public = options.public.unwrap_or(false);
+    let game_id = match db.new_game(config, public).await {
+        Some(x) => x,
+        None => {
+            return Ok(rejected("too_many_games", warp::http::StatusCode::SERVICE_UNAVAILABLE));
+        }
+    };
+
+    if let Some(bots) = options.bots.filter(|x| *x > 0) {
+        if let Ok(dbinner) = db.get(game_id.clone()).await {
+            dbinner.game.add_bots(bots, &bot_ai).await;
+        }
+    }
+
+    if (public) {
+        matchmaking::offer_lobby(&db, &game_id).await;
+    }
+
     let new_game_response = NewGameResponse { game_id };
     let response = warp::reply::json(&new_game_response).into_response();
     println!("/new {:?}", &response);
     Ok(response)
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct GameListing {
+    pub game_id : GameId,
+    #[serde(flatten)]
+    pub summary : crossy_server::GameSummary,
+}
+
+async fn games_handler(db: GameDb) -> Result<Response, std::convert::Infallible>  {
+    let listings = db.list_public().await;
+    Ok(reply::json(&listings).into_response())
+}
+
+async fn metrics_handler(db: GameDb) -> Result<Response, std::convert::Infallible>  {
+    let game_metrics = db.gather_metrics().await;
+    let body = metrics::render(&game_metrics);
+    Ok(reply::with_header(body, "content-type", "text/plain; version=0.0.4").into_response())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ResultsOptions {
+    limit : Option<usize>,
+}
+
+async fn results_handler(options : ResultsOptions, db: GameDb) -> Result<Response, std::convert::Infallible>  {
+    const DEFAULT_RESULTS_LIMIT : usize = 20;
+    let results = db.results.recent(options.limit.unwrap_or(DEFAULT_RESULTS_LIMIT));
+    Ok(reply::json(&results).into_response())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LeaderboardOptions {
+    limit : Option<usize>,
+}
+
+async fn leaderboard_handler(options : LeaderboardOptions, db: GameDb) -> Result<Response, std::convert::Infallible>  {
+    const DEFAULT_LEADERBOARD_LIMIT : usize = 50;
+    let leaderboard = db.ratings.leaderboard(options.limit.unwrap_or(DEFAULT_LEADERBOARD_LIMIT));
+    Ok(reply::json(&leaderboard).into_response())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ModerationOptions {
+    game_id : GameId,
+}
+
+// Per player counts of inputs the server rejected or flagged
+async fn moderation_handler(options : ModerationOptions, db: GameDb) -> Result<Response, Rejection>  {
+    let dbinner = db.get(options.game_id).await?;
+    let violations = dbinner.game.get_violations().await;
+    Ok(reply::json(&violations).into_response())
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct JoinOptions {
     pub game_id : GameId, 
     pub name : String, 
+    // Generated and stored by the client, used to track ratings
+    pub client_id : Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
 struct JoinResponse {
     pub socket_id : crossy_server::SocketId,
+    pub session_token : crossy_server::SessionToken,
     pub server_description : interop::ServerDescription,
     pub server_time_us : u32,
     pub server_frame_id : u32,
+    // Set when native clients can talk to this server over UDP, see udp.rs
+    pub udp_port : Option<u16>,
 }
 
-async fn join_handler(options : JoinOptions, db: GameDb) -> Result<Response, Rejection>  {
+#[derive(Debug, Clone, Serialize)]
+struct RejectedResponse {
+    pub reason : &'static str,
+}
+
+async fn join_handler(options : JoinOptions, db: GameDb, addr : Option<std::net::SocketAddr>) -> Result<Response, Rejection>  {
     println!("Join with options {options:?}");
+
+    if (!db.join_limiter.lock().unwrap().try_request(addr, std::time::Instant::now())) {
+        println!("Rate limiting /join from {:?}", addr);
+        return Ok(rejected("rate_limited", warp::http::StatusCode::TOO_MANY_REQUESTS));
+    }
+
     let dbinner = db.get(options.game_id).await?;
+
+    if (dbinner.game.is_locked().await) {
+        println!("Refusing join, room is locked");
+        return Ok(rejected("locked", warp::http::StatusCode::FORBIDDEN));
+    }
+
+    let response = join_game(&dbinner, &options.name, options.client_id.as_deref(), db.udp_port).await;
+    Ok(reply::json(&response).into_response())
+}
+
+async fn join_game(dbinner : &GameDbInner, name : &str, client_id : Option<&str>, udp_port : Option<u16>) -> JoinResponse {
     let server_description = dbinner.game.get_server_description().await;
     //let last_frame_time_us = dbinner.game.get_last_frame_time_us().await;
-    let socket_id = dbinner.game.join().await;
+    let (socket_id, session_token) = dbinner.game.join(name, client_id).await;
     let server_time_us = dbinner.game.time_since().await;
     let server_frame_id = dbinner.game.frame_id().await;
-    let response = JoinResponse {
+    JoinResponse {
         socket_id,
+        session_token,
         server_description,
         server_time_us : server_time_us.as_micros() as u32,
         server_frame_id,
+        udp_port,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct QuickPlayOptions {
+    pub name : String,
+    pub client_id : Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct QuickPlayResponse {
+    pub game_id : GameId,
+    #[serde(flatten)]
+    pub join : JoinResponse,
+}
+
+// Long poll, responds once we have found a game or given up waiting.
+async fn quickplay_handler(options : QuickPlayOptions, db: GameDb, addr : Option<std::net::SocketAddr>) -> Result<Response, Rejection>  {
+    println!("Quickplay with options {options:?}");
+
+    // Quickplay joins a game so shares the /join limit
+    if (!db.join_limiter.lock().unwrap().try_request(addr, std::time::Instant::now())) {
+        println!("Rate limiting /quickplay from {:?}", addr);
+        return Ok(rejected("rate_limited", warp::http::StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    match matchmaking::quick_play(&db).await {
+        Ok(matched) => {
+            let game_id = matched.game_id;
+            let dbinner = db.get(game_id.clone()).await?;
+            let join = join_game(&dbinner, &options.name, options.client_id.as_deref(), db.udp_port).await;
+            // Their slot stays reserved until they /play
+            db.matchmaking_queue.lock().unwrap().bind_reservation(matched.ticket, join.socket_id);
+            Ok(reply::json(&QuickPlayResponse { game_id, join }).into_response())
+        }
+        Err(e) => {
+            println!("Quickplay failed {:?}", e);
+            let (reason, status) = match e {
+                matchmaking::QuickPlayError::Timeout => ("timeout", warp::http::StatusCode::REQUEST_TIMEOUT),
+                matchmaking::QuickPlayError::Cancelled => ("cancelled", warp::http::StatusCode::SERVICE_UNAVAILABLE),
+                matchmaking::QuickPlayError::TooManyGames => ("too_many_games", warp::http::StatusCode::SERVICE_UNAVAILABLE),
+            };
+            Ok(rejected(reason, status))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RejoinOptions {
+    pub game_id : GameId,
+    pub session_token : crossy_server::SessionToken,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RejoinResponse {
+    pub socket_id : crossy_server::SocketId,
+    pub player_id : game::PlayerId,
+    pub server_description : interop::ServerDescription,
+    pub server_time_us : u32,
+    pub server_frame_id : u32,
+    pub udp_port : Option<u16>,
+}
+
+async fn rejoin_handler(options : RejoinOptions, db: GameDb, addr : Option<std::net::SocketAddr>) -> Result<Response, Rejection>  {
+    // Never log the token, it is all anyone needs to take the player over
+    println!("Rejoin to game {:?}", options.game_id);
+
+    // Shares the /join limit, otherwise this is a free oracle for guessing tokens
+    if (!db.join_limiter.lock().unwrap().try_request(addr, std::time::Instant::now())) {
+        println!("Rate limiting /rejoin from {:?}", addr);
+        return Ok(rejected("rate_limited", warp::http::StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    let dbinner = db.get(options.game_id).await?;
+    let rejoin_info = dbinner.game.rejoin(&options.session_token).await.ok_or(reject::not_found())?;
+    println!("[{:?}] Rejoined game {:?}", rejoin_info.socket_id, dbinner.id);
+    let server_description = dbinner.game.get_server_description().await;
+    let server_time_us = dbinner.game.time_since().await;
+    let server_frame_id = dbinner.game.frame_id().await;
+    let response = RejoinResponse {
+        socket_id : rejoin_info.socket_id,
+        player_id : rejoin_info.player_id,
+        server_description,
+        server_time_us : server_time_us.as_micros() as u32,
+        server_frame_id,
+        udp_port : db.udp_port,
     };
 
     Ok(reply::json(&response).into_response())
@@ -243,15 +653,35 @@ struct PlayOptions {
 async fn play_handler(options: PlayOptions, db: GameDb) -> Result<Response, Rejection>  {
     println!("Play with options {options:?}");
     let dbinner = db.get(options.game_id).await?;
+
+    // Someone who /joined just before the lock still shouldnt get a player
+    if (dbinner.game.is_locked().await) {
+        println!("[{:?}] Refusing play, room is locked", options.socket_id);
+        return Ok(rejected("locked", warp::http::StatusCode::FORBIDDEN));
+    }
+
     let hello = interop::ClientHello::default();
     let init_server_response = dbinner.game.play(&hello, options.socket_id).await;
-    Ok(reply::json(&init_server_response).into_response())
+    db.matchmaking_queue.lock().unwrap().release_reservation(&dbinner.id, options.socket_id);
+    match init_server_response {
+        Ok(x) => Ok(reply::json(&x).into_response()),
+        Err(e) => {
+            println!("[{:?}] Refusing play: {}", options.socket_id, e);
+            match e {
+                "not joined" => Ok(rejected("not_joined", warp::http::StatusCode::BAD_REQUEST)),
+                "already playing" => Ok(rejected("already_playing", warp::http::StatusCode::CONFLICT)),
+                _ => Ok(rejected("full", warp::http::StatusCode::FORBIDDEN)),
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct WebSocketJoinOptions {
     pub game_id : GameId, 
     pub socket_id : crossy_server::SocketId, 
+    // eg "latency=120,jitter=30,loss=0.02", only honoured with allow_net_sim_query
+    pub net_sim : Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -266,57 +696,127 @@ async fn start_time_utc_handler(options: StartTimeUtcOptions, db: GameDb) -> Res
     Ok(reply::json(&time).into_response())
 }
 
+// Oversized messages within this get a MessageRejected, anything beyond it just drops the connection.
+const HARD_MAX_MESSAGE_BYTES : usize = interop::MAX_CLIENT_MESSAGE_BYTES * 4;
+
 async fn ws_handler(ws : ws::Ws, options: WebSocketJoinOptions, db : GameDb) -> Result<Response, Rejection> {
     println!("WS Handler");
 
-    let game = db.get(options.game_id).await?;
+    let game = db.get(options.game_id.clone()).await?;
+
+    let net_sim = match &options.net_sim {
+        Some(spec) if db.allow_net_sim_query => match spec.parse() {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Bad net_sim {spec}: {e}");
+                return Ok(rejected("invalid net_sim", warp::http::StatusCode::BAD_REQUEST));
+            }
+        },
+        Some(_) => {
+            println!("Ignoring net_sim query, allow_net_sim_query is off");
+            db.net_sim
+        }
+        None => db.net_sim,
+    };
 
     let socket_id = options.socket_id;
-    Ok(ws.on_upgrade(move |socket| {
-        websocket_main(socket, game, socket_id)
+    Ok(ws.max_message_size(HARD_MAX_MESSAGE_BYTES).max_frame_size(HARD_MAX_MESSAGE_BYTES).on_upgrade(move |socket| {
+        websocket_main(socket, game, socket_id, net_sim)
     }).into_response())
 }
 
-async fn websocket_main(ws: WebSocket, db : GameDbInner, socket_id : crossy_server::SocketId) {
+async fn websocket_main(ws: WebSocket, db : GameDbInner, socket_id : crossy_server::SocketId, net_sim : crossy_multi_core::net_sim::NetworkConditions) {
     println!("Websocket connected");
+    metrics::CONNECTED_SOCKETS.inc();
 
     let mut tick_listener = db.game.get_listener();
     let game_start = db.game.get_start_time().await;
-    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (ws_tx, mut ws_rx) = ws.split();
+
+    // Shared so the receive loop can reply to bad messages
+    let ws_tx = Arc::new(Mutex::new(ws_tx));
+    let ws_tx0 = ws_tx.clone();
+
+    // Simulated bad network, messages each way go through a delay line instead of straight through
+    let (outgoing_line, incoming_line) = if (net_sim.is_perfect()) {
+        (None, None)
+    }
+    else {
+        println!("[{:?}] Simulating network {:?}", socket_id, net_sim);
+        let seed = socket_id.0 as u64;
+
+        let (outgoing_line, mut outgoing_rx) = net_sim::DelayLine::<Message>::spawn(net_sim, seed);
+        let ws_tx = ws_tx.clone();
+        tokio::task::spawn(async move {
+            while let Some(message) = outgoing_rx.recv().await {
+                if let Err(e) = ws_tx.lock().await.send(message).await {
+                    println!("Websocket send error {e}");
+                    break;
+                }
+            }
+        });
 
+        let (incoming_line, mut incoming_rx) = net_sim::DelayLine::<interop::CrossyMessage>::spawn(net_sim, seed + 1);
+        let game = db.game.clone();
+        tokio::task::spawn(async move {
+            while let Some(message) = incoming_rx.recv().await {
+                game.queue_message(message, socket_id).await;
+            }
+        });
+
+        (Some(outgoing_line), Some(incoming_line))
+    };
 
+    let outgoing_line0 = outgoing_line.clone();
     tokio::task::spawn(async move {
+        let ws_tx = ws_tx0;
+        let outgoing_line = outgoing_line0;
         loop {
             match tick_listener.recv().await {
                 Ok(crossy_multi_core::interop::CrossyMessage::GoodBye()) => {
                     println!("Game ended cleaning up WS listener");
                     break;
                 },
-                Ok(mut to_send) => {
+                Ok(message) => {
+                    let to_send = match message_for_socket(message, socket_id, game_start) {
+                        Some(x) => x,
+                        None => continue,
+                    };
 
-                    // Special case handling for time request responses
-                    if let interop::CrossyMessage::TimeRequestIntermediate(time_request_state) = &to_send {
-                        //println!("Time request packet {:#?}", time_request_state);
-                        if (time_request_state.socket_id != socket_id.0)
+                    let mut kicked = false;
+                    if let interop::CrossyMessage::PlayerKicked(player_kicked) = &to_send {
+                        kicked = player_kicked.socket_id == socket_id.0;
+                    }
+
+                    let serialized = flexbuffers::to_vec(&to_send).unwrap();
+                    metrics::BYTES_SENT.add(serialized.len() as u64);
+                    if let interop::CrossyMessage::LindenServerTick(_) = &to_send {
+                        metrics::TICK_BYTES_SENT.observe(serialized.len() as f64);
+                    }
+
+                    if let Some(line) = &outgoing_line {
+                        if (line.send(Message::binary(serialized)).is_err()) {
+                            println!("[{:?}] Simulated network closed", socket_id);
+                            break;
+                        }
+                    }
+                    else {
+                        match ws_tx.lock().await.send(Message::binary(serialized)).await
                         {
-                            // Not for us
-                            // this kinda sucks, todo improve
-                            continue;
+                            Ok(_) => {},
+                            Err(e) => {println!("Websocket send error {e}"); break;}
                         }
-
-                        let server_send_time_us = std::time::Instant::now().saturating_duration_since(game_start).as_micros() as u32;
-                        to_send = interop::CrossyMessage::TimeResponsePacket(interop::TimeResponsePacket{
-                            client_send_time_us : time_request_state.client_send_time_us,
-                            server_receive_time_us : time_request_state.server_receive_time_us,
-                            server_send_time_us,
-                        });
                     }
 
-                    let serialized = flexbuffers::to_vec(&to_send).unwrap();
-                    match ws_tx.send(Message::binary(serialized)).await
-                    {
-                        Ok(_) => {},
-                        Err(e) => {println!("Websocket send error {e}"); break;}
+                    if (kicked) {
+                        println!("[{:?}] Kicked, closing socket", socket_id);
+                        if let Some(line) = &outgoing_line {
+                            let _ = line.send_reliable(Message::close());
+                        }
+                        else {
+                            let _ = ws_tx.lock().await.send(Message::close()).await;
+                        }
+                        break;
                     }
                 },
                 Err(tokio::sync::broadcast::error::RecvError::Closed) => {
@@ -325,6 +825,8 @@ async fn websocket_main(ws: WebSocket, db : GameDbInner, socket_id : crossy_serv
                 },
                 Err(tokio::sync::broadcast::error::RecvError::Lagged(x)) => {
                     println!("[{:?}] Client lagged by {}", socket_id, x);
+                    metrics::BROADCAST_LAGGED_EVENTS.inc();
+                    metrics::BROADCAST_LAGGED_MESSAGES.add(x);
                 },
             }
         }
@@ -334,9 +836,31 @@ async fn websocket_main(ws: WebSocket, db : GameDbInner, socket_id : crossy_serv
         match result {
             Ok(msg) =>
             {
-                if let Some(message) = parse_client_message(&msg)
+                if (msg.is_close()) {
+                    continue;
+                }
+
+                match parse_client_message(&msg)
                 {
-                    db.game.queue_message(message, socket_id).await;
+                    Ok(message) => {
+                        if let Some(line) = &incoming_line {
+                            let _ = line.send(message);
+                        }
+                        else {
+                            db.game.queue_message(message, socket_id).await;
+                        }
+                    }
+                    Err(reason) => {
+                        println!("[{:?}] Rejected client message {:?}", socket_id, reason);
+                        let rejected = interop::CrossyMessage::MessageRejected(interop::MessageRejected { reason });
+                        let serialized = flexbuffers::to_vec(&rejected).unwrap();
+                        if let Some(line) = &outgoing_line {
+                            let _ = line.send(Message::binary(serialized));
+                        }
+                        else {
+                            let _ = ws_tx.lock().await.send(Message::binary(serialized)).await;
+                        }
+                    }
                 }
             }
             Err(e) => {
@@ -347,12 +871,187 @@ async fn websocket_main(ws: WebSocket, db : GameDbInner, socket_id : crossy_serv
     }
 
     println!("Client disconnected");
+    metrics::CONNECTED_SOCKETS.dec();
+    if let Some(line) = &incoming_line {
+        // Behind anything still in flight
+        let _ = line.send_reliable(interop::CrossyMessage::ClientDrop{});
+    }
+    else {
+        db.game.queue_message(interop::CrossyMessage::ClientDrop{}, socket_id).await;
+    }
+}
+
+async fn bot_ws_handler(ws : ws::Ws, options : JoinOptions, db : GameDb, addr : Option<std::net::SocketAddr>) -> Result<Response, Rejection> {
+    println!("Bot with options {options:?}");
+
+    // Bots join a game so share the /join limit
+    if (!db.join_limiter.lock().unwrap().try_request(addr, std::time::Instant::now())) {
+        println!("Rate limiting /bot from {:?}", addr);
+        return Ok(rejected("rate_limited", warp::http::StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    let dbinner = db.get(options.game_id).await?;
+
+    if (dbinner.game.is_locked().await) {
+        println!("Refusing bot, room is locked");
+        return Ok(rejected("locked", warp::http::StatusCode::FORBIDDEN));
+    }
+
+    if (dbinner.game.is_full().await) {
+        println!("Refusing bot, room is full");
+        return Ok(rejected("full", warp::http::StatusCode::FORBIDDEN));
+    }
+
+    let name = options.name;
+    let client_id = options.client_id;
+    Ok(ws.max_message_size(HARD_MAX_MESSAGE_BYTES).max_frame_size(HARD_MAX_MESSAGE_BYTES).on_upgrade(move |socket| {
+        bot_websocket_main(socket, dbinner, name, client_id)
+    }).into_response())
+}
+
+// Same lifecycle as websocket_main, but the bot gets a json view of the game instead of the raw server ticks.
+async fn bot_websocket_main(ws: WebSocket, db : GameDbInner, name : String, client_id : Option<String>) {
+    // Only join once the upgrade succeeds, otherwise we would leave a player nobody controls
+    // Someone else can still take the last slot during the upgrade
+    let (socket_id, player_id) = match db.game.join_bot(&name, client_id.as_deref()).await {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Refusing bot: {}", e);
+            return;
+        }
+    };
+    println!("[{:?}] Bot websocket connected", socket_id);
+    metrics::CONNECTED_SOCKETS.inc();
+
+    let mut tick_listener = db.game.get_listener();
+    let game_start = db.game.get_start_time().await;
+    let (ws_tx, mut ws_rx) = ws.split();
+
+    let ws_tx = Arc::new(Mutex::new(ws_tx));
+    let ws_tx0 = ws_tx.clone();
+    let game = db.game.clone();
+
+    tokio::task::spawn(async move {
+        let ws_tx = ws_tx0;
+        loop {
+            match tick_listener.recv().await {
+                Ok(interop::CrossyMessage::GoodBye()) => {
+                    println!("Game ended cleaning up bot WS listener");
+                    break;
+                },
+                Ok(interop::CrossyMessage::LindenServerTick(_)) => {
+                    let view = bot_protocol::BotServerMessage::View(Box::new(game.get_bot_view(player_id).await));
+                    let serialized = serde_json::to_string(&view).unwrap();
+                    metrics::BYTES_SENT.add(serialized.len() as u64);
+                    metrics::TICK_BYTES_SENT.observe(serialized.len() as f64);
+
+                    if let Err(e) = ws_tx.lock().await.send(Message::text(serialized)).await {
+                        println!("Websocket send error {e}");
+                        break;
+                    }
+                },
+                Ok(interop::CrossyMessage::PlayerKicked(player_kicked)) if player_kicked.socket_id == socket_id.0 => {
+                    println!("[{:?}] Bot kicked, closing socket", socket_id);
+                    let _ = ws_tx.lock().await.send(Message::close()).await;
+                    break;
+                },
+                Ok(_) => {},
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    println!("[{:?}] Underlying game closed", socket_id);
+                    break;
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(x)) => {
+                    // Views are built from the latest state, so nothing is lost by skipping ticks
+                    println!("[{:?}] Bot lagged by {}", socket_id, x);
+                    metrics::BROADCAST_LAGGED_EVENTS.inc();
+                    metrics::BROADCAST_LAGGED_MESSAGES.add(x);
+                },
+            }
+        }
+    });
+
+    while let Some(result) = ws_rx.next().await {
+        match result {
+            Ok(msg) =>
+            {
+                if (msg.is_close() || msg.is_ping() || msg.is_pong()) {
+                    continue;
+                }
+
+                let receive_time_us = std::time::Instant::now().saturating_duration_since(game_start).as_micros() as u32;
+                let parsed = msg.to_str()
+                    .map_err(|_| interop::RejectionReason::Malformed)
+                    .and_then(|x| bot_protocol::parse_reply(x, receive_time_us));
+
+                match parsed
+                {
+                    Ok(tick) => {
+                        db.game.queue_message(interop::CrossyMessage::ClientTick(vec![tick]), socket_id).await;
+                    }
+                    Err(reason) => {
+                        println!("[{:?}] Rejected bot message {:?}", socket_id, reason);
+                        let rejected = bot_protocol::BotServerMessage::Rejected { reason };
+                        let serialized = serde_json::to_string(&rejected).unwrap();
+                        let _ = ws_tx.lock().await.send(Message::text(serialized)).await;
+                    }
+                }
+            }
+            Err(e) => {
+                println!("Bot receive err {e}");
+                break;
+            }
+        }
+    }
+
+    println!("Bot disconnected");
+    metrics::CONNECTED_SOCKETS.dec();
     db.game.queue_message(interop::CrossyMessage::ClientDrop{}, socket_id).await;
 }
 
-fn parse_client_message(ws_message : &warp::ws::Message) -> Option<interop::CrossyMessage>
+// What a socket should be sent for a message broadcast by the game, None if it isnt for them.
+fn message_for_socket(message : interop::CrossyMessage, socket_id : crossy_server::SocketId, game_start : std::time::Instant) -> Option<interop::CrossyMessage>
+{
+    // Special case handling for time request responses
+    if let interop::CrossyMessage::TimeRequestIntermediate(time_request_state) = &message {
+        //println!("Time request packet {:#?}", time_request_state);
+        if (time_request_state.socket_id != socket_id.0)
+        {
+            // Not for us
+            // this kinda sucks, todo improve
+            return None;
+        }
+
+        let server_send_time_us = std::time::Instant::now().saturating_duration_since(game_start).as_micros() as u32;
+        return Some(interop::CrossyMessage::TimeResponsePacket(interop::TimeResponsePacket{
+            client_send_time_us : time_request_state.client_send_time_us,
+            server_receive_time_us : time_request_state.server_receive_time_us,
+            server_send_time_us,
+        }));
+    }
+
+    Some(message)
+}
+
+fn parse_client_message(ws_message : &warp::ws::Message) -> Result<interop::CrossyMessage, interop::RejectionReason>
 {
     let bytes = ws_message.as_bytes();
-    let r = flexbuffers::Reader::get_root(bytes).map_err(|e| println!("{e}")).ok()?;
-    interop::CrossyMessage::deserialize(r).map_err(|e| println!("{e}")).ok()
+    if (bytes.len() > interop::MAX_CLIENT_MESSAGE_BYTES) {
+        return Err(interop::RejectionReason::MessageTooLarge);
+    }
+
+    let r = flexbuffers::Reader::get_root(bytes).map_err(|e| {
+        println!("{e}");
+        interop::RejectionReason::Malformed
+    })?;
+
+    let message = interop::CrossyMessage::deserialize(r).map_err(|e| {
+        println!("{e}");
+        interop::RejectionReason::Malformed
+    })?;
+
+    if (!message.within_collection_limits()) {
+        return Err(interop::RejectionReason::TooManyEntries);
+    }
+
+    Ok(message)
 }
\ No newline at end of file
@@ -0,0 +1,345 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossy_multi_core::crossy_ruleset::{GameConfig, MAX_PLAYERS};
+use tokio::sync::oneshot;
+
+use crate::crossy_server::{GamePhase, GameSummary, SocketId};
+use crate::{GameDb, GameId};
+
+// How many players need to be waiting before we spin up a new game for them.
+const QUICKPLAY_MIN_PLAYERS: usize = 2;
+const QUICKPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a matched player has to /play before someone else can have their slot.
+const RESERVATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuickPlayError {
+    Timeout,
+    Cancelled,
+    TooManyGames,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuickPlayMatch {
+    pub game_id: GameId,
+    // Hand this back with the socket id once joined, see MatchmakingQueue::bind_reservation
+    pub ticket: u64,
+}
+
+struct QueuedPlayer {
+    ticket: u64,
+    // None if we couldnt create a game for them
+    tx: oneshot::Sender<Option<GameId>>,
+}
+
+// A slot held for a matched player between being matched and calling /play.
+// Without these the roster looks emptier than it is and concurrent quickplays overfill the lobby.
+struct Reservation {
+    ticket: u64,
+    game_id: GameId,
+    // Set once they have /joined, so /play can give the slot back
+    socket_id: Option<SocketId>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+pub struct MatchmakingQueue {
+    next_ticket: u64,
+    waiting: Vec<QueuedPlayer>,
+    reservations: Vec<Reservation>,
+}
+
+impl MatchmakingQueue {
+    fn next_ticket(&mut self) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        ticket
+    }
+
+    fn push(&mut self, ticket: u64, tx: oneshot::Sender<Option<GameId>>) {
+        self.waiting.push(QueuedPlayer {
+            ticket,
+            tx,
+        });
+    }
+
+    fn remove(&mut self, ticket: u64) {
+        self.waiting.retain(|x| x.ticket != ticket);
+    }
+
+    // Longest waiting first
+    fn take_waiting(&mut self, count: usize) -> Vec<QueuedPlayer> {
+        let count = count.min(self.waiting.len());
+        self.waiting.drain(..count).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    fn reserve(&mut self, ticket: u64, game_id: &GameId, now: Instant) {
+        self.reservations.push(Reservation {
+            ticket,
+            game_id: game_id.clone(),
+            socket_id: None,
+            expires: now + RESERVATION_TIMEOUT,
+        });
+    }
+
+    fn cancel_reservation(&mut self, ticket: u64) {
+        self.reservations.retain(|x| x.ticket != ticket);
+    }
+
+    pub fn bind_reservation(&mut self, ticket: u64, socket_id: SocketId) {
+        if let Some(reservation) = self.reservations.iter_mut().find(|x| x.ticket == ticket) {
+            reservation.socket_id = Some(socket_id);
+        }
+    }
+
+    // Called on /play, from here on the roster counts them instead.
+    pub fn release_reservation(&mut self, game_id: &GameId, socket_id: SocketId) {
+        self.reservations.retain(|x| !(x.game_id == *game_id && x.socket_id == Some(socket_id)));
+    }
+
+    pub fn prune(&mut self, now: Instant) {
+        self.reservations.retain(|x| x.expires > now);
+    }
+
+    fn free_slots(&mut self, game_id: &GameId, summary: &GameSummary, now: Instant) -> usize {
+        if (summary.phase != GamePhase::Lobby || summary.locked) {
+            return 0;
+        }
+
+        self.prune(now);
+        let reserved = self.reservations.iter().filter(|x| x.game_id == *game_id).count();
+        (MAX_PLAYERS as usize).saturating_sub(summary.player_count as usize + reserved)
+    }
+
+    // Reserves a slot for each player and tells them where to go.
+    fn assign(&mut self, players: Vec<QueuedPlayer>, game_id: &GameId, now: Instant) {
+        for player in players {
+            self.reserve(player.ticket, game_id, now);
+            if (player.tx.send(Some(game_id.clone())).is_err()) {
+                // They gave up in the meantime
+                self.cancel_reservation(player.ticket);
+            }
+        }
+    }
+}
+
+// Takes a player out of the queue if their request goes away before they are matched
+// eg the client closes the connection or we hit the timeout.
+struct QueueTicketGuard {
+    queue: Arc<Mutex<MatchmakingQueue>>,
+    ticket: u64,
+}
+
+impl Drop for QueueTicketGuard {
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().remove(self.ticket);
+    }
+}
+
+pub async fn quick_play(db: &GameDb) -> Result<QuickPlayMatch, QuickPlayError> {
+    let ticket = db.matchmaking_queue.lock().unwrap().next_ticket();
+
+    if let Some(game_id) = reserve_open_lobby(db, ticket).await {
+        println!("Quickplay found open lobby {:?}", game_id);
+        return Ok(QuickPlayMatch { game_id, ticket });
+    }
+
+    let (tx, rx) = oneshot::channel();
+
+    let matched = {
+        let mut queue = db.matchmaking_queue.lock().unwrap();
+        queue.push(ticket, tx);
+
+        if (queue.len() >= QUICKPLAY_MIN_PLAYERS) {
+            queue.take_waiting(MAX_PLAYERS as usize)
+        }
+        else {
+            Vec::new()
+        }
+    };
+
+    let _guard = QueueTicketGuard {
+        queue: db.matchmaking_queue.clone(),
+        ticket,
+    };
+
+    if (!matched.is_empty()) {
+        match db.new_game(GameConfig::default(), true).await {
+            Some(game_id) => {
+                println!("Quickplay matched {} players into {:?}", matched.len(), game_id);
+                db.matchmaking_queue.lock().unwrap().assign(matched, &game_id, Instant::now());
+            }
+            None => {
+                // Out of games, tell everyone we matched rather than leaving them waiting
+                for player in matched {
+                    // Fails if they cancelled in the meantime, nothing to do.
+                    let _ = player.tx.send(None);
+                }
+            }
+        }
+    }
+
+    match tokio::time::timeout(QUICKPLAY_TIMEOUT, rx).await {
+        Ok(Ok(Some(game_id))) => Ok(QuickPlayMatch { game_id, ticket }),
+        Ok(Ok(None)) => Err(QuickPlayError::TooManyGames),
+        Ok(Err(_)) => Err(QuickPlayError::Cancelled),
+        Err(_) => Err(QuickPlayError::Timeout),
+    }
+}
+
+// A public lobby opened up, hand it to anyone still waiting.
+pub async fn offer_lobby(db: &GameDb, game_id: &GameId) {
+    let summary = match db.get(game_id.clone()).await {
+        Ok(x) => x.game.get_summary().await,
+        Err(_) => return,
+    };
+
+    let now = Instant::now();
+    let mut queue = db.matchmaking_queue.lock().unwrap();
+    let free_slots = queue.free_slots(game_id, &summary, now);
+    let players = queue.take_waiting(free_slots);
+    if (!players.is_empty()) {
+        println!("Quickplay matched {} waiting players into {:?}", players.len(), game_id);
+        queue.assign(players, game_id, now);
+    }
+}
+
+async fn reserve_open_lobby(db: &GameDb, ticket: u64) -> Option<GameId> {
+    let listings = db.list_public().await;
+
+    let now = Instant::now();
+    let mut queue = db.matchmaking_queue.lock().unwrap();
+    for listing in listings {
+        if (queue.free_slots(&listing.game_id, &listing.summary, now) > 0) {
+            queue.reserve(ticket, &listing.game_id, now);
+            return Some(listing.game_id);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::test_db;
+
+    fn lobby(player_count: u32) -> GameSummary {
+        GameSummary {
+            player_count,
+            phase: GamePhase::Lobby,
+            round_id: 0,
+            locked: false,
+            created_utc: String::new(),
+            average_rating: None,
+        }
+    }
+
+    fn game_id(x: &str) -> GameId {
+        GameId(x.to_owned())
+    }
+
+    #[test]
+    fn reservations_take_slots_until_play() {
+        let now = Instant::now();
+        let mut queue = MatchmakingQueue::default();
+        let (a, b) = (game_id("a"), game_id("b"));
+        let full = MAX_PLAYERS as usize;
+
+        assert_eq!(queue.free_slots(&a, &lobby(1), now), full - 1);
+
+        let ticket = queue.next_ticket();
+        queue.reserve(ticket, &a, now);
+        let other_ticket = queue.next_ticket();
+        queue.reserve(other_ticket, &a, now);
+        assert_eq!(queue.free_slots(&a, &lobby(1), now), full - 3);
+        assert_eq!(queue.free_slots(&b, &lobby(1), now), full - 1);
+
+        // Once they /play the roster counts them instead
+        queue.bind_reservation(ticket, SocketId(7));
+        queue.release_reservation(&a, SocketId(7));
+        assert_eq!(queue.free_slots(&a, &lobby(2), now), full - 3);
+
+        // Nobody can hold a slot forever
+        assert_eq!(queue.free_slots(&a, &lobby(2), now + RESERVATION_TIMEOUT * 2), full - 2);
+    }
+
+    #[test]
+    fn no_slots_outside_open_lobbies() {
+        let now = Instant::now();
+        let mut queue = MatchmakingQueue::default();
+        let a = game_id("a");
+
+        let locked = GameSummary { locked: true, ..lobby(1) };
+        assert_eq!(queue.free_slots(&a, &locked, now), 0);
+        let in_round = GameSummary { phase: GamePhase::InRound, ..lobby(1) };
+        assert_eq!(queue.free_slots(&a, &in_round, now), 0);
+        assert_eq!(queue.free_slots(&a, &lobby(MAX_PLAYERS as u32 + 1), now), 0);
+    }
+
+    #[test]
+    fn assign_skips_cancelled_players() {
+        let now = Instant::now();
+        let mut queue = MatchmakingQueue::default();
+        let a = game_id("a");
+
+        let (tx0, mut rx0) = oneshot::channel();
+        let (tx1, rx1) = oneshot::channel();
+        let (tx2, _rx2) = oneshot::channel();
+        for tx in [tx0, tx1, tx2] {
+            let ticket = queue.next_ticket();
+            queue.push(ticket, tx);
+        }
+        drop(rx1);
+
+        let players = queue.take_waiting(2);
+        assert_eq!(queue.len(), 1);
+        queue.assign(players, &a, now);
+
+        assert_eq!(rx0.try_recv().unwrap(), Some(a.clone()));
+        // Only the player still listening holds a slot
+        assert_eq!(queue.free_slots(&a, &lobby(0), now), MAX_PLAYERS as usize - 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_quickplays_dont_overfill() {
+        let db = test_db("overfill");
+        let game_id = db.new_game(GameConfig::default(), true).await.unwrap();
+
+        // Nobody has /played yet so the roster stays empty throughout
+        let matches = futures::future::join_all((0..MAX_PLAYERS).map(|_| quick_play(&db))).await;
+        assert!(matches.iter().all(|x| x.as_ref().map(|x| x.game_id == game_id).unwrap_or(false)));
+
+        // Lobby is spoken for, the next player has to wait
+        let next = tokio::time::timeout(Duration::from_millis(100), quick_play(&db)).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn waiting_player_gets_new_lobby() {
+        let db = test_db("waiting");
+
+        let waiting = {
+            let db = db.clone();
+            tokio::task::spawn(async move { quick_play(&db).await })
+        };
+
+        // Give them time to join the queue
+        while (db.matchmaking_queue.lock().unwrap().len() == 0) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let game_id = db.new_game(GameConfig::default(), true).await.unwrap();
+        offer_lobby(&db, &game_id).await;
+
+        let matched = tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap().unwrap();
+        assert_eq!(matched.game_id, game_id);
+        assert_eq!(db.matchmaking_queue.lock().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+// Minimal prometheus style metrics, rendered in the text exposition format on /metrics.
+// Per game values (games, players per phase) are gathered when scraped rather than tracked here.
+
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self {
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, x: u64) {
+        self.value.fetch_add(x, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge {
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Self {
+            value: AtomicI64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    bucket_counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    // f64 stored as bits so we can update without a lock
+    sum_bits: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            bucket_counts: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_bits: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, x: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.bucket_counts) {
+            if (x <= *bound) {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        let mut current = self.sum_bits.load(Ordering::Relaxed);
+        loop {
+            let new = (f64::from_bits(current) + x).to_bits();
+            match self.sum_bits.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, bucket) in self.bounds.iter().zip(&self.bucket_counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, f64::from_bits(self.sum_bits.load(Ordering::Relaxed)));
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+const TICK_DURATION_BOUNDS: [f64; 10] = [0.0005, 0.001, 0.002, 0.004, 0.008, 0.012, 0.016, 0.025, 0.05, 0.1];
+const TICK_BYTES_BOUNDS: [f64; 9] = [256.0, 512.0, 1024.0, 2048.0, 4096.0, 8192.0, 16384.0, 32768.0, 65536.0];
+
+pub static CONNECTED_SOCKETS: Gauge = Gauge::new();
+pub static FUTURE_INPUT_REQUEUES: Counter = Counter::new();
+pub static BROADCAST_LAGGED_EVENTS: Counter = Counter::new();
+pub static BROADCAST_LAGGED_MESSAGES: Counter = Counter::new();
+pub static BYTES_SENT: Counter = Counter::new();
+pub static INPUT_VIOLATIONS: Counter = Counter::new();
+pub static UDP_PACKETS_DROPPED: Counter = Counter::new();
+
+lazy_static! {
+    pub static ref TICK_DURATION_SECONDS: Histogram = Histogram::new(&TICK_DURATION_BOUNDS);
+    pub static ref TICK_BYTES_SENT: Histogram = Histogram::new(&TICK_BYTES_BOUNDS);
+}
+
+pub struct GameMetrics {
+    pub active_games: usize,
+    // (phase label, player count)
+    pub players_per_phase: Vec<(&'static str, u32)>,
+}
+
+pub fn render(game_metrics: &GameMetrics) -> String {
+    let mut out = String::with_capacity(4096);
+
+    render_value(&mut out, "crossy_active_games", "gauge", "Games currently running", game_metrics.active_games as f64);
+    render_value(&mut out, "crossy_connected_sockets", "gauge", "Open websocket and UDP connections", CONNECTED_SOCKETS.get() as f64);
+
+    let _ = writeln!(out, "# HELP crossy_players Players in a game by ruleset phase");
+    let _ = writeln!(out, "# TYPE crossy_players gauge");
+    for (phase, count) in &game_metrics.players_per_phase {
+        let _ = writeln!(out, "crossy_players{{phase=\"{}\"}} {}", phase, count);
+    }
+
+    TICK_DURATION_SECONDS.render(&mut out, "crossy_tick_duration_seconds", "Time spent simulating and broadcasting one server tick");
+    TICK_BYTES_SENT.render(&mut out, "crossy_tick_bytes_sent", "Bytes sent to one socket for one server tick");
+
+    render_value(&mut out, "crossy_future_input_requeues_total", "counter", "Client inputs from the future sent back to the queue", FUTURE_INPUT_REQUEUES.get() as f64);
+    render_value(&mut out, "crossy_broadcast_lagged_total", "counter", "Times a socket fell behind the game broadcast channel", BROADCAST_LAGGED_EVENTS.get() as f64);
+    render_value(&mut out, "crossy_broadcast_lagged_messages_total", "counter", "Messages skipped by sockets that fell behind", BROADCAST_LAGGED_MESSAGES.get() as f64);
+    render_value(&mut out, "crossy_input_violations_total", "counter", "Client inputs that failed server validation", INPUT_VIOLATIONS.get() as f64);
+    render_value(&mut out, "crossy_udp_packets_dropped_total", "counter", "UDP packets that were malformed, unauthorised, duplicated or out of date", UDP_PACKETS_DROPPED.get() as f64);
+    render_value(&mut out, "crossy_bytes_sent_total", "counter", "Bytes sent over all websockets and UDP", BYTES_SENT.get() as f64);
+
+    out
+}
+
+fn render_value(out: &mut String, name: &str, metric_type: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(out, "{} {}", name, value);
+}
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+use crossy_multi_core::net_sim::{NetworkConditions, NetworkSimulator};
+use tokio::sync::mpsc;
+
+// Pushes messages through a NetworkSimulator on their way somewhere else, in real time.
+// Used to give websocket connections a bad network in both directions, see core/src/net_sim.rs.
+
+#[derive(Clone)]
+pub struct DelayLine<T> {
+    tx: mpsc::UnboundedSender<(bool, T)>,
+}
+
+impl<T: Clone + Send + 'static> DelayLine<T> {
+    // Messages come out of the receiver once the simulated network delivers them.
+    // Dropping the DelayLine lets whatever is still in flight arrive, then closes the receiver.
+    pub fn spawn(conditions: NetworkConditions, seed: u64) -> (Self, mpsc::UnboundedReceiver<T>) {
+        let (tx, mut in_rx) = mpsc::unbounded_channel::<(bool, T)>();
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+
+        tokio::task::spawn(async move {
+            let start = Instant::now();
+            let mut sim = NetworkSimulator::new(conditions, seed);
+            let mut closed = false;
+
+            loop {
+                let now_us = start.elapsed().as_micros() as u64;
+                for message in sim.poll(now_us) {
+                    if (out_tx.send(message).is_err()) {
+                        return;
+                    }
+                }
+
+                if (closed && sim.in_flight_count() == 0) {
+                    return;
+                }
+
+                let wait = sim.next_delivery_us().map(|x| Duration::from_micros(x.saturating_sub(now_us)));
+                tokio::select! {
+                    received = in_rx.recv(), if !closed => {
+                        let now_us = start.elapsed().as_micros() as u64;
+                        match received {
+                            Some((true, message)) => sim.send_reliable(now_us, message),
+                            Some((false, message)) => sim.send(now_us, message),
+                            None => closed = true,
+                        }
+                    }
+                    _ = sleep_or_forever(wait) => {}
+                }
+            }
+        });
+
+        (Self { tx }, out_rx)
+    }
+
+    // Gives the message back once whatever is on the far end has gone.
+    pub fn send(&self, message: T) -> Result<(), T> {
+        self.tx.send((false, message)).map_err(|e| e.0.1)
+    }
+
+    // Never lost or reordered, for things the real transport guarantees like closing the socket.
+    pub fn send_reliable(&self, message: T) -> Result<(), T> {
+        self.tx.send((true, message)).map_err(|e| e.0.1)
+    }
+}
+
+async fn sleep_or_forever(duration: Option<Duration>) {
+    match duration {
+        Some(x) => tokio::time::sleep(x).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn send_fails_once_receiver_is_gone() {
+        let conditions: NetworkConditions = "latency=5".parse().unwrap();
+        let (line, rx) = DelayLine::spawn(conditions, 1);
+        assert!(line.send(1).is_ok());
+
+        // The line only notices when it next delivers something
+        drop(rx);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(line.send(2), Err(2));
+        assert_eq!(line.send_reliable(3), Err(3));
+    }
+}
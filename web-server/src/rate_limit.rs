@@ -0,0 +1,114 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// Sliding window limit, allows at most max_requests in any window.
+pub struct WindowLimiter {
+    max_requests: usize,
+    window: Duration,
+    recent: VecDeque<Instant>,
+}
+
+impl WindowLimiter {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        Self {
+            max_requests,
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn try_request(&mut self, now: Instant) -> bool {
+        while let Some(front) = self.recent.front() {
+            if (now.saturating_duration_since(*front) > self.window) {
+                self.recent.pop_front();
+            }
+            else {
+                break;
+            }
+        }
+
+        if (self.recent.len() >= self.max_requests) {
+            return false;
+        }
+
+        self.recent.push_back(now);
+        true
+    }
+
+    // Nothing in the window, forgetting this limiter changes nothing
+    fn is_idle(&self, now: Instant) -> bool {
+        self.recent.back().map(|x| now.saturating_duration_since(*x) > self.window).unwrap_or(true)
+    }
+}
+
+// Same limit applied to each IP separately.
+// NOTE if we end up behind a proxy we will need to key on the forwarded address instead.
+pub struct IpRateLimiter {
+    max_requests: usize,
+    window: Duration,
+    recent: HashMap<IpAddr, WindowLimiter>,
+}
+
+impl IpRateLimiter {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        Self {
+            max_requests,
+            window,
+            recent: HashMap::new(),
+        }
+    }
+
+    pub fn try_request(&mut self, addr: Option<SocketAddr>, now: Instant) -> bool {
+        // Should always be set for tcp connections
+        let ip = match addr {
+            Some(x) => x.ip(),
+            None => return true,
+        };
+
+        let (max_requests, window) = (self.max_requests, self.window);
+        self.recent.entry(ip)
+            .or_insert_with(|| WindowLimiter::new(max_requests, window))
+            .try_request(now)
+    }
+
+    // Forget IPs that have no requests in the window so the map doesnt grow forever.
+    pub fn prune(&mut self, now: Instant) {
+        self.recent.retain(|_, limiter| !limiter.is_idle(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_limiter_refills_after_window() {
+        let start = Instant::now();
+        let mut limiter = WindowLimiter::new(2, Duration::from_secs(5));
+        assert!(limiter.try_request(start));
+        assert!(limiter.try_request(start + Duration::from_secs(1)));
+        assert!(!limiter.try_request(start + Duration::from_secs(2)));
+
+        // First request has left the window
+        assert!(limiter.try_request(start + Duration::from_secs(6)));
+        assert!(!limiter.try_request(start + Duration::from_secs(6)));
+    }
+
+    #[test]
+    fn ip_limiter_is_per_ip_and_prunes() {
+        let start = Instant::now();
+        let a: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        let mut limiter = IpRateLimiter::new(1, Duration::from_secs(5));
+
+        assert!(limiter.try_request(Some(a), start));
+        assert!(!limiter.try_request(Some(a), start));
+        assert!(limiter.try_request(Some(b), start));
+
+        limiter.prune(start + Duration::from_secs(1));
+        assert_eq!(limiter.recent.len(), 2);
+        limiter.prune(start + Duration::from_secs(10));
+        assert!(limiter.recent.is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crossy_multi_core::player_id_map::PlayerIdMap;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::results::MatchResult;
+
+pub const RATINGS_PATH: &str = "ratings.json";
+
+const INITIAL_RATING: f64 = 1500.0;
+
+// Elo K factors, winning the match counts for more than winning a single round.
+const ROUND_K: f64 = 12.0;
+const MATCH_K: f64 = 32.0;
+
+// A player is identified by their name and a token the client generates and keeps hold of.
+// Identities show up on /results and /leaderboard, so they are a HMAC of the two under a key only the server has.
+// Without the key nobody can work back to a client id, or make up one that matches someone elses identity.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlayerIdentity(String);
+
+impl PlayerIdentity {
+    fn new(key: &[u8], name: &str, client_id: &str) -> Option<Self> {
+        if (client_id.is_empty()) {
+            return None;
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        // Length prefixed so ("ab", "c") and ("a", "bc") differ
+        mac.update(&(name.len() as u64).to_le_bytes());
+        mac.update(name.as_bytes());
+        mac.update(client_id.as_bytes());
+
+        let hash = mac.finalize().into_bytes();
+        Some(PlayerIdentity(hash[..16].iter().map(|x| format!("{:02x}", x)).collect()))
+    }
+}
+
+// Kept next to the ratings file, losing it means everyone starts again with a new identity.
+fn load_or_create_key(path: &str) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(key) if !key.is_empty() => return key,
+        Ok(_) => println!("Identity key {} is empty, making a new one", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => println!("Unable to read identity key from {}: {}", path, e),
+    }
+
+    let key = rand::random::<[u8; 32]>().to_vec();
+    if let Err(e) = std::fs::write(path, &key) {
+        println!("Unable to save identity key to {}, ratings wont survive a restart: {}", path, e);
+    }
+    key
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerRating {
+    pub identity: PlayerIdentity,
+    pub name: String,
+    pub rating: f64,
+    pub matches: u32,
+    pub match_wins: u32,
+    pub rounds: u32,
+    pub round_wins: u32,
+}
+
+impl PlayerRating {
+    fn new(identity: PlayerIdentity, name: String) -> Self {
+        Self {
+            identity,
+            name,
+            rating: INITIAL_RATING,
+            matches: 0,
+            match_wins: 0,
+            rounds: 0,
+            round_wins: 0,
+        }
+    }
+}
+
+// Ratings are small enough to keep in memory and rewrite the whole file after each match.
+pub struct RatingsStore {
+    path: String,
+    identity_key: Vec<u8>,
+    ratings: Mutex<HashMap<PlayerIdentity, PlayerRating>>,
+    // Bumped on every change. Files are written outside the ratings lock so
+    // saves can finish out of order, never replace a newer file with an older one.
+    version: AtomicU64,
+    saved_version: Mutex<u64>,
+}
+
+impl RatingsStore {
+    pub fn open(path: &str) -> Self {
+        let ratings: HashMap<PlayerIdentity, PlayerRating> = match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                match serde_json::from_str::<Vec<PlayerRating>>(&contents) {
+                    Ok(ratings) => ratings.into_iter().map(|x| (x.identity.clone(), x)).collect(),
+                    Err(e) => {
+                        println!("Unable to parse ratings from {}: {}", path, e);
+                        HashMap::new()
+                    },
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                println!("Unable to read ratings from {}: {}", path, e);
+                HashMap::new()
+            },
+        };
+
+        println!("Loaded {} ratings from {}", ratings.len(), path);
+
+        Self {
+            path: path.to_owned(),
+            identity_key: load_or_create_key(&format!("{}.key", path)),
+            ratings: Mutex::new(ratings),
+            version: AtomicU64::new(0),
+            saved_version: Mutex::new(0),
+        }
+    }
+
+    // None for players that didnt send a client id, they dont get rated
+    pub fn identity(&self, name: &str, client_id: &str) -> Option<PlayerIdentity> {
+        PlayerIdentity::new(&self.identity_key, name, client_id)
+    }
+
+    pub fn record_match(&self, result: &MatchResult) {
+        let mut ratings = self.ratings.lock().unwrap();
+
+        // Only players that sent a client id get rated
+        let mut rated = PlayerIdMap::new();
+        for player in &result.players {
+            if let Some(identity) = &player.identity {
+                rated.set(player.player_id, identity.clone());
+            }
+        }
+
+        for player in &result.players {
+            if let Some(identity) = &player.identity {
+                let rating = ratings.entry(identity.clone())
+                    .or_insert_with(|| PlayerRating::new(identity.clone(), player.name.clone()));
+                rating.name = player.name.clone();
+                rating.matches += 1;
+                if (player.player_id == result.winner) {
+                    rating.match_wins += 1;
+                }
+            }
+        }
+
+        for round in &result.rounds {
+            let participants: Vec<&PlayerIdentity> = round.players.iter().filter_map(|x| rated.get(*x)).collect();
+            for identity in &participants {
+                if let Some(rating) = ratings.get_mut(*identity) {
+                    rating.rounds += 1;
+                }
+            }
+
+            if let Some(winner) = round.winner.and_then(|x| rated.get(x)) {
+                if let Some(rating) = ratings.get_mut(winner) {
+                    rating.round_wins += 1;
+                }
+
+                apply_win(&mut ratings, winner, &participants, ROUND_K);
+            }
+        }
+
+        if let Some(winner) = rated.get(result.winner) {
+            let participants: Vec<&PlayerIdentity> = rated.iter().map(|(_, x)| x).collect();
+            apply_win(&mut ratings, winner, &participants, MATCH_K);
+        }
+
+        // Snapshot under the lock, /games and /leaderboard read ratings while we write
+        let all: Vec<&PlayerRating> = ratings.values().collect();
+        let contents = serde_json::to_string(&all);
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        drop(ratings);
+
+        let result = contents.map_err(std::io::Error::from).and_then(|x| self.save(&x, version));
+        if let Err(e) = result {
+            println!("Failed to write ratings to {}: {}", self.path, e);
+        }
+    }
+
+    fn save(&self, contents: &str, version: u64) -> std::io::Result<()> {
+        let mut saved_version = self.saved_version.lock().unwrap();
+        if (*saved_version > version) {
+            return Ok(());
+        }
+
+        // Write then rename so a crash mid write doesnt lose everything
+        let tmp_path = format!("{}.tmp", self.path);
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        *saved_version = version;
+        Ok(())
+    }
+
+    // Highest rated first
+    pub fn leaderboard(&self, limit: usize) -> Vec<PlayerRating> {
+        let ratings = self.ratings.lock().unwrap();
+        let mut all: Vec<PlayerRating> = ratings.values().cloned().collect();
+        all.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        all.truncate(limit);
+        all
+    }
+
+    // Players we havent seen before count as the initial rating.
+    pub fn average_rating<'a>(&self, identities: impl Iterator<Item = &'a PlayerIdentity>) -> Option<f64> {
+        let ratings = self.ratings.lock().unwrap();
+        let mut total = 0.0;
+        let mut count = 0;
+        for identity in identities {
+            total += ratings.get(identity).map(|x| x.rating).unwrap_or(INITIAL_RATING);
+            count += 1;
+        }
+
+        if (count == 0) {
+            None
+        }
+        else {
+            Some(total / count as f64)
+        }
+    }
+}
+
+// Multiplayer Elo, the winner beats each other participant with the K factor split between them.
+fn apply_win(ratings: &mut HashMap<PlayerIdentity, PlayerRating>, winner: &PlayerIdentity, participants: &[&PlayerIdentity], k: f64) {
+    let losers: Vec<&PlayerIdentity> = participants.iter().copied().filter(|x| *x != winner).collect();
+    if (losers.is_empty()) {
+        return;
+    }
+
+    let get_rating = |ratings: &HashMap<PlayerIdentity, PlayerRating>, x: &PlayerIdentity| {
+        ratings.get(x).map(|x| x.rating).unwrap_or(INITIAL_RATING)
+    };
+
+    let k = k / losers.len() as f64;
+    let winner_rating = get_rating(ratings, winner);
+    let mut winner_delta = 0.0;
+
+    for loser in losers {
+        let loser_rating = get_rating(ratings, loser);
+        let expected = 1.0 / (1.0 + 10f64.powf((loser_rating - winner_rating) / 400.0));
+        let delta = k * (1.0 - expected);
+        winner_delta += delta;
+
+        if let Some(rating) = ratings.get_mut(loser) {
+            rating.rating -= delta;
+        }
+    }
+
+    if let Some(rating) = ratings.get_mut(winner) {
+        rating.rating += winner_delta;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results::{MatchPlayer, RoundResult};
+    use crossy_multi_core::game::PlayerId;
+
+    fn temp_store(name: &str) -> (RatingsStore, String) {
+        let path = std::env::temp_dir().join(format!("crossy_ratings_{}_{}.json", name, std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.key", path));
+        (RatingsStore::open(&path), path)
+    }
+
+    fn player(id: u8) -> MatchPlayer {
+        let name = format!("p{}", id);
+        MatchPlayer {
+            player_id: PlayerId(id),
+            identity: Some(PlayerIdentity(name.clone())),
+            name,
+        }
+    }
+
+    fn result(players: &[u8], round_winners: &[Option<u8>], winner: u8) -> MatchResult {
+        MatchResult {
+            game_id: String::from("test"),
+            seed: 0,
+            finished_utc: String::new(),
+            players: players.iter().map(|x| player(*x)).collect(),
+            rounds: round_winners.iter().enumerate().map(|(i, x)| RoundResult {
+                round_id: i as u8 + 1,
+                winner: x.map(PlayerId),
+                players: players.iter().map(|x| PlayerId(*x)).collect(),
+                duration_ms: 0,
+            }).collect(),
+            winner: PlayerId(winner),
+            win_counts: PlayerIdMap::new(),
+            duration_ms: 0,
+        }
+    }
+
+    fn rating_of(store: &RatingsStore, id: u8) -> f64 {
+        let identity = player(id).identity.unwrap();
+        store.ratings.lock().unwrap().get(&identity).unwrap().rating
+    }
+
+    #[test]
+    fn identity_is_keyed_and_stable_across_restarts() {
+        let (store, path) = temp_store("identity");
+        let a = store.identity("dan", "secret-client-id").unwrap();
+        assert_eq!(Some(&a), store.identity("dan", "secret-client-id").as_ref());
+        assert_ne!(Some(&a), store.identity("dan", "other-client-id").as_ref());
+        assert_ne!(Some(&a), store.identity("not dan", "secret-client-id").as_ref());
+        assert_ne!(store.identity("ab", "c"), store.identity("a", "bc"));
+        assert!(!a.0.contains("secret"));
+        assert_eq!(a.0.len(), 32);
+
+        // No client id, no rating
+        assert!(store.identity("dan", "").is_none());
+
+        // Same key after a restart, a different server gives a different identity
+        assert_eq!(Some(&a), RatingsStore::open(&path).identity("dan", "secret-client-id").as_ref());
+        let (other, _) = temp_store("identity_other");
+        assert_ne!(Some(&a), other.identity("dan", "secret-client-id").as_ref());
+    }
+
+    #[test]
+    fn ratings_are_conserved() {
+        let (store, path) = temp_store("conserved");
+        let players = [1, 2, 3, 4];
+        store.record_match(&result(&players, &[Some(1), Some(2), None, Some(1)], 1));
+        store.record_match(&result(&players, &[Some(3), Some(4), Some(3)], 3));
+        store.record_match(&result(&[1, 3], &[Some(3), Some(1), Some(3)], 3));
+
+        let total: f64 = players.iter().map(|x| rating_of(&store, *x)).sum();
+        assert!((total - INITIAL_RATING * players.len() as f64).abs() < 1e-6);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ratings_follow_rank_order() {
+        let (store, path) = temp_store("rank");
+        store.record_match(&result(&[1, 2, 3], &[Some(1), Some(2), Some(1)], 1));
+
+        // Match winner on top, the player who took a round next, then the one who won nothing
+        let (a, b, c) = (rating_of(&store, 1), rating_of(&store, 2), rating_of(&store, 3));
+        assert!(a > b && b > c, "{} {} {}", a, b, c);
+        assert!(a > INITIAL_RATING && c < INITIAL_RATING);
+
+        // Beating a stronger player is worth more than beating a weaker one
+        let before = (rating_of(&store, 1), rating_of(&store, 3));
+        store.record_match(&result(&[1, 3], &[], 3));
+        let upset = rating_of(&store, 3) - before.1;
+        store.record_match(&result(&[1, 3], &[], 1));
+        let expected_win = rating_of(&store, 1) - (before.0 - upset);
+        assert!(upset > expected_win, "{} {}", upset, expected_win);
+
+        // Survives a restart
+        let reloaded = RatingsStore::open(&path);
+        assert_eq!(reloaded.leaderboard(1)[0].identity, player(1).identity.unwrap());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
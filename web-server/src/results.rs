@@ -0,0 +1,339 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Mutex;
+
+use crossy_multi_core::crossy_ruleset::{AliveState, CrossyRulesetFST, RoundState};
+use crossy_multi_core::game::PlayerId;
+use crossy_multi_core::interop::PlayerRosterEntry;
+use crossy_multi_core::player_id_map::PlayerIdMap;
+use serde::{Deserialize, Serialize};
+
+use crate::ratings::PlayerIdentity;
+
+pub const RESULTS_PATH: &str = "results.jsonl";
+
+// How many results we keep in memory to serve /results
+const MAX_RECENT_RESULTS: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchPlayer {
+    pub player_id: PlayerId,
+    pub name: String,
+    // Missing for players that didnt send a client id
+    #[serde(default)]
+    pub identity: Option<PlayerIdentity>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoundResult {
+    pub round_id: u8,
+    // None when everyone died
+    pub winner: Option<PlayerId>,
+    // Players alive at the start of the round
+    pub players: Vec<PlayerId>,
+    pub duration_ms: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchResult {
+    pub game_id: String,
+    pub seed: u32,
+    pub finished_utc: String,
+    pub players: Vec<MatchPlayer>,
+    pub rounds: Vec<RoundResult>,
+    pub winner: PlayerId,
+    pub win_counts: PlayerIdMap<u8>,
+    pub duration_ms: u32,
+}
+
+// Results of a match that has ended, before we attach the game level details.
+pub struct CompletedMatch {
+    pub rounds: Vec<RoundResult>,
+    pub winner: PlayerId,
+    pub win_counts: PlayerIdMap<u8>,
+    pub duration_ms: u32,
+}
+
+impl CompletedMatch {
+    pub fn into_result(self, game_id: &str, seed: u32, roster: &PlayerIdMap<PlayerRosterEntry>, identities: &PlayerIdMap<PlayerIdentity>) -> MatchResult {
+        let players = self.win_counts.iter().map(|(player_id, _)| MatchPlayer {
+            player_id,
+            name: roster.get(player_id).map(|x| x.name.clone()).unwrap_or_default(),
+            identity: identities.get(player_id).cloned(),
+        }).collect();
+
+        MatchResult {
+            game_id: game_id.to_owned(),
+            seed,
+            finished_utc: chrono::Utc::now().to_rfc3339(),
+            players,
+            rounds: self.rounds,
+            winner: self.winner,
+            win_counts: self.win_counts,
+            duration_ms: self.duration_ms,
+        }
+    }
+}
+
+// Watches the ruleset state machine and reconstructs the match history from its transitions.
+// Should be fed states old enough that they wont be resimulated (eg the lkg state).
+#[derive(Default)]
+pub struct MatchTracker {
+    prev: Option<CrossyRulesetFST>,
+    match_start_us: u32,
+    round_start_us: u32,
+    rounds: Vec<RoundResult>,
+}
+
+impl MatchTracker {
+    pub fn observe(&mut self, fst: &CrossyRulesetFST, time_us: u32) -> Option<CompletedMatch> {
+        let prev = self.prev.replace(fst.clone())?;
+        if (prev.same_variant(fst)) {
+            return None;
+        }
+
+        match (&prev, fst) {
+            (CrossyRulesetFST::Lobby { .. }, CrossyRulesetFST::RoundWarmup(_)) => {
+                self.match_start_us = time_us;
+                self.rounds.clear();
+            },
+            (CrossyRulesetFST::RoundWarmup(_), CrossyRulesetFST::Round(_)) => {
+                self.round_start_us = time_us;
+            },
+            (CrossyRulesetFST::RoundCooldown(cooldown), _) => {
+                let round_state = &cooldown.round_state;
+                let winner = round_winner(round_state);
+                self.rounds.push(RoundResult {
+                    round_id: round_state.round_id,
+                    winner,
+                    players: round_state.alive_states.iter()
+                        .filter(|(_, x)| **x != AliveState::NotInGame)
+                        .map(|(id, _)| id)
+                        .collect(),
+                    duration_ms: time_us.saturating_sub(self.round_start_us) / 1000,
+                });
+
+                if let CrossyRulesetFST::EndWinner(end_state) = fst {
+                    // The final win is not recorded in the ruleset state, add it back on.
+                    let mut win_counts = round_state.win_counts.clone();
+                    let count = win_counts.get_copy(end_state.winner_id).unwrap_or(0);
+                    win_counts.set(end_state.winner_id, count + 1);
+
+                    return Some(CompletedMatch {
+                        rounds: std::mem::take(&mut self.rounds),
+                        winner: end_state.winner_id,
+                        win_counts,
+                        duration_ms: time_us.saturating_sub(self.match_start_us) / 1000,
+                    });
+                }
+            },
+            _ => {},
+        }
+
+        None
+    }
+}
+
+fn round_winner(round_state: &RoundState) -> Option<PlayerId> {
+    round_state.alive_states.iter().filter(|(_, x)| **x == AliveState::Alive).map(|(id, _)| id).next()
+}
+
+// Append only JSON lines file, with the most recent results cached in memory.
+pub struct ResultsStore {
+    path: String,
+    recent: Mutex<VecDeque<MatchResult>>,
+}
+
+impl ResultsStore {
+    pub fn open(path: &str) -> Self {
+        let mut recent = VecDeque::new();
+
+        match std::fs::File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines().map_while(Result::ok) {
+                    match serde_json::from_str::<MatchResult>(&line) {
+                        Ok(result) => {
+                            if (recent.len() >= MAX_RECENT_RESULTS) {
+                                recent.pop_front();
+                            }
+                            recent.push_back(result);
+                        },
+                        Err(e) => println!("Skipping bad result line in {}: {}", path, e),
+                    }
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => println!("Unable to read results from {}: {}", path, e),
+        }
+
+        println!("Loaded {} results from {}", recent.len(), path);
+
+        Self {
+            path: path.to_owned(),
+            recent: Mutex::new(recent),
+        }
+    }
+
+    pub fn push(&self, result: MatchResult) {
+        // Losing a result is not worth taking the game down for
+        if let Err(e) = self.append_to_file(&result) {
+            println!("Failed to write result for {} to {}: {}", result.game_id, self.path, e);
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if (recent.len() >= MAX_RECENT_RESULTS) {
+            recent.pop_front();
+        }
+        recent.push_back(result);
+    }
+
+    fn append_to_file(&self, result: &MatchResult) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(result)?;
+        line.push('\n');
+
+        let mut file = std::fs::OpenOptions::new().append(true).create(true).open(&self.path)?;
+        file.write_all(line.as_bytes())
+    }
+
+    // Newest first
+    pub fn recent(&self, limit: usize) -> Vec<MatchResult> {
+        let recent = self.recent.lock().unwrap();
+        recent.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossy_multi_core::crossy_ruleset::{CooldownState, EndWinnerState, WarmupState};
+
+    const A: PlayerId = PlayerId(1);
+    const B: PlayerId = PlayerId(2);
+
+    fn ids(alive: &[(PlayerId, AliveState)]) -> PlayerIdMap<AliveState> {
+        let mut map = PlayerIdMap::new();
+        for (id, state) in alive {
+            map.set(*id, *state);
+        }
+        map
+    }
+
+    fn wins(counts: &[(PlayerId, u8)]) -> PlayerIdMap<u8> {
+        let mut map = PlayerIdMap::new();
+        for (id, count) in counts {
+            map.set(*id, *count);
+        }
+        map
+    }
+
+    fn lobby() -> CrossyRulesetFST {
+        CrossyRulesetFST::Lobby { time_with_all_players_in_ready_zone: 0, raft_pos: 0.0 }
+    }
+
+    fn warmup(round_id: u8, win_counts: &[(PlayerId, u8)]) -> CrossyRulesetFST {
+        CrossyRulesetFST::RoundWarmup(WarmupState {
+            remaining_us: 0,
+            time_full_us: 0,
+            alive_states: ids(&[(A, AliveState::Alive), (B, AliveState::Alive)]),
+            win_counts: wins(win_counts),
+            round_id,
+        })
+    }
+
+    fn round(round_id: u8, alive: &[(PlayerId, AliveState)], win_counts: &[(PlayerId, u8)]) -> RoundState {
+        RoundState {
+            screen_y: 0,
+            alive_states: ids(alive),
+            win_counts: wins(win_counts),
+            round_id,
+        }
+    }
+
+    fn cooldown(round_state: RoundState) -> CrossyRulesetFST {
+        CrossyRulesetFST::RoundCooldown(CooldownState {
+            remaining_us: 0,
+            round_state,
+        })
+    }
+
+    #[test]
+    fn tracks_full_match() {
+        let mut tracker = MatchTracker::default();
+        let both_alive = [(A, AliveState::Alive), (B, AliveState::Alive)];
+        let a_won = [(A, AliveState::Alive), (B, AliveState::Dead)];
+        let all_dead = [(A, AliveState::Dead), (B, AliveState::Dead)];
+
+        let states = [
+            (lobby(), 0),
+            (lobby(), 1_000_000),
+            (warmup(1, &[]), 2_000_000),
+            (CrossyRulesetFST::Round(round(1, &both_alive, &[])), 5_000_000),
+            (cooldown(round(1, &a_won, &[])), 25_000_000),
+            (warmup(2, &[(A, 1)]), 27_000_000),
+            (CrossyRulesetFST::Round(round(2, &both_alive, &[(A, 1)])), 30_000_000),
+            // Nobody wins this one
+            (cooldown(round(2, &all_dead, &[(A, 1)])), 40_000_000),
+            (warmup(3, &[(A, 1)]), 42_000_000),
+            (CrossyRulesetFST::Round(round(3, &both_alive, &[(A, 1)])), 45_000_000),
+            (cooldown(round(3, &a_won, &[(A, 1)])), 50_000_000),
+        ];
+
+        for (fst, time_us) in &states {
+            assert!(tracker.observe(fst, *time_us).is_none());
+        }
+
+        let completed = tracker.observe(&CrossyRulesetFST::EndWinner(EndWinnerState { winner_id: A, remaining_us: 0 }), 52_000_000).unwrap();
+        assert_eq!(completed.winner, A);
+        assert_eq!(completed.win_counts.get_copy(A), Some(2));
+        assert_eq!(completed.duration_ms, 50_000);
+
+        let round_winners: Vec<Option<PlayerId>> = completed.rounds.iter().map(|x| x.winner).collect();
+        assert_eq!(round_winners, vec![Some(A), None, Some(A)]);
+        // Rounds are recorded as the cooldown ends, so include it
+        assert_eq!(completed.rounds[0].duration_ms, 22_000);
+        assert_eq!(completed.rounds[0].players, vec![A, B]);
+
+        // Back to the lobby starts fresh
+        assert!(tracker.observe(&lobby(), 60_000_000).is_none());
+        assert!(tracker.observe(&warmup(1, &[]), 61_000_000).is_none());
+        assert!(tracker.rounds.is_empty());
+    }
+
+    #[test]
+    fn everyone_leaving_is_not_a_result() {
+        let mut tracker = MatchTracker::default();
+        let both_alive = [(A, AliveState::Alive), (B, AliveState::Alive)];
+
+        assert!(tracker.observe(&lobby(), 0).is_none());
+        assert!(tracker.observe(&warmup(1, &[]), 1_000_000).is_none());
+        assert!(tracker.observe(&CrossyRulesetFST::Round(round(1, &both_alive, &[])), 2_000_000).is_none());
+        assert!(tracker.observe(&CrossyRulesetFST::EndAllLeft(Default::default()), 3_000_000).is_none());
+    }
+
+    #[test]
+    fn store_reloads_newest_first() {
+        let path = std::env::temp_dir().join(format!("crossy_results_test_{}.jsonl", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&path);
+
+        let store = ResultsStore::open(&path);
+        for seed in 0..3 {
+            store.push(MatchResult {
+                game_id: format!("game{}", seed),
+                seed,
+                finished_utc: String::new(),
+                players: Vec::new(),
+                rounds: Vec::new(),
+                winner: A,
+                win_counts: wins(&[(A, 3)]),
+                duration_ms: 0,
+            });
+        }
+
+        let seeds = |results: Vec<MatchResult>| results.iter().map(|x| x.seed).collect::<Vec<_>>();
+        assert_eq!(seeds(store.recent(2)), vec![2, 1]);
+        assert_eq!(seeds(ResultsStore::open(&path).recent(10)), vec![2, 1, 0]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
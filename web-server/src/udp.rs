@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossy_multi_core::interop::{self, CrossyMessage};
+use crossy_multi_core::udp_protocol::{InputDedup, SequenceCounter, SequenceFilter, UdpClientPacket, UdpServerPacket, MAX_DATAGRAM_BYTES};
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+use crate::crossy_server::{SessionToken, SocketId};
+use crate::{metrics, GameDb, GameDbInner, GameId};
+
+// Optional datagram transport for native clients, websockets over TCP hold every input up behind a lost packet.
+// Clients still /join (or /rejoin) and /play over http, then send packets stamped with their game, socket id and
+// session token to the udp port. See core/src/udp_protocol.rs for the framing.
+// Messages go into the same Server as websocket clients, so both can play in one lobby.
+
+// UDP never closes, a client we havent heard from in this long has gone
+const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Game and socket id, a client keeps these when a NAT moves it to a new port
+type ConnectionKey = (String, u32);
+
+struct UdpConnection {
+    game: GameDbInner,
+    socket_id: SocketId,
+    session_token: String,
+    // Shared with the sender so it follows the client to a new address
+    addr: Arc<std::sync::Mutex<SocketAddr>>,
+
+    incoming_seq: SequenceFilter,
+    inputs: InputDedup,
+    // Shared with the sender so every packet out acks the newest input we have
+    input_ack: Arc<std::sync::Mutex<Option<u32>>>,
+
+    last_heard: Instant,
+    sender: tokio::task::JoinHandle<()>,
+}
+
+impl UdpConnection {
+    fn addr(&self) -> SocketAddr {
+        *self.addr.lock().unwrap()
+    }
+
+    async fn close(self) {
+        self.sender.abort();
+        metrics::CONNECTED_SOCKETS.dec();
+        self.game.game.queue_message(CrossyMessage::ClientDrop(), self.socket_id).await;
+    }
+}
+
+#[derive(Default)]
+struct UdpConnections {
+    connections: HashMap<ConnectionKey, UdpConnection>,
+    // Only one client can be behind an address, so we can tell when an old socket is replaced after /rejoin
+    by_addr: HashMap<SocketAddr, ConnectionKey>,
+}
+
+impl UdpConnections {
+    fn get(&self, key: &ConnectionKey) -> Option<&UdpConnection> {
+        self.connections.get(key)
+    }
+
+    fn get_mut(&mut self, key: &ConnectionKey) -> Option<&mut UdpConnection> {
+        self.connections.get_mut(key)
+    }
+
+    // Returns any other connection that was using the address
+    fn insert(&mut self, key: ConnectionKey, connection: UdpConnection) -> Option<UdpConnection> {
+        let addr = connection.addr();
+        self.connections.insert(key.clone(), connection);
+        self.bind_addr(&key, addr)
+    }
+
+    // Points the connection at a new address, returns any other connection that was using it
+    fn bind_addr(&mut self, key: &ConnectionKey, addr: SocketAddr) -> Option<UdpConnection> {
+        let connection = self.connections.get(key)?;
+        let old_addr = connection.addr();
+        *connection.addr.lock().unwrap() = addr;
+
+        if (self.by_addr.get(&old_addr) == Some(key)) {
+            self.by_addr.remove(&old_addr);
+        }
+
+        match self.by_addr.insert(addr, key.clone()) {
+            Some(displaced) if displaced != *key => self.connections.remove(&displaced),
+            _ => None,
+        }
+    }
+
+    fn remove(&mut self, key: &ConnectionKey) -> Option<UdpConnection> {
+        let connection = self.connections.remove(key)?;
+        let addr = connection.addr();
+        if (self.by_addr.get(&addr) == Some(key)) {
+            self.by_addr.remove(&addr);
+        }
+        Some(connection)
+    }
+
+    fn timed_out(&self, now: Instant) -> Vec<ConnectionKey> {
+        self.connections.iter()
+            .filter(|(_, x)| now.saturating_duration_since(x.last_heard) > UDP_TIMEOUT)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+pub async fn serve(socket: UdpSocket, db: GameDb) {
+    let socket = Arc::new(socket);
+    let mut connections = UdpConnections::default();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_BYTES];
+    let mut sweep = tokio::time::interval(UDP_TIMEOUT / 5);
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                match received {
+                    Ok((len, addr)) => receive_packet(&buffer[..len], addr, &socket, &db, &mut connections).await,
+                    // Can just be an ICMP error from a client that went away, keep serving everyone else
+                    Err(e) => println!("UDP receive error {e}"),
+                }
+            }
+            _ = sweep.tick() => {
+                for key in connections.timed_out(Instant::now()) {
+                    if let Some(connection) = connections.remove(&key) {
+                        println!("[{:?}] UDP client {} timed out", connection.socket_id, connection.addr());
+                        connection.close().await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn receive_packet(bytes: &[u8], addr: SocketAddr, socket: &Arc<UdpSocket>, db: &GameDb, connections: &mut UdpConnections) {
+    let packet = match parse_client_packet(bytes) {
+        Ok(x) => x,
+        Err(reason) => {
+            // No reply, we dont know who really sent it
+            println!("Dropping UDP packet from {}: {:?}", addr, reason);
+            metrics::UDP_PACKETS_DROPPED.inc();
+            return;
+        }
+    };
+
+    let key = (packet.game_id.clone(), packet.socket_id);
+    match connections.get(&key) {
+        Some(connection) if connection.session_token != packet.session_token => {
+            println!("[{:?}] Dropping UDP packet from {}, bad session token", connection.socket_id, addr);
+            metrics::UDP_PACKETS_DROPPED.inc();
+            return;
+        }
+        Some(connection) => {
+            // Same client from a new port, usually a NAT rebinding. Keep the player and just send to the new address
+            if (connection.addr() != addr) {
+                println!("[{:?}] UDP client moved from {} to {}", connection.socket_id, connection.addr(), addr);
+                if let Some(old) = connections.bind_addr(&key, addr) {
+                    old.close().await;
+                }
+            }
+        }
+        None => {
+            // New client, or an old one on a new socket after /rejoin
+            let connection = match connect(&packet, addr, socket, db).await {
+                Some(x) => x,
+                None => {
+                    metrics::UDP_PACKETS_DROPPED.inc();
+                    return;
+                }
+            };
+
+            if let Some(old) = connections.insert(key.clone(), connection) {
+                old.close().await;
+            }
+        }
+    }
+
+    let connection = connections.get_mut(&key).unwrap();
+    connection.last_heard = Instant::now();
+
+    if (!connection.incoming_seq.accept(packet.seq)) {
+        // Anything it carried is either already here or out of date
+        metrics::UDP_PACKETS_DROPPED.inc();
+        return;
+    }
+
+    let mut dropped = false;
+    for message in packet.messages {
+        match message {
+            CrossyMessage::ClientTick(ticks) => {
+                // Clients resend every input until we ack it, only pass each frame on once
+                let fresh = connection.inputs.filter(ticks);
+                *connection.input_ack.lock().unwrap() = connection.inputs.latest_frame_id();
+                if (!fresh.is_empty()) {
+                    connection.game.game.queue_message(CrossyMessage::ClientTick(fresh), connection.socket_id).await;
+                }
+            }
+            CrossyMessage::ClientDrop() => {
+                // Client is leaving politely rather than timing out
+                dropped = true;
+                break;
+            }
+            message => {
+                connection.game.game.queue_message(message, connection.socket_id).await;
+            }
+        }
+    }
+
+    if (dropped) {
+        if let Some(connection) = connections.remove(&key) {
+            println!("[{:?}] UDP client {} disconnected", connection.socket_id, addr);
+            connection.close().await;
+        }
+    }
+}
+
+async fn connect(packet: &UdpClientPacket, addr: SocketAddr, socket: &Arc<UdpSocket>, db: &GameDb) -> Option<UdpConnection> {
+    let game = db.get(GameId(packet.game_id.clone())).await.ok()?;
+    let socket_id = SocketId(packet.socket_id);
+    if (!game.game.check_session(socket_id, &SessionToken(packet.session_token.clone())).await) {
+        println!("[{:?}] Refusing UDP client {}, bad session token", socket_id, addr);
+        return None;
+    }
+
+    println!("[{:?}] UDP client connected from {}", socket_id, addr);
+    metrics::CONNECTED_SOCKETS.inc();
+
+    let addr = Arc::new(std::sync::Mutex::new(addr));
+    let input_ack = Arc::new(std::sync::Mutex::new(None));
+    let sender = tokio::task::spawn(udp_sender_main(socket.clone(), addr.clone(), game.clone(), socket_id, input_ack.clone()));
+
+    Some(UdpConnection {
+        game,
+        socket_id,
+        session_token: packet.session_token.clone(),
+        addr,
+        incoming_seq: Default::default(),
+        inputs: Default::default(),
+        input_ack,
+        last_heard: Instant::now(),
+        sender,
+    })
+}
+
+// Same as the websocket sender, but each message goes out as its own sequenced datagram.
+async fn udp_sender_main(socket: Arc<UdpSocket>, addr: Arc<std::sync::Mutex<SocketAddr>>, db: GameDbInner, socket_id: SocketId, input_ack: Arc<std::sync::Mutex<Option<u32>>>) {
+    let mut tick_listener = db.game.get_listener();
+    let game_start = db.game.get_start_time().await;
+    let mut seq = SequenceCounter::default();
+
+    loop {
+        match tick_listener.recv().await {
+            Ok(CrossyMessage::GoodBye()) => {
+                println!("Game ended cleaning up UDP sender");
+                break;
+            },
+            Ok(message) => {
+                let to_send = match crate::message_for_socket(message, socket_id, game_start) {
+                    Some(x) => x,
+                    None => continue,
+                };
+
+                let kicked = matches!(&to_send, CrossyMessage::PlayerKicked(x) if x.socket_id == socket_id.0);
+                let is_tick = matches!(&to_send, CrossyMessage::LindenServerTick(_));
+
+                let packet = UdpServerPacket {
+                    seq: seq.next_seq(),
+                    input_ack_frame_id: *input_ack.lock().unwrap(),
+                    messages: vec![to_send],
+                };
+
+                let serialized = flexbuffers::to_vec(&packet).unwrap();
+                if (serialized.len() > MAX_DATAGRAM_BYTES) {
+                    println!("[{:?}] Dropping {} byte message, too big for a datagram", socket_id, serialized.len());
+                    continue;
+                }
+
+                metrics::BYTES_SENT.add(serialized.len() as u64);
+                if (is_tick) {
+                    metrics::TICK_BYTES_SENT.observe(serialized.len() as f64);
+                }
+
+                let addr = *addr.lock().unwrap();
+                if let Err(e) = socket.send_to(&serialized, addr).await {
+                    // Nothing is connected so this doesnt mean the client is gone, the receive side times them out
+                    println!("[{:?}] UDP send error {e}", socket_id);
+                }
+
+                if (kicked) {
+                    println!("[{:?}] Kicked, no longer sending", socket_id);
+                    break;
+                }
+            },
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                println!("[{:?}] Underlying game closed", socket_id);
+                break;
+            },
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(x)) => {
+                println!("[{:?}] Client lagged by {}", socket_id, x);
+                metrics::BROADCAST_LAGGED_EVENTS.inc();
+                metrics::BROADCAST_LAGGED_MESSAGES.add(x);
+            },
+        }
+    }
+}
+
+// Same limits as a websocket message, applied to the whole datagram
+fn parse_client_packet(bytes: &[u8]) -> Result<UdpClientPacket, interop::RejectionReason> {
+    if (bytes.len() > interop::MAX_CLIENT_MESSAGE_BYTES) {
+        return Err(interop::RejectionReason::MessageTooLarge);
+    }
+
+    let r = flexbuffers::Reader::get_root(bytes).map_err(|_| interop::RejectionReason::Malformed)?;
+    let packet = UdpClientPacket::deserialize(r).map_err(|_| interop::RejectionReason::Malformed)?;
+
+    if (packet.messages.len() > interop::MAX_CLIENT_TICKS_PER_MESSAGE || !packet.messages.iter().all(|x| x.within_collection_limits())) {
+        return Err(interop::RejectionReason::TooManyEntries);
+    }
+
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossy_multi_core::crossy_ruleset::GameConfig;
+    use crossy_multi_core::interop::ClientHello;
+    use crate::loopback::test_db;
+
+    // Whether anything arrives before the game has had a few ticks to send it
+    async fn receives(socket: &UdpSocket) -> bool {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_BYTES];
+        tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut buffer)).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn client_follows_nat_rebinding() {
+        let db = test_db("rebind");
+        let game_id = db.new_game(GameConfig::default(), false).await.unwrap();
+        let game = db.get(game_id.clone()).await.unwrap();
+        let (socket_id, session_token) = game.game.join("a", None).await;
+        game.game.play(&ClientHello::default(), socket_id).await.unwrap();
+
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let before = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let after = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut connections = UdpConnections::default();
+
+        let packet = |seq| {
+            flexbuffers::to_vec(&UdpClientPacket {
+                game_id: game_id.0.clone(),
+                socket_id: socket_id.0,
+                session_token: session_token.0.clone(),
+                seq,
+                messages: vec![],
+            }).unwrap()
+        };
+
+        receive_packet(&packet(0), before.local_addr().unwrap(), &server, &db, &mut connections).await;
+        assert!(receives(&before).await);
+
+        // Same client, the NAT gave it a new port
+        receive_packet(&packet(1), after.local_addr().unwrap(), &server, &db, &mut connections).await;
+        assert_eq!(connections.connections.len(), 1);
+        assert_eq!(connections.by_addr.len(), 1);
+        assert!(receives(&after).await);
+
+        // Anything already in flight to the old port, then nothing
+        while receives(&before).await {}
+        assert!(receives(&after).await);
+
+        // Moving wasnt a drop
+        assert!(game.game.is_connected(socket_id).await);
+
+        // Someone else can't take the connection over with the wrong token
+        let mut spoofed = UdpClientPacket {
+            game_id: game_id.0.clone(),
+            socket_id: socket_id.0,
+            session_token: "guess".to_owned(),
+            seq: 2,
+            messages: vec![],
+        };
+        receive_packet(&flexbuffers::to_vec(&spoofed).unwrap(), before.local_addr().unwrap(), &server, &db, &mut connections).await;
+        assert_eq!(connections.get(&(game_id.0.clone(), socket_id.0)).unwrap().addr(), after.local_addr().unwrap());
+
+        // Leaving from the new port drops the player
+        spoofed.session_token = session_token.0.clone();
+        spoofed.messages = vec![CrossyMessage::ClientDrop()];
+        receive_packet(&flexbuffers::to_vec(&spoofed).unwrap(), after.local_addr().unwrap(), &server, &db, &mut connections).await;
+        assert!(connections.connections.is_empty() && connections.by_addr.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!game.game.is_connected(socket_id).await);
+    }
+}